@@ -0,0 +1,51 @@
+//! Regras compartilhadas entre o webserver, que emite os tokens, e o serviço
+//! WebSocket, que os valida com a mesma chave.
+
+/// Tamanho mínimo da chave HS256, em bytes
+pub const MIN_SECRET_LEN: usize = 32;
+/// Trechos de valores de exemplo que nunca devem ser usados como chave
+const PLACEHOLDER_MARKERS: &[&str] = &["change_me", "changeme", "placeholder", "example", "secret"];
+
+/// Recusa chaves curtas ou que ainda são o valor de exemplo
+pub fn validate_secret(secret: &str) -> Result<(), String> {
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(format!("JWT_SECRET precisa ter ao menos {} bytes", MIN_SECRET_LEN));
+    }
+    let lowered = secret.to_lowercase();
+    if let Some(marker) = PLACEHOLDER_MARKERS.iter().find(|marker| lowered.contains(*marker)) {
+        return Err(format!("JWT_SECRET parece um valor de exemplo (contém {:?})", marker));
+    }
+    Ok(())
+}
+
+/// Lê `JWT_SECRET` do ambiente e encerra o processo se a chave for fraca
+pub fn secret_from_env() -> String {
+    let secret = std::env::var("JWT_SECRET")
+        .expect("JWT_SECRET deve estar definido no ambiente");
+    if let Err(reason) = validate_secret(&secret) {
+        panic!("{}; gere uma com `openssl rand -hex 32`", reason);
+    }
+    secret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_secret_is_accepted() {
+        assert!(validate_secret("3f9c1a7be0d24f6a8c5e91b7d2a40f6e1c8b3a9d7e5f20c4b6a1d8e3f7c9b2a0").is_ok());
+    }
+
+    #[test]
+    fn short_secret_is_rejected() {
+        assert!(validate_secret(&"a".repeat(MIN_SECRET_LEN - 1)).is_err());
+        assert!(validate_secret(&"a".repeat(MIN_SECRET_LEN)).is_ok());
+    }
+
+    #[test]
+    fn placeholder_secret_is_rejected_regardless_of_case() {
+        assert!(validate_secret("CHANGE_ME_change_me_change_me_change_me").is_err());
+        assert!(validate_secret("my-super-secret-key-for-jwt-tokens-123").is_err());
+    }
+}
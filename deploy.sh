@@ -42,6 +42,17 @@ kubectl wait --for=condition=ready pod -l app=redis --timeout=120s
 
 # Deploy Webserver (API)
 echo "🔧 Deploy API Webserver..."
+# Chave HS256 compartilhada entre webserver (emissão) e websocket (validação);
+# gerada só na primeira vez para não invalidar os tokens já emitidos
+if ! kubectl get secret jwt-secret &> /dev/null; then
+    kubectl create secret generic jwt-secret \
+        --from-literal=JWT_SECRET=$(openssl rand -hex 32)
+fi
+# Token de serviço do POST /messages/batch, compartilhado com os pods WebSocket
+if ! kubectl get secret persistence-service-token &> /dev/null; then
+    kubectl create secret generic persistence-service-token \
+        --from-literal=PERSIST_SERVICE_TOKEN=$(openssl rand -hex 32)
+fi
 kubectl apply -f deployments/webserver-deployment.yaml
 kubectl apply -f services/webserver-service.yaml
 
@@ -90,7 +101,7 @@ if command -v minikube &> /dev/null; then
     MINIKUBE_IP=$(minikube ip)
     echo "🏠 Frontend: http://$MINIKUBE_IP"
     echo "🔌 API: http://$MINIKUBE_IP/api/messages"
-    echo "💬 WebSocket: ws://$MINIKUBE_IP/ws/your-username"
+    echo "💬 WebSocket: ws://$MINIKUBE_IP/ws?access_token=<token de POST /api/auth/token>"
     echo ""
     echo "🎯 Para testar a API:"
     echo "curl -X POST http://$MINIKUBE_IP/api/messages \\"
@@ -99,5 +110,5 @@ if command -v minikube &> /dev/null; then
 else
     echo "Frontend: http://localhost"
     echo "API: http://localhost/api/messages"
-    echo "WebSocket: ws://localhost/ws/your-username"
+    echo "WebSocket: ws://localhost/ws?access_token=<token de POST /api/auth/token>"
 fi
\ No newline at end of file
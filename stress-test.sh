@@ -5,6 +5,7 @@ NUM_CLIENTS=${1:-50}
 DURATION_MINUTES=${2:-15}
 MESSAGE_INTERVAL=${3:-5}
 WS_URL=${4:-"ws://192.168.49.2"}
+API_URL=${5:-"http://192.168.49.2/api"}
 
 echo "🔥 CHAT-ACTOR STRESS TEST"
 echo "========================"
@@ -13,6 +14,7 @@ echo "   - Clientes: $NUM_CLIENTS"
 echo "   - Duração: $DURATION_MINUTES minutos"
 echo "   - Intervalo de mensagens: $MESSAGE_INTERVAL segundos"
 echo "   - URL: $WS_URL"
+echo "   - API (tokens): $API_URL"
 echo ""
 
 # Calcular duração em segundos
@@ -30,6 +32,16 @@ connect_client() {
     local log_file="$LOG_DIR/client_$client_id.log"
     
     echo "[Cliente $client_id] Iniciando conexão como $username" >> "$log_file"
+
+    # Cadastrar (ignora se já existe) e obter token de acesso
+    local credentials="{\"username\":\"$username\",\"password\":\"stress-$username\"}"
+    curl -s -X POST "$API_URL/users" -H 'Content-Type: application/json' -d "$credentials" >/dev/null
+    local token=$(curl -s -X POST "$API_URL/auth/token" -H 'Content-Type: application/json' -d "$credentials" \
+        | sed -n 's/.*"access_token":"\([^"]*\)".*/\1/p')
+    if [ -z "$token" ]; then
+        echo "[Cliente $client_id] Falha ao obter token" >> "$log_file"
+        return
+    fi
     
     # Usar wscat para conectar e enviar mensagens periodicamente
     {
@@ -37,7 +49,7 @@ connect_client() {
             # Criar mensagem JSON
             local timestamp=$(date +%s)
             local message_content="Mensagem de teste do $username - $(date)"
-            local json_message="{\"username\":\"$username\",\"content\":\"$message_content\"}"
+            local json_message="{\"type\":\"message\",\"version\":1,\"payload\":{\"room_id\":\"general\",\"content\":\"$message_content\"}}"
             
             echo "$json_message"
             echo "[Cliente $client_id] Enviou: $message_content" >> "$log_file"
@@ -49,7 +61,7 @@ connect_client() {
             
             sleep $sleep_time
         done
-    } | wscat -c "$WS_URL/ws/$username" 2>>"$log_file" &
+    } | wscat -c "$WS_URL/ws?access_token=$token" 2>>"$log_file" &
     
     local pid=$!
     echo $pid > "$LOG_DIR/client_${client_id}.pid"
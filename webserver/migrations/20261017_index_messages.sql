@@ -0,0 +1,4 @@
+-- ./migrations/20261017_index_messages.sql
+-- Índices usados pela paginação de histórico (GET /messages)
+CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp);
+CREATE INDEX IF NOT EXISTS idx_messages_user_id_id ON messages(user_id, id);
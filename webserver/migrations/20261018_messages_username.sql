@@ -0,0 +1,5 @@
+-- ./migrations/20261018_messages_username.sql
+-- Mensagens vindas do serviço WebSocket só conhecem o username
+ALTER TABLE messages ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS username TEXT;
+CREATE INDEX IF NOT EXISTS idx_messages_username_id ON messages(username, id);
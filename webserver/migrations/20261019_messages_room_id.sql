@@ -0,0 +1,4 @@
+-- ./migrations/20261019_messages_room_id.sql
+-- Cada mensagem pertence a uma sala; as antigas ficam na sala padrão
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS room_id TEXT NOT NULL DEFAULT 'general';
+CREATE INDEX IF NOT EXISTS idx_messages_room_id_id ON messages(room_id, id);
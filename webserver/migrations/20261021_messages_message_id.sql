@@ -0,0 +1,4 @@
+-- ./migrations/20261021_messages_message_id.sql
+-- ID atribuído pelo serviço WebSocket; torna idempotente o reenvio de lotes
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS message_id TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_message_id ON messages(message_id);
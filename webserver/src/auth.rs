@@ -0,0 +1,211 @@
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use subtle::ConstantTimeEq;
+use time::OffsetDateTime;
+
+/// Validade padrão dos tokens emitidos, em segundos
+const DEFAULT_TOKEN_TTL_SECS: i64 = 3600;
+
+/// Configuração de emissão de tokens, lida do ambiente na inicialização
+pub struct TokenIssuer {
+    encoding_key: EncodingKey,
+    ttl_secs: i64,
+}
+
+impl TokenIssuer {
+    pub fn from_env() -> Self {
+        let secret = chat_auth::secret_from_env();
+        let ttl_secs = std::env::var("JWT_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TOKEN_TTL_SECS);
+
+        Self {
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            ttl_secs,
+        }
+    }
+}
+
+/// Token de serviço compartilhado com os pods WebSocket; protege as rotas
+/// internas, como `POST /messages/batch`, que confiam no `username` recebido
+pub struct ServiceToken(String);
+
+impl ServiceToken {
+    pub fn from_env() -> Self {
+        let token = std::env::var("PERSIST_SERVICE_TOKEN")
+            .expect("PERSIST_SERVICE_TOKEN deve estar definido em .env ou no ambiente");
+        assert!(!token.is_empty(), "PERSIST_SERVICE_TOKEN não pode ser vazio");
+
+        Self::new(token)
+    }
+
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    /// Confere `Authorization: Bearer <token>` em tempo constante
+    pub fn authorizes(&self, req: &HttpRequest) -> bool {
+        req.headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| bool::from(token.trim().as_bytes().ct_eq(self.0.as_bytes())))
+    }
+}
+
+/// Claims do token; o serviço WebSocket deriva o username de `sub`
+#[derive(Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub uid: i32,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct CreatedUser {
+    id: i32,
+    username: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    id: i32,
+    password_hash: String,
+}
+
+#[post("/users")]
+async fn register_user(
+    pool: web::Data<PgPool>,
+    credentials: web::Json<Credentials>
+) -> impl Responder {
+    if credentials.username.is_empty() || credentials.password.is_empty() {
+        return HttpResponse::BadRequest().body("Username and password are required");
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = match Argon2::default().hash_password(credentials.password.as_bytes(), &salt) {
+        Ok(hash) => hash.to_string(),
+        Err(err) => {
+            eprintln!("Password hashing error: {}", err);
+            return HttpResponse::InternalServerError().body("Failed to create user");
+        }
+    };
+
+    let result = sqlx::query_scalar::<_, i32>(
+        "INSERT INTO users (username, password_hash) VALUES ($1, $2)
+         ON CONFLICT (username) DO NOTHING RETURNING id"
+    )
+    .bind(&credentials.username)
+    .bind(&password_hash)
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(Some(id)) => HttpResponse::Created().json(CreatedUser {
+            id,
+            username: credentials.username.clone(),
+        }),
+        Ok(None) => HttpResponse::Conflict().body("Username already taken"),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            HttpResponse::InternalServerError().body("Failed to create user")
+        }
+    }
+}
+
+#[post("/auth/token")]
+async fn issue_token(
+    pool: web::Data<PgPool>,
+    issuer: web::Data<TokenIssuer>,
+    credentials: web::Json<Credentials>
+) -> impl Responder {
+    let result = sqlx::query_as::<_, UserRow>(
+        "SELECT id, password_hash FROM users WHERE username = $1"
+    )
+    .bind(&credentials.username)
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    let user = match result {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::Unauthorized().body("Invalid credentials"),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            return HttpResponse::InternalServerError().body("Failed to issue token");
+        }
+    };
+
+    let password_ok = PasswordHash::new(&user.password_hash)
+        .map(|hash| Argon2::default().verify_password(credentials.password.as_bytes(), &hash).is_ok())
+        .unwrap_or(false);
+    if !password_ok {
+        return HttpResponse::Unauthorized().body("Invalid credentials");
+    }
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let claims = Claims {
+        sub: credentials.username.clone(),
+        uid: user.id,
+        iat: now,
+        exp: now + issuer.ttl_secs,
+    };
+
+    match encode(&Header::default(), &claims, &issuer.encoding_key) {
+        Ok(access_token) => HttpResponse::Ok().json(TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: issuer.ttl_secs,
+        }),
+        Err(err) => {
+            eprintln!("Token encoding error: {}", err);
+            HttpResponse::InternalServerError().body("Failed to issue token")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request_with(authorization: Option<&str>) -> HttpRequest {
+        let request = TestRequest::post().uri("/messages/batch");
+        match authorization {
+            Some(value) => request.insert_header(("Authorization", value)).to_http_request(),
+            None => request.to_http_request(),
+        }
+    }
+
+    #[test]
+    fn service_token_accepts_only_the_matching_bearer() {
+        let token = ServiceToken::new("token-de-servico");
+
+        assert!(token.authorizes(&request_with(Some("Bearer token-de-servico"))));
+        assert!(token.authorizes(&request_with(Some("Bearer  token-de-servico "))));
+        assert!(!token.authorizes(&request_with(Some("Bearer token-de-servic"))));
+        assert!(!token.authorizes(&request_with(Some("Bearer token-de-servico-e-mais"))));
+        assert!(!token.authorizes(&request_with(Some("token-de-servico"))));
+        assert!(!token.authorizes(&request_with(Some("Basic token-de-servico"))));
+        assert!(!token.authorizes(&request_with(None)));
+    }
+}
@@ -1,27 +1,183 @@
-use actix_web::{post, web, App, HttpServer, HttpResponse, Responder};
-use serde::Deserialize;
+use actix_web::{get, post, web, App, HttpRequest, HttpServer, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
 use dotenv::dotenv;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, QueryBuilder};
 use time::OffsetDateTime;
+use crate::auth::{ServiceToken, TokenIssuer};
+
+mod auth;
+
+/// Tamanho de página usado quando `limit` não é informado
+const DEFAULT_PAGE_SIZE: i64 = 50;
+/// Limite superior para `limit`, evita varreduras enormes numa única requisição
+const MAX_PAGE_SIZE: i64 = 200;
+/// Máximo de mensagens aceitas por `POST /messages/batch`
+const MAX_BATCH_SIZE: usize = 500;
+/// Sala usada quando a mensagem não informa `room_id`
+const DEFAULT_ROOM: &str = "general";
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
 
 #[derive(Deserialize)]
 struct ChatMessage {
     user_id: i32,
     content: String,
+    #[serde(default = "default_room")]
+    room_id: String,
     /// Optional ISO 8601 timestamp; se omitido, o default do DB (`now()`) será usado
     #[serde(with = "time::serde::rfc3339::option")]
     timestamp: Option<OffsetDateTime>,
 }
 
+/// Mensagem publicada no serviço WebSocket, identificada só pelo username
+#[derive(Deserialize)]
+struct RelayedMessage {
+    /// ID atribuído pelo serviço WebSocket; reenvios do mesmo lote são ignorados
+    message_id: Option<String>,
+    username: String,
+    content: String,
+    #[serde(default = "default_room")]
+    room_id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    timestamp: OffsetDateTime,
+}
+
+#[derive(Deserialize)]
+struct MessageBatch {
+    messages: Vec<RelayedMessage>,
+}
+
+#[derive(Serialize)]
+struct BatchInserted {
+    ids: Vec<i32>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct StoredMessage {
+    id: i32,
+    message_id: Option<String>,
+    user_id: Option<i32>,
+    username: Option<String>,
+    room_id: String,
+    content: String,
+    #[serde(with = "time::serde::rfc3339")]
+    timestamp: OffsetDateTime,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Cursor para scrollback: só mensagens com `id` menor que este
+    before: Option<i32>,
+    /// Cursor para avançar: só mensagens com `id` maior que este
+    after: Option<i32>,
+    user_id: Option<i32>,
+    username: Option<String>,
+    room_id: Option<String>,
+    /// Início do intervalo (inclusivo), ISO 8601
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    since: Option<OffsetDateTime>,
+    /// Fim do intervalo (exclusivo), ISO 8601
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    until: Option<OffsetDateTime>,
+    limit: Option<i64>,
+}
+
+/// Página de histórico. `messages` vem sempre em ordem cronológica (id crescente);
+/// `next_cursor` deve ser repassado em `before` (ou em `after`, se a requisição
+/// usou `after`) para buscar a próxima página.
+#[derive(Serialize)]
+struct MessagePage {
+    messages: Vec<StoredMessage>,
+    next_cursor: Option<i32>,
+    has_more: bool,
+}
+
+#[get("/messages")]
+async fn list_messages(
+    pool: web::Data<PgPool>,
+    query: web::Query<HistoryQuery>
+) -> impl Responder {
+    if query.before.is_some() && query.after.is_some() {
+        return HttpResponse::BadRequest().body("Use either `before` or `after`, not both");
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let forward = query.after.is_some();
+
+    let result = history_query(&query, limit)
+        .build_query_as::<StoredMessage>()
+        .fetch_all(pool.get_ref())
+        .await;
+
+    match result {
+        Ok(messages) => HttpResponse::Ok().json(into_page(messages, limit, forward)),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            HttpResponse::InternalServerError().body("Failed to load messages")
+        }
+    }
+}
+
+/// Consulta de uma página de histórico: filtros de `query`, ordenada a
+/// partir do cursor e com um registro a mais que `limit`
+fn history_query(query: &HistoryQuery, limit: i64) -> QueryBuilder<'_, Postgres> {
+    let forward = query.after.is_some();
+
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+        "SELECT id, message_id, user_id, username, room_id, content, timestamp FROM messages WHERE TRUE"
+    );
+    if let Some(before) = query.before {
+        builder.push(" AND id < ").push_bind(before);
+    }
+    if let Some(after) = query.after {
+        builder.push(" AND id > ").push_bind(after);
+    }
+    if let Some(user_id) = query.user_id {
+        builder.push(" AND user_id = ").push_bind(user_id);
+    }
+    if let Some(username) = &query.username {
+        builder.push(" AND username = ").push_bind(username);
+    }
+    if let Some(room_id) = &query.room_id {
+        builder.push(" AND room_id = ").push_bind(room_id);
+    }
+    if let Some(since) = query.since {
+        builder.push(" AND timestamp >= ").push_bind(since);
+    }
+    if let Some(until) = query.until {
+        builder.push(" AND timestamp < ").push_bind(until);
+    }
+    builder.push(if forward { " ORDER BY id ASC" } else { " ORDER BY id DESC" });
+    // Busca um registro a mais só para saber se existe próxima página
+    builder.push(" LIMIT ").push_bind(limit + 1);
+
+    builder
+}
+
+/// Monta a página a partir das linhas de `history_query`, já na ordem da consulta
+fn into_page(mut messages: Vec<StoredMessage>, limit: i64, forward: bool) -> MessagePage {
+    let has_more = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+    let next_cursor = if has_more { messages.last().map(|m| m.id) } else { None };
+    if !forward {
+        messages.reverse();
+    }
+
+    MessagePage { messages, next_cursor, has_more }
+}
+
 #[post("/messages")]
 async fn create_message(
     pool: web::Data<PgPool>,
     msg: web::Json<ChatMessage>
 ) -> impl Responder {
     let result = sqlx::query(
-        "INSERT INTO messages (user_id, content, timestamp) VALUES ($1, $2, $3)"
+        "INSERT INTO messages (user_id, room_id, content, timestamp) VALUES ($1, $2, $3, $4)"
     )
     .bind(msg.user_id)
+    .bind(&msg.room_id)
     .bind(&msg.content)
     .bind(msg.timestamp)
     .execute(pool.get_ref())
@@ -36,6 +192,62 @@ async fn create_message(
     }
 }
 
+/// Inserção em lote usada pelo pipeline de persistência do serviço WebSocket.
+/// A inserção é atômica: ou o lote inteiro é gravado, ou nada é. Mensagens
+/// com `message_id` já gravado são ignoradas e não aparecem em `ids`.
+/// Como o `username` vem do próprio lote, a rota exige o token de serviço.
+#[post("/messages/batch")]
+async fn create_messages_batch(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    service_token: web::Data<ServiceToken>,
+    batch: web::Json<MessageBatch>
+) -> impl Responder {
+    if !service_token.authorizes(&req) {
+        return HttpResponse::Unauthorized().body("Invalid service token");
+    }
+    if batch.messages.is_empty() {
+        return HttpResponse::Created().json(BatchInserted { ids: vec![] });
+    }
+    if batch.messages.len() > MAX_BATCH_SIZE {
+        return HttpResponse::PayloadTooLarge().body("Batch too large");
+    }
+
+    let result = batch_insert_query(&batch.messages)
+        .build_query_scalar::<i32>()
+        .fetch_all(pool.get_ref())
+        .await;
+
+    match result {
+        Ok(ids) => HttpResponse::Created().json(BatchInserted { ids }),
+        Err(err) => {
+            eprintln!("Database error: {}", err);
+            HttpResponse::InternalServerError().body("Failed to save messages")
+        }
+    }
+}
+
+/// `INSERT` de um lote inteiro; `message_id` repetido é ignorado
+fn batch_insert_query(messages: &[RelayedMessage]) -> QueryBuilder<'_, Postgres> {
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+        "INSERT INTO messages (user_id, message_id, username, room_id, content, timestamp) "
+    );
+    builder.push_values(messages.iter(), |mut row, msg| {
+        // user_id resolvido pelo username quando o usuário tem cadastro
+        row.push("(SELECT id FROM users WHERE username = ")
+            .push_bind_unseparated(&msg.username)
+            .push_unseparated(")")
+            .push_bind(&msg.message_id)
+            .push_bind(&msg.username)
+            .push_bind(&msg.room_id)
+            .push_bind(&msg.content)
+            .push_bind(msg.timestamp);
+    });
+    builder.push(" ON CONFLICT (message_id) DO NOTHING RETURNING id");
+
+    builder
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Carrega variáveis de ambiente de `.env`
@@ -50,13 +262,172 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Falha ao conectar no Postgres");
 
+    let token_issuer = web::Data::new(TokenIssuer::from_env());
+    let service_token = web::Data::new(ServiceToken::from_env());
+
     // Inicia o servidor HTTP
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(token_issuer.clone())
+            .app_data(service_token.clone())
+            .service(auth::register_user)
+            .service(auth::issue_token)
             .service(create_message)
+            .service(create_messages_batch)
+            .service(list_messages)
     })
     .bind(("0.0.0.0", 8080))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_query(raw: &str) -> HistoryQuery {
+        web::Query::<HistoryQuery>::from_query(raw).unwrap().into_inner()
+    }
+
+    fn stored(id: i32) -> StoredMessage {
+        StoredMessage {
+            id,
+            message_id: None,
+            user_id: None,
+            username: Some("alice".to_string()),
+            room_id: DEFAULT_ROOM.to_string(),
+            content: format!("mensagem {}", id),
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+
+    fn ids(page: &MessagePage) -> Vec<i32> {
+        page.messages.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn history_query_without_cursor_reads_newest_first() {
+        let query = parse_query("");
+        let sql = history_query(&query, 50).sql().to_string();
+
+        assert_eq!(
+            sql,
+            "SELECT id, message_id, user_id, username, room_id, content, timestamp FROM messages WHERE TRUE \
+             ORDER BY id DESC LIMIT $1"
+        );
+    }
+
+    #[test]
+    fn history_query_applies_before_cursor_and_filters() {
+        let query = parse_query("before=120&username=alice&room_id=dev&since=2026-01-01T00:00:00Z&until=2026-02-01T00:00:00Z");
+        let sql = history_query(&query, 20).sql().to_string();
+
+        assert!(sql.ends_with(
+            " WHERE TRUE AND id < $1 AND username = $2 AND room_id = $3 \
+             AND timestamp >= $4 AND timestamp < $5 ORDER BY id DESC LIMIT $6"
+        ), "{}", sql);
+    }
+
+    #[test]
+    fn history_query_with_after_cursor_reads_oldest_first() {
+        let query = parse_query("after=7&user_id=3");
+        let sql = history_query(&query, 20).sql().to_string();
+
+        assert!(sql.ends_with(" WHERE TRUE AND id > $1 AND user_id = $2 ORDER BY id ASC LIMIT $3"), "{}", sql);
+    }
+
+    #[test]
+    fn backward_page_is_chronological_and_points_to_older_messages() {
+        // `before` lê do mais novo para o mais antigo, com um registro a mais
+        let page = into_page(vec![stored(10), stored(9), stored(8)], 2, false);
+
+        assert_eq!(ids(&page), [9, 10]);
+        assert!(page.has_more);
+        assert_eq!(page.next_cursor, Some(9));
+    }
+
+    #[test]
+    fn forward_page_keeps_order_and_points_to_newer_messages() {
+        let page = into_page(vec![stored(8), stored(9), stored(10)], 2, true);
+
+        assert_eq!(ids(&page), [8, 9]);
+        assert!(page.has_more);
+        assert_eq!(page.next_cursor, Some(9));
+    }
+
+    #[test]
+    fn batch_insert_ignores_repeated_message_ids() {
+        let messages: Vec<RelayedMessage> = ["m1", "m2"].iter()
+            .map(|message_id| RelayedMessage {
+                message_id: Some(message_id.to_string()),
+                username: "alice".to_string(),
+                content: "oi".to_string(),
+                room_id: DEFAULT_ROOM.to_string(),
+                timestamp: OffsetDateTime::UNIX_EPOCH,
+            })
+            .collect();
+        let sql = batch_insert_query(&messages).sql().to_string();
+
+        assert_eq!(
+            sql,
+            "INSERT INTO messages (user_id, message_id, username, room_id, content, timestamp) VALUES \
+             ((SELECT id FROM users WHERE username = $1), $2, $3, $4, $5, $6), \
+             ((SELECT id FROM users WHERE username = $7), $8, $9, $10, $11, $12) \
+             ON CONFLICT (message_id) DO NOTHING RETURNING id"
+        );
+    }
+
+    #[actix_web::test]
+    async fn batch_endpoint_checks_service_token_before_touching_the_database() {
+        use actix_web::{http::StatusCode, test};
+
+        // Pool preguiçoso: qualquer acesso ao banco falharia o teste
+        let pool = PgPool::connect_lazy("postgres://postgres@127.0.0.1:1/unused").unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(ServiceToken::new("token-de-servico")))
+                .service(create_messages_batch)
+        ).await;
+        let empty_batch = r#"{"messages": []}"#;
+
+        let without_token = test::TestRequest::post().uri("/messages/batch")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(empty_batch)
+            .to_request();
+        assert_eq!(test::call_service(&app, without_token).await.status(), StatusCode::UNAUTHORIZED);
+
+        let wrong_token = test::TestRequest::post().uri("/messages/batch")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("Authorization", "Bearer outro-token"))
+            .set_payload(empty_batch)
+            .to_request();
+        assert_eq!(test::call_service(&app, wrong_token).await.status(), StatusCode::UNAUTHORIZED);
+
+        let authorized = test::TestRequest::post().uri("/messages/batch")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("Authorization", "Bearer token-de-servico"))
+            .set_payload(empty_batch)
+            .to_request();
+        assert_eq!(test::call_service(&app, authorized).await.status(), StatusCode::CREATED);
+
+        let message = r#"{"username": "alice", "content": "oi", "timestamp": "2026-01-01T00:00:00Z"}"#;
+        let oversized = format!(r#"{{"messages": [{}]}}"#, vec![message; MAX_BATCH_SIZE + 1].join(","));
+        let too_large = test::TestRequest::post().uri("/messages/batch")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("Authorization", "Bearer token-de-servico"))
+            .set_payload(oversized)
+            .to_request();
+        assert_eq!(test::call_service(&app, too_large).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn last_page_has_no_cursor() {
+        let page = into_page(vec![stored(2), stored(1)], 5, false);
+
+        assert_eq!(ids(&page), [1, 2]);
+        assert!(!page.has_more);
+        assert_eq!(page.next_cursor, None);
+    }
+}
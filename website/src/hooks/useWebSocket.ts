@@ -1,4 +1,5 @@
 import { useState, useEffect, useRef, useCallback } from 'react';
+import { isExpired, type AuthSession } from '../services/auth';
 
 export interface Message {
     id: number;
@@ -7,20 +8,38 @@ export interface Message {
     timestamp: Date;
 }
 
-export interface WebSocketMessage {
-    username: string;
-    content: string;
-}
+// Envelope do protocolo v1: {"type", "version", "id"?, "payload"}
+export const PROTOCOL_VERSION = 1;
 
-export interface JoinEvent {
-    username: string;
+export interface Envelope<T = unknown> {
+    type: string;
+    version: number;
+    id?: string;
+    payload: T;
 }
 
-export interface LeaveEvent {
+export interface ChatMessagePayload {
+    message_id: string;
+    seq: number;
     username: string;
+    room_id: string;
+    content: string;
 }
 
-export const useWebSocket = (username: string) => {
+// O navegador não expõe o status de um upgrade recusado; repetimos a
+// requisição como GET comum para saber se foi 401
+const isUnauthorized = async (wsUrl: string) => {
+    try {
+        const response = await fetch(wsUrl.replace(/^ws/, 'http'));
+        return response.status === 401;
+    } catch {
+        return false;
+    }
+};
+
+// `onUnauthorized` é chamado quando o token expira ou o upgrade volta com 401
+export const useWebSocket = (session: AuthSession | null, onUnauthorized: () => void) => {
+    const username = session?.username ?? '';
     const [messages, setMessages] = useState<Message[]>([]);
     const [connectedUsers] = useState<string[]>([]);
     const [isConnected, setIsConnected] = useState(false);
@@ -28,45 +47,97 @@ export const useWebSocket = (username: string) => {
 
     const ws = useRef<WebSocket | null>(null);
     const messageIdCounter = useRef(1);
+    // Entregas são at-least-once: mensagens reenviadas na reconexão são ignoradas
+    const seenMessageIds = useRef(new Set<string>());
+    // Resume token (session_id do welcome) e maior seq vista em cada sala,
+    // usados na reconexão; a seq é contada por sala
+    const resumeToken = useRef<string | null>(null);
+    const lastSeqByRoom = useRef(new Map<string, number>());
+    // Zerado por um frame `reconnect`: o servidor já sorteou o atraso ao fechar
+    const reconnectDelay = useRef(3000);
+    const onUnauthorizedRef = useRef(onUnauthorized);
+    onUnauthorizedRef.current = onUnauthorized;
+    // Sessão atual; reconexões agendadas por uma sessão anterior são descartadas
+    const sessionRef = useRef(session);
+    sessionRef.current = session;
+
+    const sendAck = useCallback((messageId: string) => {
+        if (ws.current?.readyState === WebSocket.OPEN) {
+            const ack: Envelope = {
+                type: 'ack',
+                version: PROTOCOL_VERSION,
+                payload: { message_id: messageId },
+            };
+            ws.current.send(JSON.stringify(ack));
+        }
+    }, []);
 
     // Determinar URL do WebSocket baseado no ambiente
     const getWebSocketUrl = useCallback(() => {
         const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
         const host = window.location.hostname;
+        // Navegadores não enviam headers no upgrade: o token vai na query
+        const params = new URLSearchParams({ access_token: session?.accessToken ?? '' });
+        if (resumeToken.current) {
+            params.set('resume_token', resumeToken.current);
+            const roomSeqs = [...lastSeqByRoom.current].map(([room, seq]) => `${room}:${seq}`);
+            if (roomSeqs.length > 0) {
+                params.set('room_seqs', roomSeqs.join(','));
+            }
+        }
 
         // Se estiver rodando em desenvolvimento local
         if (host === 'localhost' || host === '127.0.0.1') {
             // Assumir que minikube está rodando na porta padrão
-            return `ws://192.168.49.2/ws/${encodeURIComponent(username)}`;
+            return `ws://192.168.49.2/ws?${params}`;
         }
 
         // Para produção ou outros ambientes
-        return `${protocol}//${host}/ws/${encodeURIComponent(username)}`;
-    }, [username]);
+        return `${protocol}//${host}/ws?${params}`;
+    }, [session]);
 
     const connect = useCallback(() => {
-        if (ws.current?.readyState === WebSocket.OPEN) {
+        if (ws.current?.readyState === WebSocket.OPEN || !session) {
+            return;
+        }
+        if (isExpired(session)) {
+            console.log('Token expirado, voltando ao login');
+            onUnauthorizedRef.current();
             return;
         }
 
         try {
             const wsUrl = getWebSocketUrl();
-            console.log('Conectando ao WebSocket:', wsUrl);
+            console.log('Conectando ao WebSocket');
 
-            ws.current = new WebSocket(wsUrl);
+            const socket = new WebSocket(wsUrl, `chat.v${PROTOCOL_VERSION}`);
+            ws.current = socket;
+            let opened = false;
 
-            ws.current.onopen = () => {
+            socket.onopen = () => {
                 console.log('WebSocket conectado');
+                opened = true;
                 setIsConnected(true);
                 setError(null);
             };
 
-            ws.current.onmessage = (event) => {
+            socket.onmessage = (event) => {
                 try {
-                    const data = JSON.parse(event.data);
-                    console.log('Mensagem recebida:', data);
+                    const frame: Envelope = JSON.parse(event.data);
+                    console.log('Frame recebido:', frame);
+
+                    if (frame.type === 'message') {
+                        const data = frame.payload as ChatMessagePayload;
+                        sendAck(data.message_id);
+                        if (seenMessageIds.current.has(data.message_id)) {
+                            return;
+                        }
+                        seenMessageIds.current.add(data.message_id);
+                        lastSeqByRoom.current.set(
+                            data.room_id,
+                            Math.max(lastSeqByRoom.current.get(data.room_id) ?? 0, data.seq),
+                        );
 
-                    if (data.username && data.content) {
                         const newMessage: Message = {
                             id: messageIdCounter.current++,
                             user: data.username,
@@ -74,28 +145,52 @@ export const useWebSocket = (username: string) => {
                             timestamp: new Date(),
                         };
                         setMessages(prev => [...prev, newMessage]);
-                    } else if (data.username && !data.content) {
-                        console.log('Evento de usuário:', data);
+                    } else if (frame.type === 'welcome') {
+                        resumeToken.current = (frame.payload as { session_id: string }).session_id;
+                    } else if (frame.type === 'reconnect') {
+                        // Pod encerrando: ele fecha a conexão no momento certo
+                        console.log('Servidor pediu reconexão:', frame.payload);
+                        resumeToken.current = (frame.payload as { resume_token: string }).resume_token;
+                        reconnectDelay.current = 0;
+                    } else if (frame.type === 'direct_message') {
+                        sendAck((frame.payload as { message_id: string }).message_id);
+                        console.log('Mensagem direta:', frame.payload);
+                    } else if (frame.type === 'error') {
+                        console.error('Erro do servidor:', frame.payload);
+                    } else {
+                        console.log('Evento:', frame);
                     }
                 } catch (err) {
                     console.error('Erro ao processar mensagem:', err);
                 }
             };
 
-            ws.current.onclose = (event) => {
+            socket.onclose = async (event) => {
                 console.log('WebSocket desconectado:', event.code, event.reason);
                 setIsConnected(false);
 
-                // Reconectar automaticamente após 3 segundos se não foi fechamento intencional
-                if (event.code !== 1000) {
-                    setTimeout(() => {
-                        console.log('Tentando reconectar...');
-                        connect();
-                    }, 3000);
+                // Reconectar automaticamente se não foi fechamento intencional
+                if (event.code === 1000) {
+                    return;
+                }
+                if (!opened && await isUnauthorized(wsUrl)) {
+                    console.log('Upgrade recusado com 401, voltando ao login');
+                    onUnauthorizedRef.current();
+                    return;
                 }
+
+                const delay = reconnectDelay.current;
+                reconnectDelay.current = 3000;
+                setTimeout(() => {
+                    if (sessionRef.current !== session) {
+                        return;
+                    }
+                    console.log('Tentando reconectar...');
+                    connect();
+                }, delay);
             };
 
-            ws.current.onerror = (error) => {
+            socket.onerror = (error) => {
                 console.error('Erro WebSocket:', error);
                 setError('Erro na conexão WebSocket');
                 setIsConnected(false);
@@ -104,7 +199,7 @@ export const useWebSocket = (username: string) => {
             console.error('Erro ao criar WebSocket:', err);
             setError('Falha ao conectar');
         }
-    }, [getWebSocketUrl]);
+    }, [session, getWebSocketUrl, sendAck]);
 
     const disconnect = useCallback(() => {
         if (ws.current) {
@@ -115,9 +210,10 @@ export const useWebSocket = (username: string) => {
 
     const sendMessage = useCallback((content: string) => {
         if (ws.current?.readyState === WebSocket.OPEN && content.trim()) {
-            const message: WebSocketMessage = {
-                username,
-                content: content.trim(),
+            const message: Envelope = {
+                type: 'message',
+                version: PROTOCOL_VERSION,
+                payload: { room_id: 'general', content: content.trim() },
             };
 
             console.log('Enviando mensagem:', message);
@@ -139,14 +235,14 @@ export const useWebSocket = (username: string) => {
 
     // Conectar automaticamente quando o hook é usado
     useEffect(() => {
-        if (username) {
+        if (session) {
             connect();
         }
 
         return () => {
             disconnect();
         };
-    }, [username, connect, disconnect]);
+    }, [session, connect, disconnect]);
 
     return {
         messages,
@@ -1,22 +1,55 @@
-import React, { useState, useRef, useEffect } from "react";
+import React, { useState, useRef, useEffect, useCallback } from "react";
 import { useWebSocket } from "../hooks/useWebSocket";
+import {
+  clearSession,
+  loadSession,
+  login,
+  register,
+  type AuthSession,
+} from "../services/auth";
 import "../assets/css/chat.css";
 
 const ChatScreen: React.FC = () => {
-  const [username, setUsername] = useState<string>("");
+  const [session, setSession] = useState<AuthSession | null>(loadSession);
+  const [username, setUsername] = useState<string>(session?.username ?? "");
+  const [password, setPassword] = useState("");
+  const [authError, setAuthError] = useState<string | null>(null);
+  const [authPending, setAuthPending] = useState(false);
   const [input, setInput] = useState("");
-  const [isUsernameSet, setIsUsernameSet] = useState(false);
   const messagesEndRef = useRef<HTMLDivElement>(null);
 
-  const { messages, isConnected, error, sendMessage } = useWebSocket(username);
+  // Token expirado ou recusado: volta para o login
+  const handleUnauthorized = useCallback(() => {
+    clearSession();
+    setSession(null);
+    setAuthError("Sessão expirada, entre novamente");
+  }, []);
 
-  const handleSetUsername = (e: React.FormEvent) => {
-    e.preventDefault();
-    if (username.trim()) {
-      setIsUsernameSet(true);
+  const { messages, isConnected, error, sendMessage } = useWebSocket(session, handleUnauthorized);
+
+  const authenticate = async (createAccount: boolean) => {
+    const name = username.trim();
+    if (!name || !password) {
+      return;
+    }
+
+    setAuthPending(true);
+    setAuthError(null);
+    try {
+      setSession(await (createAccount ? register(name, password) : login(name, password)));
+      setPassword("");
+    } catch (err) {
+      setAuthError(err instanceof Error ? err.message : "Falha ao autenticar");
+    } finally {
+      setAuthPending(false);
     }
   };
 
+  const handleLogin = (e: React.FormEvent) => {
+    e.preventDefault();
+    authenticate(false);
+  };
+
   const handleSendMessage = (e: React.FormEvent) => {
     e.preventDefault();
     if (input.trim() && sendMessage(input)) {
@@ -28,13 +61,15 @@ const ChatScreen: React.FC = () => {
     messagesEndRef.current?.scrollIntoView({ behavior: "smooth" });
   }, [messages]);
 
-  // Tela de entrada do usuário
-  if (!isUsernameSet) {
+  // Tela de login/cadastro
+  if (!session) {
+    const canSubmit = !authPending && username.trim() !== "" && password !== "";
+
     return (
         <div className="login-container">
           <div className="login-form">
-            <h2>Digite seu nome de usuário</h2>
-            <form onSubmit={handleSetUsername}>
+            <h2>Entre no chat</h2>
+            <form onSubmit={handleLogin}>
               <input
                   type="text"
                   placeholder="Nome de usuário"
@@ -42,15 +77,31 @@ const ChatScreen: React.FC = () => {
                   onChange={(e) => setUsername(e.target.value)}
                   autoFocus
               />
-              <button type="submit" disabled={!username.trim()}>
+              <input
+                  type="password"
+                  placeholder="Senha"
+                  value={password}
+                  onChange={(e) => setPassword(e.target.value)}
+              />
+              {authError && <p className="auth-error">{authError}</p>}
+              <button type="submit" disabled={!canSubmit}>
                 Entrar no Chat
               </button>
+              <button
+                  type="button"
+                  className="secondary"
+                  disabled={!canSubmit}
+                  onClick={() => authenticate(true)}
+              >
+                Criar conta
+              </button>
             </form>
           </div>
         </div>
     );
   }
 
+
   return (
       <div className="chat-container">
         <div className="chat-main">
@@ -69,14 +120,14 @@ const ChatScreen: React.FC = () => {
           <div className="chat-messages">
             {messages.length === 0 ? (
                 <div className="welcome-message">
-                  <p>Bem-vindo ao chat, {username}! 👋</p>
+                  <p>Bem-vindo ao chat, {session.username}! 👋</p>
                   <p>Digite uma mensagem para começar a conversar.</p>
                 </div>
             ) : (
                 messages.map((msg) => (
                     <div
                         key={msg.id}
-                        className={`message ${msg.user === username ? 'own-message' : ''}`}
+                        className={`message ${msg.user === session.username ? 'own-message' : ''}`}
                     >
                       <span className="message-user">{msg.user}:</span>{" "}
                       <span className="message-text">{msg.text}</span>
@@ -109,7 +160,7 @@ const ChatScreen: React.FC = () => {
           </header>
           <div className="current-user">
             <div className="user-info">
-              <span className="username">{username}</span>
+              <span className="username">{session.username}</span>
               <span className="user-status">
               {isConnected ? "Online" : "Offline"}
             </span>
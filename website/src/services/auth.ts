@@ -0,0 +1,94 @@
+// Sessão autenticada: token de `POST /api/auth/token`, exigido no upgrade WebSocket
+export interface AuthSession {
+    username: string;
+    accessToken: string;
+    // Epoch em ms; depois disso o servidor recusa o token com 401
+    expiresAt: number;
+}
+
+interface TokenResponse {
+    access_token: string;
+    token_type: string;
+    expires_in: number;
+}
+
+const SESSION_KEY = 'chat.session';
+
+export class AuthError extends Error {
+    readonly status: number;
+
+    constructor(status: number, message: string) {
+        super(message);
+        this.status = status;
+    }
+}
+
+// Mesma regra de host usada para o WebSocket
+export const getApiBaseUrl = () => {
+    const host = window.location.hostname;
+
+    if (host === 'localhost' || host === '127.0.0.1') {
+        return 'http://192.168.49.2/api';
+    }
+
+    return `${window.location.protocol}//${host}/api`;
+};
+
+const postCredentials = (path: string, username: string, password: string) =>
+    fetch(`${getApiBaseUrl()}${path}`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ username, password }),
+    });
+
+export const login = async (username: string, password: string): Promise<AuthSession> => {
+    const response = await postCredentials('/auth/token', username, password);
+    if (!response.ok) {
+        throw new AuthError(
+            response.status,
+            response.status === 401 ? 'Usuário ou senha inválidos' : 'Falha ao autenticar',
+        );
+    }
+
+    const token: TokenResponse = await response.json();
+    const session: AuthSession = {
+        username,
+        accessToken: token.access_token,
+        expiresAt: Date.now() + token.expires_in * 1000,
+    };
+    sessionStorage.setItem(SESSION_KEY, JSON.stringify(session));
+    return session;
+};
+
+// Cadastra o usuário e já emite o token
+export const register = async (username: string, password: string): Promise<AuthSession> => {
+    const response = await postCredentials('/users', username, password);
+    if (!response.ok) {
+        throw new AuthError(
+            response.status,
+            response.status === 409 ? 'Nome de usuário já existe' : 'Falha ao criar conta',
+        );
+    }
+
+    return login(username, password);
+};
+
+export const isExpired = (session: AuthSession) => Date.now() >= session.expiresAt;
+
+export const loadSession = (): AuthSession | null => {
+    const stored = sessionStorage.getItem(SESSION_KEY);
+    if (!stored) {
+        return null;
+    }
+
+    try {
+        const session: AuthSession = JSON.parse(stored);
+        return isExpired(session) ? null : session;
+    } catch {
+        return null;
+    }
+};
+
+export const clearSession = () => {
+    sessionStorage.removeItem(SESSION_KEY);
+};
@@ -1,14 +1,74 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::actors::relay::RelayActor;
 use crate::actors::ws::WsConn;
 
 pub mod ws;
 pub mod relay;
-pub mod redis_manager;
+pub mod persistence;
+#[cfg(test)]
+pub mod testing;
+
+/// Sala em que toda conexão entra ao se registrar num relay
+pub const DEFAULT_ROOM: &str = "general";
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+/// IDs de sala viram nomes de canais Redis, então só aceitamos um conjunto restrito
+pub fn is_valid_room_id(room_id: &str) -> bool {
+    !room_id.is_empty()
+        && room_id.len() <= 64
+        && room_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
 
 #[derive(actix::Message)]
 #[rtype(result="()")]
 pub struct RegisterConnection {
     pub username: String,
-    pub addr: actix::Addr<WsConn>
+    pub session_id: String,
+    pub addr: actix::Addr<WsConn>,
+    /// Presente quando o cliente quer retomar uma sessão anterior
+    pub resume: Option<ResumeRequest>,
+}
+
+/// Pedido de retomada feito no upgrade: o `session_id` da conexão anterior
+/// (o resume token) e a maior `seq` que o cliente já viu em cada sala
+#[derive(Debug, Clone)]
+pub struct ResumeRequest {
+    pub token: String,
+    /// `seq` única de clientes antigos, que só acompanham uma sala; vale
+    /// para todas as salas quando `room_seqs` não vem
+    pub last_seq: u64,
+    /// Maior `seq` vista em cada sala; uma sala ausente não teve nenhuma
+    pub room_seqs: HashMap<String, u64>,
+}
+
+impl ResumeRequest {
+    pub fn last_seq_in(&self, room_id: &str) -> u64 {
+        if self.room_seqs.is_empty() {
+            return self.last_seq;
+        }
+        self.room_seqs.get(room_id).copied().unwrap_or(0)
+    }
+}
+
+/// O que o relay guarda de cada sessão para permitir a retomada
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ResumeState {
+    pub username: String,
+    pub rooms: Vec<String>,
+}
+
+/// Resultado da retomada, enviado à nova sessão
+#[derive(actix::Message, Clone)]
+#[rtype(result="()")]
+pub enum ResumeOutcome {
+    /// Salas restauradas e `replayed` mensagens perdidas reenviadas
+    Resumed { replayed: usize },
+    /// Token desconhecido, expirado ou de outro usuário
+    Expired,
 }
 
 #[derive(actix::Message, Clone, serde::Serialize, serde::Deserialize)]
@@ -21,19 +81,211 @@ pub struct JoinEvent {
 #[rtype(result="()")]
 pub struct UnRegisterConnection {
     pub username: String,
+    /// Sessão encerrada; vazio quando o evento representa a saída do usuário
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub session_id: String,
 }
 
 #[derive(actix::Message, Clone, serde::Serialize, serde::Deserialize)]
 #[rtype(result="()")]
 pub struct UserMessage {
+    /// ID atribuído pelo servidor ao aceitar a mensagem; usado nos acks
+    #[serde(default)]
+    pub message_id: String,
+    /// Posição da mensagem na sala, usada na retomada; 0 se não foi sequenciada
+    #[serde(default)]
+    pub seq: u64,
     pub username: String,
-    pub content: String
+    pub content: String,
+    #[serde(default = "default_room")]
+    pub room_id: String,
+    /// Sessão que enviou a mensagem; só existe no relay de origem, para não
+    /// ecoar de volta ao próprio dispositivo
+    #[serde(skip)]
+    pub origin_session: Option<String>,
+}
+
+#[derive(actix::Message, Clone, serde::Serialize, serde::Deserialize)]
+#[rtype(result="()")]
+pub struct DirectMessage {
+    #[serde(default)]
+    pub message_id: String,
+    pub from: String,
+    pub to: String,
+    pub content: String,
+    /// Sessão que enviou a mensagem, para o ack; só existe no relay de origem
+    #[serde(skip)]
+    pub origin_session: Option<String>,
+}
+
+/// Avisa o remetente que o destinatário está offline e a mensagem ficou guardada
+#[derive(actix::Message, Clone)]
+#[rtype(result="()")]
+pub struct DirectMessageQueued {
+    pub message_id: String,
+    pub to: String,
+}
+
+/// Estado de uma mensagem do ponto de vista de quem a enviou
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    /// O relay aceitou e distribuiu a mensagem
+    Accepted,
+    /// A mensagem foi gravada no Postgres
+    Persisted,
+    /// A persistência descartou a mensagem (fila cheia)
+    Failed,
+}
+
+/// Ack enviado à sessão que originou `message_id`
+#[derive(actix::Message, Clone)]
+#[rtype(result="()")]
+pub struct MessageAck {
+    pub message_id: String,
+    pub status: AckStatus,
+}
+
+/// Confirmação de que `to` recebeu a mensagem direta `message_id` de `from`
+#[derive(actix::Message, Clone, serde::Serialize, serde::Deserialize)]
+#[rtype(result="()")]
+pub struct DeliveryReceipt {
+    pub message_id: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// Mensagem entregue a uma sessão e ainda não confirmada pelo cliente
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PendingDelivery {
+    Room(UserMessage),
+    Direct(DirectMessage),
+}
+
+impl PendingDelivery {
+    pub fn message_id(&self) -> &str {
+        match self {
+            PendingDelivery::Room(msg) => &msg.message_id,
+            PendingDelivery::Direct(msg) => &msg.message_id,
+        }
+    }
+}
+
+/// Mensagens sem ack de uma sessão encerrada, para retransmitir na reconexão
+#[derive(actix::Message)]
+#[rtype(result="()")]
+pub struct StoreUnacked {
+    pub username: String,
+    pub deliveries: Vec<PendingDelivery>,
+}
+
+#[derive(actix::Message, Clone, serde::Serialize, serde::Deserialize)]
+#[rtype(result="()")]
+pub struct JoinRoom {
+    pub username: String,
+    pub room_id: String,
+}
+
+#[derive(actix::Message, Clone, serde::Serialize, serde::Deserialize)]
+#[rtype(result="()")]
+pub struct LeaveRoom {
+    pub username: String,
+    pub room_id: String,
 }
 
 #[derive(actix::Message)]
 #[rtype(result="crate::actors::relay::RelayMetrics")]
 pub struct GetMetrics;
 
+/// Pede ao relay atual de `username` que passe todas as sessões locais dele
+/// para `to`, sem derrubar as conexões. Retorna `false` se o usuário não
+/// tinha sessões neste relay.
+#[derive(actix::Message)]
+#[rtype(result="bool")]
+pub struct MigrateUser {
+    pub username: String,
+    pub to_relay_id: u32,
+    pub to: actix::Addr<RelayActor>,
+}
+
+/// Sessões e salas de um usuário migrado, entregues ao relay de destino
+#[derive(actix::Message)]
+#[rtype(result="()")]
+pub struct AttachUser {
+    pub username: String,
+    pub sessions: HashMap<String, actix::Addr<WsConn>>,
+    pub rooms: Vec<String>,
+}
+
+/// Aponta a conexão para outro relay depois de uma migração
+#[derive(actix::Message)]
+#[rtype(result="()")]
+pub struct SwitchRelay {
+    pub relay_actor: actix::Addr<RelayActor>,
+}
+
+/// Usuários com sessões no relay, para drená-lo
+#[derive(actix::Message)]
+#[rtype(result="Vec<String>")]
+pub struct GetLocalUsers;
+
+/// Encerra um relay drenado: cancela as assinaturas e para o ator. Retorna
+/// `false` (e não para) se ainda houver sessões nele.
+#[derive(actix::Message)]
+#[rtype(result="bool")]
+pub struct RetireRelay;
+
+/// O pod está encerrando: avisa cada sessão do relay para reconectar em
+/// outro pod, com atrasos sorteados até `max_jitter` para não reconectarem
+/// todas juntas. Retorna quantas sessões foram avisadas.
+#[derive(actix::Message)]
+#[rtype(result="usize")]
+pub struct DrainConnections {
+    pub max_jitter: Duration,
+}
+
+/// Pede ao cliente que reconecte em outro pod depois de `retry_after`,
+/// quando a conexão é fechada
+#[derive(actix::Message)]
+#[rtype(result="()")]
+pub struct Reconnect {
+    pub retry_after: Duration,
+}
+
+/// Prazo de encerramento esgotado: remove do bus a localização das sessões
+/// que ainda restam no relay. Retorna quantas foram removidas.
+#[derive(actix::Message)]
+#[rtype(result="usize")]
+pub struct ReleaseSessions;
+
+/// Mensagem do bus que chegou ao relay antigo de um usuário migrado
+#[derive(actix::Message)]
+#[rtype(result="()")]
+pub struct ForwardRedisMessage(pub RedisMessage);
+
+#[derive(actix::Message)]
+#[rtype(result="()")]
+pub struct PersistMessage {
+    pub message_id: String,
+    pub username: String,
+    pub content: String,
+    pub room_id: String,
+    pub timestamp: time::OffsetDateTime,
+    /// Sessão que recebe o ack de persistência (ou de falha)
+    pub ack_to: Option<actix::Recipient<MessageAck>>,
+}
+
+#[derive(actix::Message)]
+#[rtype(result="crate::actors::persistence::PersistenceStats")]
+pub struct GetPersistenceStats;
+
+/// Envia tudo o que está na fila de persistência; responde quando a fila
+/// esvazia. Usado no encerramento do pod, com um prazo do lado de quem pede.
+#[derive(actix::Message)]
+#[rtype(result="()")]
+pub struct FlushPersistence;
+
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct RedisMessage {
     pub from_pod_id: String,
@@ -47,5 +299,51 @@ pub enum RedisMessageType {
     UserMessage(UserMessage),
     JoinEvent(JoinEvent),
     UnRegisterConnection(UnRegisterConnection),
+    JoinRoom(JoinRoom),
+    LeaveRoom(LeaveRoom),
+    DirectMessage(DirectMessage),
+    DeliveryReceipt(DeliveryReceipt),
     RelayHeartbeat { relay_id: u32, active_connections: usize },
-}
\ No newline at end of file
+    PodMetrics(crate::load_balancer::PodMetrics),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resume(last_seq: u64, room_seqs: &[(&str, u64)]) -> ResumeRequest {
+        ResumeRequest {
+            token: "sessao-anterior".to_string(),
+            last_seq,
+            room_seqs: room_seqs.iter().map(|(room_id, seq)| (room_id.to_string(), *seq)).collect(),
+        }
+    }
+
+    #[test]
+    fn room_seqs_take_precedence_and_missing_rooms_start_from_zero() {
+        let request = resume(40, &[("general", 12), ("dev", 40)]);
+
+        assert_eq!(request.last_seq_in("general"), 12);
+        assert_eq!(request.last_seq_in("dev"), 40);
+        // Sem mensagens vistas na sala: reenvia todo o buffer dela
+        assert_eq!(request.last_seq_in("random"), 0);
+    }
+
+    #[test]
+    fn single_last_seq_applies_to_every_room() {
+        let request = resume(7, &[]);
+
+        assert_eq!(request.last_seq_in("general"), 7);
+        assert_eq!(request.last_seq_in("random"), 7);
+    }
+
+    #[test]
+    fn room_ids_are_restricted_to_safe_channel_names() {
+        assert!(is_valid_room_id("general"));
+        assert!(is_valid_room_id("time-dev_2"));
+        assert!(!is_valid_room_id(""));
+        assert!(!is_valid_room_id("sala com espaço"));
+        assert!(!is_valid_room_id("room:general"));
+        assert!(!is_valid_room_id(&"a".repeat(65)));
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::VecDeque;
+use std::env;
+use std::time::Duration;
+use actix::{Actor, ActorFutureExt, AsyncContext, Context, Handler, ResponseFuture, WrapFuture};
+use log::{debug, info, warn};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+use crate::actors::{AckStatus, FlushPersistence, GetPersistenceStats, MessageAck, PersistMessage};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Maior lote aceito pelo `POST /messages/batch` (`MAX_BATCH_SIZE` do
+/// webserver); acima disso ele responde 413
+const MAX_BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Clone)]
+pub struct PersistenceConfig {
+    pub endpoint: String,
+    /// Token de serviço exigido pelo `POST /messages/batch`
+    pub service_token: String,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    pub queue_capacity: usize,
+    pub retry_backoff: Duration,
+}
+
+impl PersistenceConfig {
+    pub fn from_env() -> Self {
+        let webserver_url = env::var("WEBSERVER_URL")
+            .unwrap_or_else(|_| "http://webserver-service:8080".to_string());
+        let service_token = env::var("PERSIST_SERVICE_TOKEN").unwrap_or_default();
+        if service_token.is_empty() {
+            warn!("PERSIST_SERVICE_TOKEN não definido; o webserver vai recusar os lotes");
+        }
+
+        let batch_size = env_or("PERSIST_BATCH_SIZE", 100);
+        if batch_size > MAX_BATCH_SIZE {
+            warn!("PERSIST_BATCH_SIZE={} acima do limite do webserver, usando {}", batch_size, MAX_BATCH_SIZE);
+        }
+
+        Self {
+            endpoint: format!("{}/messages/batch", webserver_url.trim_end_matches('/')),
+            service_token,
+            batch_size: batch_size.clamp(1, MAX_BATCH_SIZE),
+            flush_interval: Duration::from_millis(env_or("PERSIST_FLUSH_INTERVAL_MS", 500)),
+            queue_capacity: env_or("PERSIST_QUEUE_CAPACITY", 10_000),
+            retry_backoff: Duration::from_millis(env_or("PERSIST_RETRY_BACKOFF_MS", 500)),
+        }
+    }
+}
+
+pub(crate) fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PersistenceStats {
+    /// Mensagens ainda não gravadas, incluindo o lote em envio
+    pub queued: usize,
+    pub persisted: u64,
+    pub dropped: u64,
+    pub failed_batches: u64,
+}
+
+#[derive(Serialize)]
+struct BatchItem {
+    message_id: String,
+    username: String,
+    content: String,
+    room_id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    timestamp: OffsetDateTime,
+    #[serde(skip)]
+    ack_to: Option<actix::Recipient<MessageAck>>,
+}
+
+impl BatchItem {
+    fn ack(&self, status: AckStatus) {
+        if let Some(ack_to) = &self.ack_to {
+            ack_to.do_send(MessageAck {
+                message_id: self.message_id.clone(),
+                status,
+            });
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BatchRequest<'a> {
+    messages: &'a [BatchItem],
+}
+
+/// Grava no Postgres (via `POST /messages/batch` do webserver) toda mensagem
+/// aceita pelos relays deste pod. As mensagens ficam numa fila limitada e são
+/// enviadas em lotes; um lote que falha volta para o início da fila e é
+/// reenviado com backoff exponencial enquanto o webserver estiver fora. Só
+/// quando a fila passa de `queue_capacity` as mensagens mais antigas são
+/// descartadas. A sessão de origem recebe um ack `persisted` ou, se a
+/// mensagem for descartada, `failed`; o webserver ignora `message_id`
+/// repetido, então reenvios não duplicam.
+pub struct PersistenceActor {
+    config: PersistenceConfig,
+    client: awc::Client,
+    queue: VecDeque<BatchItem>,
+    in_flight: bool,
+    /// Tamanho do lote em envio, fora de `queue` até a resposta
+    sending: usize,
+    retries: u32,
+    stats: PersistenceStats,
+    /// Pedidos de `FlushPersistence` esperando a fila esvaziar
+    flush_waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl PersistenceActor {
+    pub fn new(config: PersistenceConfig) -> Self {
+        Self {
+            config,
+            client: awc::Client::default(),
+            queue: VecDeque::new(),
+            in_flight: false,
+            sending: 0,
+            retries: 0,
+            stats: PersistenceStats::default(),
+            flush_waiters: Vec::new(),
+        }
+    }
+
+    fn enqueue(&mut self, item: BatchItem) {
+        self.queue.push_back(item);
+        self.trim_to_capacity();
+    }
+
+    /// Fila cheia: descarta as mensagens mais antigas para não crescer sem limite
+    fn trim_to_capacity(&mut self) {
+        let excess = self.queue.len().saturating_sub(self.config.queue_capacity);
+        if excess == 0 {
+            return;
+        }
+
+        for dropped in self.queue.drain(..excess) {
+            dropped.ack(AckStatus::Failed);
+        }
+        self.stats.dropped += excess as u64;
+        warn!("Fila de persistência cheia ({}), {} mensagens mais antigas descartadas",
+              self.config.queue_capacity, excess);
+    }
+
+    fn is_flushed(&self) -> bool {
+        self.queue.is_empty() && !self.in_flight
+    }
+
+    fn notify_flushed(&mut self) {
+        if self.is_flushed() {
+            for waiter in self.flush_waiters.drain(..) {
+                let _ = waiter.send(());
+            }
+        }
+    }
+
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        if self.in_flight || self.queue.is_empty() {
+            return;
+        }
+
+        let count = self.queue.len().min(self.config.batch_size);
+        let batch: Vec<BatchItem> = self.queue.drain(..count).collect();
+        self.in_flight = true;
+        self.sending = batch.len();
+
+        let request = self.client
+            .post(&self.config.endpoint)
+            .timeout(Duration::from_secs(10))
+            .bearer_auth(&self.config.service_token)
+            .send_json(&BatchRequest { messages: &batch });
+
+        let fut = async move {
+            match request.await {
+                Ok(response) if response.status().is_success() => Ok(()),
+                Ok(response) => Err(format!("status {}", response.status())),
+                Err(e) => Err(e.to_string()),
+            }
+        };
+
+        let fut = fut.into_actor(self).map(move |result, act, ctx| {
+            act.in_flight = false;
+            act.sending = 0;
+
+            match result {
+                Ok(()) => {
+                    debug!("Lote de {} mensagens persistido", batch.len());
+                    act.stats.persisted += batch.len() as u64;
+                    act.retries = 0;
+                    for item in &batch {
+                        item.ack(AckStatus::Persisted);
+                    }
+
+                    // Com alguém esperando o flush, não espera o próximo intervalo
+                    if act.queue.len() >= act.config.batch_size || !act.flush_waiters.is_empty() {
+                        act.flush(ctx);
+                    }
+                    act.notify_flushed();
+                }
+                Err(e) => {
+                    act.stats.failed_batches += 1;
+                    act.retries += 1;
+
+                    // Devolve o lote ao início da fila, preservando a ordem
+                    for item in batch.into_iter().rev() {
+                        act.queue.push_front(item);
+                    }
+                    act.trim_to_capacity();
+
+                    let backoff = act.config.retry_backoff
+                        .saturating_mul(2u32.saturating_pow(act.retries - 1))
+                        .min(MAX_BACKOFF);
+                    warn!("Falha ao persistir lote (tentativa {}), nova tentativa em {:?}: {}",
+                          act.retries, backoff, e);
+                    act.in_flight = true;
+                    ctx.run_later(backoff, |act, ctx| {
+                        act.in_flight = false;
+                        act.flush(ctx);
+                    });
+                }
+            }
+        });
+
+        ctx.spawn(fut);
+    }
+}
+
+impl Actor for PersistenceActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("PersistenceActor iniciado: endpoint {}, lotes de até {} mensagens",
+              self.config.endpoint, self.config.batch_size);
+
+        ctx.run_interval(self.config.flush_interval, |act, ctx| {
+            act.flush(ctx);
+        });
+    }
+}
+
+impl Handler<PersistMessage> for PersistenceActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PersistMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.enqueue(BatchItem {
+            message_id: msg.message_id,
+            username: msg.username,
+            content: msg.content,
+            room_id: msg.room_id,
+            timestamp: msg.timestamp,
+            ack_to: msg.ack_to,
+        });
+
+        if self.queue.len() >= self.config.batch_size {
+            self.flush(ctx);
+        }
+    }
+}
+
+impl Handler<FlushPersistence> for PersistenceActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: FlushPersistence, ctx: &mut Self::Context) -> Self::Result {
+        if self.is_flushed() {
+            return Box::pin(async {});
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.flush_waiters.push(tx);
+        self.flush(ctx);
+        Box::pin(async move {
+            let _ = rx.await;
+        })
+    }
+}
+
+impl Handler<GetPersistenceStats> for PersistenceActor {
+    type Result = actix::MessageResult<GetPersistenceStats>;
+
+    fn handle(&mut self, _msg: GetPersistenceStats, _ctx: &mut Self::Context) -> Self::Result {
+        actix::MessageResult(PersistenceStats {
+            queued: self.queue.len() + self.sending,
+            ..self.stats.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(queue_capacity: usize) -> PersistenceConfig {
+        config_for("http://webserver.invalid/messages/batch", queue_capacity)
+    }
+
+    fn config_for(endpoint: &str, queue_capacity: usize) -> PersistenceConfig {
+        PersistenceConfig {
+            endpoint: endpoint.to_string(),
+            service_token: String::new(),
+            batch_size: 10,
+            flush_interval: Duration::from_secs(60),
+            queue_capacity,
+            retry_backoff: Duration::from_millis(10),
+        }
+    }
+
+    fn item(message_id: &str) -> BatchItem {
+        BatchItem {
+            message_id: message_id.to_string(),
+            username: "alice".to_string(),
+            content: "oi".to_string(),
+            room_id: "general".to_string(),
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            ack_to: None,
+        }
+    }
+
+    fn persist_message(message_id: &str) -> PersistMessage {
+        PersistMessage {
+            message_id: message_id.to_string(),
+            username: "alice".to_string(),
+            content: "oi".to_string(),
+            room_id: "general".to_string(),
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            ack_to: None,
+        }
+    }
+
+    /// Webserver falso: responde `201` a cada requisição e conta quantas recebeu
+    async fn fake_webserver() -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/messages/batch", listener.local_addr().unwrap());
+        let requests = std::sync::Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    // Lê cabeçalhos e corpo inteiros antes de responder
+                    loop {
+                        let Ok(n) = socket.read(&mut buf).await else { return };
+                        if n == 0 {
+                            return;
+                        }
+                        received.extend_from_slice(&buf[..n]);
+                        let text = String::from_utf8_lossy(&received).to_lowercase();
+                        let Some(header_end) = text.find("\r\n\r\n") else { continue };
+                        let content_length = text.lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .and_then(|value| value.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if received.len() >= header_end + 4 + content_length {
+                            break;
+                        }
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let _ = socket.write_all(b"HTTP/1.1 201 Created\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+                });
+            }
+        });
+
+        (endpoint, requests)
+    }
+
+    fn queued_ids(actor: &PersistenceActor) -> Vec<&str> {
+        actor.queue.iter().map(|item| item.message_id.as_str()).collect()
+    }
+
+    #[actix::test]
+    async fn full_queue_drops_oldest_messages() {
+        let mut actor = PersistenceActor::new(config(2));
+        for message_id in ["m1", "m2", "m3"] {
+            actor.enqueue(item(message_id));
+        }
+
+        assert_eq!(queued_ids(&actor), ["m2", "m3"]);
+        assert_eq!(actor.stats.dropped, 1);
+    }
+
+    #[actix::test]
+    async fn requeued_batch_is_trimmed_from_the_oldest_end() {
+        let mut actor = PersistenceActor::new(config(3));
+        actor.enqueue(item("m3"));
+        actor.enqueue(item("m4"));
+
+        // Mesmo caminho do lote que falhou: volta ao início e a fila é aparada
+        for message_id in ["m2", "m1"] {
+            actor.queue.push_front(item(message_id));
+        }
+        actor.trim_to_capacity();
+
+        assert_eq!(queued_ids(&actor), ["m2", "m3", "m4"]);
+        assert_eq!(actor.stats.dropped, 1);
+    }
+
+    #[actix::test]
+    async fn flush_persists_queue_before_the_next_interval() {
+        let (endpoint, requests) = fake_webserver().await;
+        let mut config = config_for(&endpoint, 100);
+        config.batch_size = 2;
+        let actor = PersistenceActor::new(config).start();
+        for message_id in ["m1", "m2", "m3", "m4", "m5"] {
+            actor.do_send(persist_message(message_id));
+        }
+
+        // O intervalo é de 60s: só o flush explícito grava o resto da fila
+        tokio::time::timeout(Duration::from_secs(5), actor.send(FlushPersistence)).await
+            .expect("flush não terminou")
+            .unwrap();
+
+        let stats = actor.send(GetPersistenceStats).await.unwrap();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.persisted, 5);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[actix::test]
+    async fn flush_of_empty_queue_returns_immediately() {
+        let actor = PersistenceActor::new(config(10)).start();
+
+        tokio::time::timeout(Duration::from_millis(100), actor.send(FlushPersistence)).await
+            .expect("flush de fila vazia não deveria esperar")
+            .unwrap();
+    }
+}
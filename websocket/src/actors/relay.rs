@@ -1,19 +1,65 @@
 use crate::actors::ws::WsConn;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
-use actix::{Actor, Context, Handler, AsyncContext, ActorFutureExt, WrapFuture};
+use actix::{Actor, ActorContext, Context, Handler, AsyncContext, ActorFutureExt, ResponseActFuture, WrapFuture};
+use rand::Rng;
 use tokio::sync::mpsc;
 use crate::actors::{
     JoinEvent, RegisterConnection, UnRegisterConnection, UserMessage,
-    RedisMessage, RedisMessageType, GetMetrics
+    RedisMessage, RedisMessageType, GetMetrics, PersistMessage,
+    JoinRoom, LeaveRoom, DirectMessage, DirectMessageQueued, DEFAULT_ROOM,
+    AckStatus, DeliveryReceipt, MessageAck, PendingDelivery, StoreUnacked,
+    ResumeOutcome, ResumeRequest, ResumeState, is_valid_room_id,
+    AttachUser, ForwardRedisMessage, MigrateUser, SwitchRelay, GetLocalUsers, RetireRelay,
+    DrainConnections, Reconnect, ReleaseSessions
 };
-use crate::redis_cluster::RedisClusterManager;
+use crate::actors::persistence::PersistenceActor;
+use crate::channels::{Channel, ChannelSubscriptions};
+use crate::bus::{BusError, MessageBus};
+use crate::dynamic_relay_balancer::UserDeparture;
+
+/// Sessões e salas de um relay mantidas fora do ator: se ele morrer, a nova
+/// instância com o mesmo id recupera as conexões que sobreviveram
+#[derive(Clone, Default)]
+pub struct RelayRegistry {
+    users: Arc<Mutex<HashMap<String, RegisteredUser>>>,
+}
+
+#[derive(Clone)]
+struct RegisteredUser {
+    sessions: HashMap<String, actix::Addr<WsConn>>,
+    rooms: Vec<String>,
+}
+
+impl RelayRegistry {
+    fn users(&self) -> MutexGuard<'_, HashMap<String, RegisteredUser>> {
+        self.users.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Por quanto tempo o relay antigo repassa o que ainda chega para um usuário migrado
+const MIGRATION_FORWARD_WINDOW: Duration = Duration::from_secs(30);
 
 pub struct RelayActor {
     relay_id: u32,
-    connections: HashMap<String, actix::Addr<WsConn>>,
-    redis_manager: RedisClusterManager,
-    redis_receiver: Option<mpsc::UnboundedReceiver<RedisMessage>>,
+    /// Sessões locais de cada usuário (username -> session_id -> conexão)
+    connections: HashMap<String, HashMap<String, actix::Addr<WsConn>>>,
+    /// Membros locais de cada sala (sala -> usernames conectados neste relay)
+    rooms: HashMap<String, HashSet<String>>,
+    bus: Arc<dyn MessageBus>,
+    redis_receiver: mpsc::UnboundedReceiver<RedisMessage>,
+    /// Controle e inbox sempre, mais uma assinatura por sala com membros locais
+    channels: ChannelSubscriptions,
+    persistence: actix::Addr<PersistenceActor>,
+    /// Usuários migrados para outro relay do pod e quando; mensagens que
+    /// ainda chegam aqui para eles são repassadas ao novo relay
+    migrated: HashMap<String, (actix::Addr<RelayActor>, Instant)>,
+    registry: RelayRegistry,
+    /// Avisa o balanceador quando um usuário não tem mais sessões aqui
+    departures: mpsc::UnboundedSender<UserDeparture>,
+    /// Aposentado pelo pool: se o supervisor o reiniciar, fica parado
+    retired: bool,
     last_heartbeat: Instant,
     metrics: RelayMetrics,
 }
@@ -27,14 +73,28 @@ pub struct RelayMetrics {
 }
 
 impl RelayActor {
-    pub async fn new(relay_id: u32) -> Result<Self, redis::RedisError> {
-        let redis_manager = RedisClusterManager::new()?;
-
-        Ok(Self {
+    pub fn new(
+        relay_id: u32,
+        persistence: actix::Addr<PersistenceActor>,
+        bus: Arc<dyn MessageBus>,
+        registry: RelayRegistry,
+        departures: mpsc::UnboundedSender<UserDeparture>,
+    ) -> Self {
+        let (redis_sender, redis_receiver) = mpsc::unbounded_channel();
+        let channels = ChannelSubscriptions::new(bus.clone(), relay_id, redis_sender);
+
+        Self {
             relay_id,
             connections: HashMap::new(),
-            redis_manager,
-            redis_receiver: None,
+            rooms: HashMap::new(),
+            bus,
+            redis_receiver,
+            channels,
+            persistence,
+            migrated: HashMap::new(),
+            registry,
+            departures,
+            retired: false,
             last_heartbeat: Instant::now(),
             metrics: RelayMetrics {
                 active_connections: 0,
@@ -42,22 +102,34 @@ impl RelayActor {
                 last_message_time: Instant::now(),
                 avg_response_time: 0.0,
             },
-        })
+        }
     }
 
     fn start_heartbeat(&self, ctx: &mut Context<Self>) {
-        ctx.run_interval(Duration::from_secs(15), |act, _ctx| {
-            let redis_manager = act.redis_manager.clone();
+        ctx.run_interval(Duration::from_secs(15), |act, ctx| {
+            let bus = act.bus.clone();
             let relay_id = act.relay_id;
-            let active_connections = act.connections.len();
-
-            let channel = format!("relay_heartbeat_{}", relay_id);
-            let fallback_channel = "relay_heartbeat_global";
+            let active_connections = act.session_count();
+            let sessions: Vec<(String, String)> = act.connections.iter()
+                .flat_map(|(username, sessions)| {
+                    sessions.keys().map(move |session_id| (username.clone(), session_id.clone()))
+                })
+                .collect();
+            // Renova também os resume tokens das sessões vivas
+            let resume_states: Vec<(String, ResumeState)> = sessions.iter()
+                .map(|(username, session_id)| (session_id.clone(), act.resume_state(username)))
+                .collect();
+            act.save_resume_states(resume_states, ctx);
+            act.migrated.retain(|_, (_, migrated_at)| migrated_at.elapsed() < MIGRATION_FORWARD_WINDOW);
 
             let fut = async move {
-                if let Err(e) = redis_manager.publish_with_fallback(
-                    &channel,
-                    fallback_channel,
+                // Mantém as sessões em `user_location` vivas enquanto as conexões durarem
+                if let Err(e) = bus.refresh_user_sessions(sessions, relay_id).await {
+                    eprintln!("Relay {}: Erro ao renovar localização dos usuários: {}", relay_id, e);
+                }
+
+                if let Err(e) = bus.publish(
+                    &Channel::Control.name(),
                     relay_id,
                     RedisMessageType::RelayHeartbeat {
                         relay_id,
@@ -68,53 +140,449 @@ impl RelayActor {
                 }
             };
 
-            _ctx.spawn(fut.into_actor(act));
+            ctx.spawn(fut.into_actor(act));
             act.last_heartbeat = Instant::now();
         });
     }
 
-    fn start_redis_listener(&mut self, ctx: &mut Context<Self>) {
-        let redis_manager = self.redis_manager.clone();
+    fn start_redis_listener(&mut self) {
+        self.channels.subscribe_all();
+
+        println!("Relay {}: Conectado ao message bus {}", self.relay_id, self.bus.name());
+    }
+
+    /// Reassina só os canais que mudaram de nó quando a topologia do bus muda
+    fn watch_ring_changes(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_secs(1), |act, _ctx| {
+            if let Some(moved) = act.channels.resubscribe_moved() {
+                println!("Relay {}: topologia do bus mudou, {} canais reassinados", act.relay_id, moved);
+            }
+        });
+    }
+
+    fn poll_redis_messages(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_millis(5), |act, ctx| {
+            let mut messages = Vec::new();
+            let start_time = Instant::now();
+
+            // Processar até 10 mensagens por vez para evitar bloqueio
+            for _ in 0..10 {
+                match act.redis_receiver.try_recv() {
+                    Ok(message) => messages.push(message),
+                    Err(_) => break,
+                }
+            }
+
+            let processed = !messages.is_empty();
+            for message in messages {
+                act.handle_redis_message(message, ctx);
+            }
+
+            // Atualizar métricas de resposta
+            if processed {
+                let processing_time = start_time.elapsed().as_millis() as f64;
+                act.update_response_time(processing_time);
+            }
+        });
+    }
+
+    fn session_count(&self) -> usize {
+        self.connections.values().map(HashMap::len).sum()
+    }
+
+    fn session_addr(&self, username: &str, session_id: Option<&str>) -> Option<&actix::Addr<WsConn>> {
+        self.connections.get(username)?.get(session_id?)
+    }
+
+    /// Envia um ack à sessão que originou a mensagem, se ela ainda estiver aqui
+    fn ack(&self, username: &str, session_id: Option<&str>, message_id: &str, status: AckStatus) {
+        if let Some(connection) = self.session_addr(username, session_id) {
+            connection.do_send(MessageAck {
+                message_id: message_id.to_string(),
+                status,
+            });
+        }
+    }
+
+    /// Entrega `msg` a todas as sessões locais de `username`; retorna se havia alguma
+    fn send_to_user<M>(&self, username: &str, msg: M) -> bool
+    where
+        M: actix::Message<Result = ()> + Clone + Send + 'static,
+        WsConn: Handler<M>,
+    {
+        let Some(sessions) = self.connections.get(username) else {
+            return false;
+        };
+
+        for connection in sessions.values() {
+            connection.do_send(msg.clone());
+        }
+        !sessions.is_empty()
+    }
+
+    /// Entrega `msg` a todas as sessões locais, exceto as de `except_user`
+    fn send_to_all<M>(&self, except_user: Option<&str>, msg: M)
+    where
+        M: actix::Message<Result = ()> + Clone + Send + 'static,
+        WsConn: Handler<M>,
+    {
+        for (username, sessions) in self.connections.iter() {
+            if except_user == Some(username.as_str()) {
+                continue;
+            }
+            for connection in sessions.values() {
+                connection.do_send(msg.clone());
+            }
+        }
+    }
+
+    /// Entrega `msg` às sessões dos membros locais da sala, exceto a sessão
+    /// `except_session` (os outros dispositivos do remetente também recebem)
+    fn send_to_room<M>(&self, room_id: &str, except_session: Option<&str>, msg: M)
+    where
+        M: actix::Message<Result = ()> + Clone + Send + 'static,
+        WsConn: Handler<M>,
+    {
+        let Some(members) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        for username in members {
+            let Some(sessions) = self.connections.get(username) else {
+                continue;
+            };
+            for (session_id, connection) in sessions {
+                if except_session == Some(session_id.as_str()) {
+                    continue;
+                }
+                connection.do_send(msg.clone());
+            }
+        }
+    }
+
+    fn publish_to_room(&self, ctx: &mut Context<Self>, room_id: &str, message_type: RedisMessageType) {
+        let bus = self.bus.clone();
         let relay_id = self.relay_id;
+        let channel = Channel::room(room_id).name();
+
+        let fut = async move {
+            if let Err(e) = bus.publish(&channel, relay_id, message_type).await {
+                eprintln!("Relay {}: Falha ao publicar no canal {}: {}", relay_id, channel, e);
+            }
+        };
+
+        ctx.spawn(fut.into_actor(self));
+    }
+
+    /// Relay para onde `username` foi migrado há pouco, se não tem mais sessões aqui
+    fn migrated_to(&self, username: &str) -> Option<&actix::Addr<RelayActor>> {
+        if self.connections.contains_key(username) {
+            return None;
+        }
+        self.migrated.get(username).map(|(relay, _)| relay)
+    }
+
+    /// Espelha no registro as sessões e salas atuais do usuário
+    fn sync_registry(&self, username: &str) {
+        let mut users = self.registry.users();
+        let Some(sessions) = self.connections.get(username) else {
+            users.remove(username);
+            return;
+        };
+
+        let rooms = self.rooms.iter()
+            .filter(|(_, members)| members.contains(username))
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+        users.insert(username.to_string(), RegisteredUser { sessions: sessions.clone(), rooms });
+    }
+
+    /// Nova instância depois que a anterior morreu: retoma as conexões
+    /// registradas que continuam vivas e as salas em que estavam
+    fn recover_sessions(&mut self, ctx: &mut Context<Self>) {
+        let users: Vec<(String, RegisteredUser)> = self.registry.users().drain().collect();
+        if users.is_empty() {
+            return;
+        }
+
+        let mut lost_sessions = Vec::new();
+        for (username, user) in users {
+            let (sessions, lost): (HashMap<_, _>, HashMap<_, _>) = user.sessions.into_iter()
+                .partition(|(_, connection)| connection.connected());
+            lost_sessions.extend(lost.into_keys().map(|session_id| (username.clone(), session_id)));
+            if sessions.is_empty() {
+                continue;
+            }
+
+            for connection in sessions.values() {
+                connection.do_send(SwitchRelay { relay_actor: ctx.address() });
+            }
+            self.connections.insert(username.clone(), sessions);
+            for room_id in &user.rooms {
+                self.add_room_member(&username, room_id);
+            }
+            self.sync_registry(&username);
+        }
+        self.metrics.active_connections = self.session_count();
+
+        println!("Relay {}: {} sessões recuperadas, {} perdidas enquanto o relay estava fora",
+                 self.relay_id, self.session_count(), lost_sessions.len());
 
+        // Conexões que caíram enquanto o relay estava morto não chegaram a se desregistrar
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
         let fut = async move {
-            // Subscribe a múltiplos canais para redundância
-            let channels = vec![
-                format!("relay_messages_{}", relay_id),
-                "relay_messages_global".to_string(),
-                format!("relay_events_{}", relay_id),
-            ];
-
-            // Usar o primeiro canal disponível
-            for channel in channels {
-                match redis_manager.subscribe_to_channel(&channel).await {
-                    Ok(receiver) => return Ok(receiver),
+            for (username, session_id) in lost_sessions {
+                if let Err(e) = bus.remove_user_session(&username, &session_id).await {
+                    eprintln!("Relay {}: Falha ao remover sessão perdida de {}: {}", relay_id, username, e);
+                }
+            }
+        };
+        ctx.spawn(fut.into_actor(self));
+    }
+
+    /// Inclui o usuário nos membros locais da sala, assinando o canal se ele
+    /// for o primeiro; retorna `false` se já era membro
+    fn add_room_member(&mut self, username: &str, room_id: &str) -> bool {
+        let members = self.rooms.entry(room_id.to_string()).or_default();
+        if !members.insert(username.to_string()) {
+            return false;
+        }
+
+        let channel = Channel::room(room_id);
+        if !self.channels.is_subscribed(&channel) {
+            self.channels.subscribe(channel);
+        }
+        self.sync_registry(username);
+        true
+    }
+
+    /// Tira o usuário dos membros locais da sala
+    fn remove_room_member(&mut self, username: &str, room_id: &str) {
+        let Some(members) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+        members.remove(username);
+        if members.is_empty() {
+            // Último membro local saiu: não precisamos mais do canal da sala
+            self.rooms.remove(room_id);
+            self.channels.unsubscribe(&Channel::room(room_id));
+        }
+        self.sync_registry(username);
+    }
+
+    fn join_room(&mut self, username: &str, room_id: &str, ctx: &mut Context<Self>) {
+        if !self.add_room_member(username, room_id) {
+            return;
+        }
+
+        let event = JoinRoom {
+            username: username.to_string(),
+            room_id: room_id.to_string(),
+        };
+        // O próprio usuário também recebe o evento, como confirmação
+        self.send_to_room(room_id, None, event.clone());
+        self.publish_to_room(ctx, room_id, RedisMessageType::JoinRoom(event));
+
+        self.save_user_resume_state(username, ctx);
+
+        println!("Relay {}: Usuário {} entrou na sala {}", self.relay_id, username, room_id);
+    }
+
+    fn leave_room(&mut self, username: &str, room_id: &str, ctx: &mut Context<Self>) {
+        let is_member = self.rooms.get(room_id).is_some_and(|members| members.contains(username));
+        if !is_member {
+            return;
+        }
+
+        let event = LeaveRoom {
+            username: username.to_string(),
+            room_id: room_id.to_string(),
+        };
+        self.send_to_room(room_id, None, event.clone());
+        self.publish_to_room(ctx, room_id, RedisMessageType::LeaveRoom(event));
+        self.remove_room_member(username, room_id);
+
+        self.save_user_resume_state(username, ctx);
+
+        println!("Relay {}: Usuário {} saiu da sala {}", self.relay_id, username, room_id);
+    }
+
+    fn dispatch_user_message(&mut self, msg: UserMessage, ctx: &mut Context<Self>) {
+        // Só mensagens aceitas aqui são persistidas; as que chegam via Redis
+        // já foram gravadas pelo pod de origem
+        let origin = self.session_addr(&msg.username, msg.origin_session.as_deref());
+        self.persistence.do_send(PersistMessage {
+            message_id: msg.message_id.clone(),
+            username: msg.username.clone(),
+            content: msg.content.clone(),
+            room_id: msg.room_id.clone(),
+            timestamp: time::OffsetDateTime::now_utc(),
+            ack_to: origin.map(|connection| connection.clone().recipient()),
+        });
+        self.ack(&msg.username, msg.origin_session.as_deref(), &msg.message_id, AckStatus::Accepted);
+
+        // Distribuir localmente, inclusive aos outros dispositivos do remetente
+        self.send_to_room(&msg.room_id, msg.origin_session.as_deref(), msg.clone());
+
+        let room_id = msg.room_id.clone();
+        self.publish_to_room(ctx, &room_id, RedisMessageType::UserMessage(msg));
+    }
+
+    /// Salas do usuário neste relay, como ficam guardadas para a retomada
+    fn resume_state(&self, username: &str) -> ResumeState {
+        let mut rooms: Vec<String> = self.rooms.iter()
+            .filter(|(_, members)| members.contains(username))
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+        rooms.sort();
+
+        ResumeState {
+            username: username.to_string(),
+            rooms,
+        }
+    }
+
+    fn save_resume_states(&self, states: Vec<(String, ResumeState)>, ctx: &mut Context<Self>) {
+        if states.is_empty() {
+            return;
+        }
+
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+        let fut = async move {
+            if let Err(e) = bus.save_resume_states(states).await {
+                eprintln!("Relay {}: Falha ao salvar estado de retomada: {}", relay_id, e);
+            }
+        };
+
+        ctx.spawn(fut.into_actor(self));
+    }
+
+    /// Atualiza o estado de retomada de todas as sessões locais do usuário
+    fn save_user_resume_state(&self, username: &str, ctx: &mut Context<Self>) {
+        let Some(sessions) = self.connections.get(username) else {
+            return;
+        };
+
+        let state = self.resume_state(username);
+        let states = sessions.keys()
+            .map(|session_id| (session_id.clone(), state.clone()))
+            .collect();
+        self.save_resume_states(states, ctx);
+    }
+
+    /// Restaura as salas da sessão anterior e reenvia à nova sessão o que
+    /// ficou no buffer de cada sala depois da `seq` vista nela
+    fn resume_session(&mut self, username: String, resume: ResumeRequest, addr: actix::Addr<WsConn>, ctx: &mut Context<Self>) {
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+
+        let token = resume.token.clone();
+        let fut = async move {
+            bus.take_resume_state(&token).await
+        };
+        let fut = fut.into_actor(self).map(move |result, act, ctx| {
+            let state = match result {
+                Ok(Some(state)) if state.username == username => state,
+                Ok(_) => {
+                    addr.do_send(ResumeOutcome::Expired);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Relay {}: Falha ao buscar estado de retomada de {}: {}", relay_id, username, e);
+                    addr.do_send(ResumeOutcome::Expired);
+                    return;
+                }
+            };
+
+            let rooms: Vec<String> = state.rooms.into_iter()
+                .filter(|room_id| is_valid_room_id(room_id))
+                .collect();
+            for room_id in &rooms {
+                act.join_room(&username, room_id, ctx);
+            }
+
+            let bus = act.bus.clone();
+            let fut = async move {
+                let mut missed = Vec::new();
+                for room_id in &rooms {
+                    missed.extend(bus.read_room_buffer(room_id, resume.last_seq_in(room_id)).await?);
+                }
+                Ok::<_, BusError>(missed)
+            };
+            let fut = fut.into_actor(act).map(move |result, _act, _ctx| {
+                let mut missed = match result {
+                    Ok(missed) => missed,
                     Err(e) => {
-                        eprintln!("Falha ao conectar canal {}: {}", channel, e);
-                        continue;
+                        eprintln!("Relay {}: Falha ao ler buffer das salas para {}: {}", relay_id, username, e);
+                        addr.do_send(ResumeOutcome::Expired);
+                        return;
                     }
+                };
+                // A `seq` só ordena dentro da sala
+                missed.sort_by(|a, b| a.room_id.cmp(&b.room_id).then(a.seq.cmp(&b.seq)));
+
+                let replayed = missed.len();
+                for message in missed {
+                    addr.do_send(message);
                 }
+                addr.do_send(ResumeOutcome::Resumed { replayed });
+
+                println!("Relay {}: Sessão de {} retomada, {} mensagens reenviadas", relay_id, username, replayed);
+            });
+            ctx.spawn(fut);
+        });
+
+        ctx.spawn(fut);
+    }
+
+    /// Entrega às sessões locais do destinatário e publica no inbox de cada
+    /// outro relay onde `user_location` indica sessões dele. Sem nenhuma
+    /// sessão, a mensagem fica guardada para quando ele se conectar.
+    fn route_direct_message(&mut self, msg: DirectMessage, ctx: &mut Context<Self>) {
+        let delivered_locally = self.send_to_user(&msg.to, msg.clone());
+
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+        let sender = msg.from.clone();
+        let origin_session = msg.origin_session.clone();
+        let message_id = msg.message_id.clone();
+
+        let fut = async move {
+            let own_pod = bus.pod_id().to_string();
+            let remote: Vec<(String, u32)> = bus.get_user_locations(&msg.to).await?
+                .into_iter()
+                .filter(|(pod_id, target_relay_id)| *pod_id != own_pod || *target_relay_id != relay_id)
+                .collect();
+
+            if remote.is_empty() && !delivered_locally {
+                bus.push_offline_message(&msg).await?;
+                return Ok(Some(msg));
             }
 
-            Err(redis::RedisError::from((
-                redis::ErrorKind::IoError,
-                "Falha ao conectar a qualquer canal Redis"
-            )))
+            for (pod_id, target_relay_id) in remote {
+                let channel = Channel::relay_inbox(&pod_id, target_relay_id).name();
+                bus.publish(&channel, relay_id, RedisMessageType::DirectMessage(msg.clone())).await?;
+            }
+            Ok(None)
         };
 
-        let fut = fut.into_actor(self).map(|result, act, ctx| {
+        let fut = fut.into_actor(self).map(move |result: Result<Option<DirectMessage>, BusError>, act, _ctx| {
             match result {
-                Ok(receiver) => {
-                    act.redis_receiver = Some(receiver);
-                    act.poll_redis_messages(ctx);
-                    println!("Relay {}: Conectado ao Redis Cluster", act.relay_id);
+                Ok(Some(queued)) => {
+                    if let Some(connection) = act.session_addr(&sender, origin_session.as_deref()) {
+                        connection.do_send(DirectMessageQueued { message_id: queued.message_id, to: queued.to });
+                    }
+                }
+                Ok(None) => {
+                    act.ack(&sender, origin_session.as_deref(), &message_id, AckStatus::Accepted);
                 }
                 Err(e) => {
-                    eprintln!("Relay {}: Erro ao conectar ao Redis Cluster: {}", act.relay_id, e);
-                    // Tentar reconectar após 5 segundos
-                    ctx.run_later(Duration::from_secs(5), |act, ctx| {
-                        act.start_redis_listener(ctx);
-                    });
+                    eprintln!("Relay {}: Falha ao rotear mensagem direta: {}", relay_id, e);
+                    // Se já chegou a alguma sessão local, foi aceita mesmo sem o Redis
+                    let status = if delivered_locally { AckStatus::Accepted } else { AckStatus::Failed };
+                    act.ack(&sender, origin_session.as_deref(), &message_id, status);
                 }
             }
         });
@@ -122,66 +590,107 @@ impl RelayActor {
         ctx.spawn(fut);
     }
 
-    fn poll_redis_messages(&mut self, ctx: &mut Context<Self>) {
-        if let Some(ref mut receiver) = self.redis_receiver {
-            ctx.run_interval(Duration::from_millis(5), |act, _ctx| {
-                let mut messages = Vec::new();
-                let start_time = Instant::now();
-
-                if let Some(ref mut receiver) = act.redis_receiver {
-                    // Processar até 10 mensagens por vez para evitar bloqueio
-                    for _ in 0..10 {
-                        match receiver.try_recv() {
-                            Ok(message) => messages.push(message),
-                            Err(_) => break,
-                        }
-                    }
-                }
+    /// Leva a confirmação de entrega às sessões do remetente, locais ou em
+    /// outros relays. Sem sessões vivas, a confirmação é descartada.
+    fn route_delivery_receipt(&mut self, receipt: DeliveryReceipt, ctx: &mut Context<Self>) {
+        self.send_to_user(&receipt.from, receipt.clone());
 
-                for message in messages.clone() {
-                    act.handle_redis_message(message);
-                }
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
 
-                // Atualizar métricas de resposta
-                if !messages.is_empty() {
-                    let processing_time = start_time.elapsed().as_millis() as f64;
-                    act.update_response_time(processing_time);
+        let fut = async move {
+            let own_pod = bus.pod_id().to_string();
+            for (pod_id, target_relay_id) in bus.get_user_locations(&receipt.from).await? {
+                if pod_id == own_pod && target_relay_id == relay_id {
+                    continue;
                 }
-            });
-        }
+                let channel = Channel::relay_inbox(&pod_id, target_relay_id).name();
+                bus.publish(&channel, relay_id, RedisMessageType::DeliveryReceipt(receipt.clone())).await?;
+            }
+            Ok::<_, BusError>(())
+        };
+        let fut = fut.into_actor(self).map(move |result, _act, _ctx| {
+            if let Err(e) = result {
+                eprintln!("Relay {}: Falha ao rotear confirmação de entrega: {}", relay_id, e);
+            }
+        });
+
+        ctx.spawn(fut);
     }
 
-    fn handle_redis_message(&mut self, message: RedisMessage) {
+    /// Mensagem direta que chegou pelo inbox mas cujo destinatário já saiu
+    /// deste relay (localização desatualizada): guarda como offline.
+    fn store_undeliverable(&self, msg: DirectMessage, ctx: &mut Context<Self>) {
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+
+        let fut = async move {
+            if let Err(e) = bus.push_offline_message(&msg).await {
+                eprintln!("Relay {}: Falha ao guardar mensagem offline para {}: {}", relay_id, msg.to, e);
+            }
+        };
+
+        ctx.spawn(fut.into_actor(self));
+    }
+
+    fn handle_redis_message(&mut self, message: RedisMessage, ctx: &mut Context<Self>) {
         self.metrics.message_count += 1;
         self.metrics.last_message_time = Instant::now();
 
         match message.message_type {
             RedisMessageType::UserMessage(user_msg) => {
-                println!("Relay {}: Mensagem Redis de {}: {}",
-                         self.relay_id, user_msg.username, user_msg.content);
+                println!("Relay {}: Mensagem Redis de {} na sala {}: {}",
+                         self.relay_id, user_msg.username, user_msg.room_id, user_msg.content);
 
-                // Distribuir para conexões locais exceto o remetente
-                for (username, connection) in self.connections.iter() {
-                    if username != &user_msg.username {
-                        connection.do_send(user_msg.clone());
-                    }
+                // Veio de outro relay: todas as sessões locais da sala recebem,
+                // inclusive outros dispositivos do remetente
+                let room_id = user_msg.room_id.clone();
+                self.send_to_room(&room_id, None, user_msg);
+            }
+            RedisMessageType::JoinRoom(event) => {
+                let room_id = event.room_id.clone();
+                self.send_to_room(&room_id, None, event);
+            }
+            RedisMessageType::LeaveRoom(event) => {
+                let room_id = event.room_id.clone();
+                self.send_to_room(&room_id, None, event);
+            }
+            RedisMessageType::DirectMessage(direct) => {
+                if self.send_to_user(&direct.to, direct.clone()) {
+                    return;
+                }
+                // A localização ainda apontava para cá quando a mensagem foi publicada
+                if let Some(relay) = self.migrated_to(&direct.to) {
+                    relay.do_send(ForwardRedisMessage(RedisMessage {
+                        message_type: RedisMessageType::DirectMessage(direct),
+                        ..message
+                    }));
+                } else {
+                    self.store_undeliverable(direct, ctx);
+                }
+            }
+            RedisMessageType::DeliveryReceipt(receipt) => {
+                if self.send_to_user(&receipt.from, receipt.clone()) {
+                    return;
+                }
+                if let Some(relay) = self.migrated_to(&receipt.from) {
+                    relay.do_send(ForwardRedisMessage(RedisMessage {
+                        message_type: RedisMessageType::DeliveryReceipt(receipt),
+                        ..message
+                    }));
                 }
             }
             RedisMessageType::JoinEvent(join_event) => {
                 println!("Relay {}: Usuário {} entrou (via Redis)",
                          self.relay_id, join_event.username);
 
-                for (_, connection) in self.connections.iter() {
-                    connection.do_send(join_event.clone());
-                }
+                self.send_to_all(None, join_event);
             }
             RedisMessageType::UnRegisterConnection(unreg_msg) => {
                 println!("Relay {}: Usuário {} saiu (via Redis)",
                          self.relay_id, unreg_msg.username);
 
-                for (_, connection) in self.connections.iter() {
-                    connection.do_send(unreg_msg.clone());
-                }
+                self.send_to_all(None, unreg_msg);
             }
             RedisMessageType::RelayHeartbeat { relay_id, active_connections } => {
                 if relay_id != self.relay_id {
@@ -189,6 +698,8 @@ impl RelayActor {
                              self.relay_id, relay_id, message.from_pod_id, active_connections);
                 }
             }
+            // Só o `LoadBalancer` assina `pod_metrics`
+            RedisMessageType::PodMetrics(_) => {}
         }
     }
 
@@ -199,7 +710,7 @@ impl RelayActor {
 
     pub fn get_metrics(&self) -> RelayMetrics {
         RelayMetrics {
-            active_connections: self.connections.len(),
+            active_connections: self.session_count(),
             message_count: self.metrics.message_count,
             last_message_time: self.metrics.last_message_time,
             avg_response_time: self.metrics.avg_response_time,
@@ -209,17 +720,17 @@ impl RelayActor {
     // Health check periódico do Redis
     fn start_health_check(&self, ctx: &mut Context<Self>) {
         ctx.run_interval(Duration::from_secs(30), |act, ctx| {
-            let redis_manager = act.redis_manager.clone();
+            let bus = act.bus.clone();
             let relay_id = act.relay_id;
 
             let fut = async move {
-                redis_manager.health_check().await
+                bus.health_check().await
             };
 
-            let fut = fut.into_actor(act).map(move |is_healthy, act, ctx| {
+            let fut = fut.into_actor(act).map(move |is_healthy, act, _ctx| {
                 if !is_healthy {
                     eprintln!("Relay {}: Redis Cluster não responsivo, tentando reconectar...", relay_id);
-                    act.start_redis_listener(ctx);
+                    act.start_redis_listener();
                 }
             });
 
@@ -232,9 +743,17 @@ impl Actor for RelayActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        if self.retired {
+            return;
+        }
         println!("RelayActor {} iniciado com Redis Cluster", self.relay_id);
 
-        self.start_redis_listener(ctx);
+        self.start_redis_listener();
+        if self.connections.is_empty() {
+            self.recover_sessions(ctx);
+        }
+        self.poll_redis_messages(ctx);
+        self.watch_ring_changes(ctx);
         self.start_heartbeat(ctx);
         self.start_health_check(ctx);
     }
@@ -244,50 +763,132 @@ impl Actor for RelayActor {
     }
 }
 
+impl actix::Supervised for RelayActor {
+    fn restarting(&mut self, _ctx: &mut Self::Context) {
+        // Conexões e salas continuam no próprio ator; `started` refaz as
+        // assinaturas e os timers
+        if !self.retired {
+            println!("RelayActor {} reiniciado pelo supervisor", self.relay_id);
+        }
+    }
+}
+
 impl Handler<RegisterConnection> for RelayActor {
     type Result = ();
 
     fn handle(&mut self, msg: RegisterConnection, ctx: &mut Self::Context) -> Self::Result {
-        let is_new_user = !self.connections.contains_key(&msg.username);
-
-        if !is_new_user {
-            println!("Relay {}: Usuário {} já conectado", self.relay_id, msg.username);
-            return;
+        let RegisterConnection { username, session_id, addr, resume } = msg;
+
+        let sessions = self.connections.entry(username.clone()).or_default();
+        let first_local_session = sessions.is_empty();
+        sessions.insert(session_id.clone(), addr.clone());
+        self.metrics.active_connections = self.session_count();
+        self.sync_registry(&username);
+
+        if first_local_session {
+            self.join_room(&username, DEFAULT_ROOM, ctx);
+        } else {
+            self.save_user_resume_state(&username, ctx);
+            // Usuário já estava neste relay: a nova sessão herda as salas dele
+            for (room_id, members) in self.rooms.iter() {
+                if members.contains(&username) {
+                    addr.do_send(JoinRoom {
+                        username: username.clone(),
+                        room_id: room_id.clone(),
+                    });
+                }
+            }
         }
 
-        // Notificar conexões locais
-        for (_, connection) in self.connections.iter() {
-            connection.do_send(JoinEvent {
-                username: msg.username.clone(),
-            })
-        }
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+        let session_username = username.clone();
+        let event_username = username.clone();
+
+        let fut = async move {
+            bus.add_user_session(&session_username, &session_id, relay_id).await
+        };
+        let fut = fut.into_actor(self).map(move |result, act, ctx| {
+            let username = event_username;
+            // Só a primeira sessão do usuário no cluster gera o evento de entrada;
+            // sem Redis, vale a primeira sessão local
+            let first_session = match result {
+                Ok(count) => count == 1,
+                Err(e) => {
+                    eprintln!("Relay {}: Falha ao registrar sessão de {}: {}", relay_id, username, e);
+                    first_local_session
+                }
+            };
+            if !first_session {
+                return;
+            }
 
-        self.connections.insert(msg.username.clone(), msg.addr);
-        self.metrics.active_connections = self.connections.len();
+            act.send_to_all(Some(&username), JoinEvent { username: username.clone() });
 
-        let redis_manager = self.redis_manager.clone();
-        let relay_id = self.relay_id;
-        let username = msg.username.clone();
+            let bus = act.bus.clone();
+            let fut = async move {
+                if let Err(e) = bus.publish(
+                    &Channel::Control.name(),
+                    relay_id,
+                    RedisMessageType::JoinEvent(JoinEvent { username }),
+                ).await {
+                    eprintln!("Relay {}: Falha ao publicar entrada de usuário: {}", relay_id, e);
+                }
+            };
+            ctx.spawn(fut.into_actor(act));
+        });
+        ctx.spawn(fut);
+
+        if let Some(resume) = resume {
+            self.resume_session(username.clone(), resume, addr.clone(), ctx);
+        }
 
+        // Reenviar à nova sessão o que sessões anteriores não confirmaram
+        let bus = self.bus.clone();
+        let pending_username = username.clone();
         let fut = async move {
-            let _ = redis_manager.set_user_location(&username, relay_id).await;
-
-            // Publicar com fallback
-            let primary_channel = format!("relay_events_{}", relay_id);
-            let fallback_channel = "relay_events_global";
-
-            let _ = redis_manager.publish_with_fallback(
-                &primary_channel,
-                &fallback_channel,
-                relay_id,
-                RedisMessageType::JoinEvent(JoinEvent { username }),
-            ).await;
+            bus.take_pending_deliveries(&pending_username).await
         };
+        let fut = fut.into_actor(self).map(move |result, _act, _ctx| {
+            match result {
+                Ok(deliveries) => {
+                    for delivery in deliveries {
+                        match delivery {
+                            PendingDelivery::Room(message) => addr.do_send(message),
+                            PendingDelivery::Direct(message) => addr.do_send(message),
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Relay {}: Falha ao buscar entregas pendentes: {}", relay_id, e);
+                }
+            }
+        });
+        ctx.spawn(fut);
 
-        ctx.spawn(fut.into_actor(self));
+        // Entregar mensagens diretas recebidas enquanto o usuário estava offline
+        let bus = self.bus.clone();
+        let offline_username = username.clone();
+        let fut = async move {
+            bus.take_offline_messages(&offline_username).await
+                .map(|messages| (offline_username, messages))
+        };
+        let fut = fut.into_actor(self).map(move |result, act, _ctx| {
+            match result {
+                Ok((username, messages)) => {
+                    for message in messages {
+                        act.send_to_user(&username, message);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Relay {}: Falha ao buscar mensagens offline: {}", relay_id, e);
+                }
+            }
+        });
+        ctx.spawn(fut);
 
-        println!("Relay {}: Usuário {} conectado, total: {}",
-                 self.relay_id, msg.username, self.connections.len());
+        println!("Relay {}: Usuário {} conectado ({} sessões locais), total: {}",
+                 self.relay_id, username, self.connections[&username].len(), self.session_count());
     }
 }
 
@@ -295,36 +896,92 @@ impl Handler<UnRegisterConnection> for RelayActor {
     type Result = ();
 
     fn handle(&mut self, msg: UnRegisterConnection, ctx: &mut Self::Context) -> Self::Result {
-        if self.connections.remove(&msg.username).is_some() {
-            self.metrics.active_connections = self.connections.len();
+        let UnRegisterConnection { username, session_id } = msg;
+
+        // A conexão caiu antes de saber que tinha sido migrada
+        if let Some(relay) = self.migrated_to(&username) {
+            relay.do_send(UnRegisterConnection { username, session_id });
+            return;
+        }
+
+        let Some(sessions) = self.connections.get_mut(&username) else {
+            return;
+        };
+        if sessions.remove(&session_id).is_none() {
+            return;
+        }
+
+        let last_local_session = sessions.is_empty();
+
+        // O token da sessão encerrada guarda as salas em que ela estava
+        let state = self.resume_state(&username);
+        self.save_resume_states(vec![(session_id.clone(), state)], ctx);
 
-            for (_, connection) in self.connections.iter() {
-                connection.do_send(msg.clone())
+        if last_local_session {
+            let joined_rooms: Vec<String> = self.rooms.iter()
+                .filter(|(_, members)| members.contains(&username))
+                .map(|(room_id, _)| room_id.clone())
+                .collect();
+            for room_id in joined_rooms {
+                self.leave_room(&username, &room_id, ctx);
             }
+            self.connections.remove(&username);
+            // Sem receptor o pod está encerrando; não há o que limpar
+            let _ = self.departures.send(UserDeparture {
+                username: username.clone(),
+                relay_id: self.relay_id,
+                at: Instant::now(),
+            });
+        }
+        self.metrics.active_connections = self.session_count();
+        self.sync_registry(&username);
 
-            let redis_manager = self.redis_manager.clone();
-            let relay_id = self.relay_id;
-            let username = msg.username.clone();
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+        let session_username = username.clone();
+        let removed_session = session_id.clone();
 
-            let fut = async move {
-                let _ = redis_manager.remove_user_location(&username).await;
+        let fut = async move {
+            bus.remove_user_session(&session_username, &removed_session).await
+        };
+        let event_username = username.clone();
+        let fut = fut.into_actor(self).map(move |result, act, ctx| {
+            let username = event_username;
+            // Só a última sessão do usuário no cluster gera o evento de saída
+            let last_session = match result {
+                Ok(remaining) => remaining == 0,
+                Err(e) => {
+                    eprintln!("Relay {}: Falha ao remover sessão de {}: {}", relay_id, username, e);
+                    last_local_session
+                }
+            };
+            // Pode ter reconectado neste relay enquanto o Redis respondia
+            if !last_session || act.connections.contains_key(&username) {
+                return;
+            }
 
-                let primary_channel = format!("relay_events_{}", relay_id);
-                let fallback_channel = "relay_events_global";
+            let event = UnRegisterConnection {
+                username,
+                session_id: String::new(),
+            };
+            act.send_to_all(None, event.clone());
 
-                let _ = redis_manager.publish_with_fallback(
-                    &primary_channel,
-                    &fallback_channel,
+            let bus = act.bus.clone();
+            let fut = async move {
+                if let Err(e) = bus.publish(
+                    &Channel::Control.name(),
                     relay_id,
-                    RedisMessageType::UnRegisterConnection(UnRegisterConnection { username }),
-                ).await;
+                    RedisMessageType::UnRegisterConnection(event),
+                ).await {
+                    eprintln!("Relay {}: Falha ao publicar saída de usuário: {}", relay_id, e);
+                }
             };
+            ctx.spawn(fut.into_actor(act));
+        });
+        ctx.spawn(fut);
 
-            ctx.spawn(fut.into_actor(self));
-
-            println!("Relay {}: Usuário {} desconectado, total: {}",
-                     self.relay_id, msg.username, self.connections.len());
-        }
+        println!("Relay {}: Sessão {} de {} desconectada, total: {}",
+                 self.relay_id, session_id, username, self.session_count());
     }
 }
 
@@ -334,32 +991,40 @@ impl Handler<UserMessage> for RelayActor {
     fn handle(&mut self, msg: UserMessage, ctx: &mut Self::Context) -> Self::Result {
         let start_time = Instant::now();
 
-        println!("Relay {}: Mensagem de {}: {}", self.relay_id, msg.username, msg.content);
+        if let Some(relay) = self.migrated_to(&msg.username) {
+            relay.do_send(msg);
+            return;
+        }
 
-        // Distribuir localmente
-        for (username, connection) in self.connections.iter() {
-            if username != &msg.username {
-                connection.do_send(msg.clone())
-            }
+        let is_member = self.rooms.get(&msg.room_id)
+            .is_some_and(|members| members.contains(&msg.username));
+        if !is_member {
+            println!("Relay {}: {} tentou enviar para a sala {} sem ser membro",
+                     self.relay_id, msg.username, msg.room_id);
+            self.ack(&msg.username, msg.origin_session.as_deref(), &msg.message_id, AckStatus::Failed);
+            return;
         }
 
-        let redis_manager = self.redis_manager.clone();
-        let relay_id = self.relay_id;
-        let user_msg = msg.clone();
+        println!("Relay {}: Mensagem de {} na sala {}: {}",
+                 self.relay_id, msg.username, msg.room_id, msg.content);
 
+        // A mensagem recebe sua `seq` e entra no buffer da sala antes de ser
+        // distribuída; sem o bus ela segue sem `seq` e não poderá ser reenviada
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+        let unsequenced = msg.clone();
         let fut = async move {
-            let primary_channel = format!("relay_messages_{}", relay_id);
-            let fallback_channel = "relay_messages_global";
-
-            let _ = redis_manager.publish_with_fallback(
-                &primary_channel,
-                &fallback_channel,
-                relay_id,
-                RedisMessageType::UserMessage(user_msg),
-            ).await;
+            bus.buffer_room_message(msg).await
         };
-
-        ctx.spawn(fut.into_actor(self));
+        let fut = fut.into_actor(self).map(move |result, act, ctx| {
+            let msg = result.unwrap_or_else(|e| {
+                eprintln!("Relay {}: Falha ao guardar mensagem no buffer da sala {}: {}",
+                          relay_id, unsequenced.room_id, e);
+                unsequenced
+            });
+            act.dispatch_user_message(msg, ctx);
+        });
+        ctx.spawn(fut);
 
         // Atualizar métricas de performance
         let processing_time = start_time.elapsed().as_millis() as f64;
@@ -368,10 +1033,361 @@ impl Handler<UserMessage> for RelayActor {
     }
 }
 
+impl Handler<DirectMessage> for RelayActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: DirectMessage, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(relay) = self.migrated_to(&msg.from) {
+            relay.do_send(msg);
+            return;
+        }
+        if !self.connections.contains_key(&msg.from) {
+            return;
+        }
+        self.metrics.message_count += 1;
+        self.route_direct_message(msg, ctx);
+    }
+}
+
+impl Handler<DeliveryReceipt> for RelayActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeliveryReceipt, ctx: &mut Self::Context) -> Self::Result {
+        self.route_delivery_receipt(msg, ctx);
+    }
+}
+
+impl Handler<StoreUnacked> for RelayActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: StoreUnacked, ctx: &mut Self::Context) -> Self::Result {
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+
+        let fut = async move {
+            if let Err(e) = bus.push_pending_deliveries(&msg.username, &msg.deliveries).await {
+                eprintln!("Relay {}: Falha ao guardar {} entregas sem ack de {}: {}",
+                          relay_id, msg.deliveries.len(), msg.username, e);
+            }
+        };
+
+        ctx.spawn(fut.into_actor(self));
+    }
+}
+
+impl Handler<JoinRoom> for RelayActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: JoinRoom, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(relay) = self.migrated_to(&msg.username) {
+            relay.do_send(msg);
+            return;
+        }
+        if !self.connections.contains_key(&msg.username) {
+            return;
+        }
+        self.join_room(&msg.username, &msg.room_id, ctx);
+    }
+}
+
+impl Handler<LeaveRoom> for RelayActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaveRoom, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(relay) = self.migrated_to(&msg.username) {
+            relay.do_send(msg);
+            return;
+        }
+        self.leave_room(&msg.username, &msg.room_id, ctx);
+    }
+}
+
+impl Handler<MigrateUser> for RelayActor {
+    type Result = ResponseActFuture<Self, bool>;
+
+    fn handle(&mut self, msg: MigrateUser, _ctx: &mut Self::Context) -> Self::Result {
+        let MigrateUser { username, to_relay_id, to } = msg;
+
+        let Some(sessions) = self.connections.remove(&username) else {
+            return Box::pin(actix::fut::ready(false));
+        };
+
+        // Sai das salas sem eventos de saída: para os outros membros nada mudou
+        let rooms: Vec<String> = self.rooms.iter()
+            .filter(|(_, members)| members.contains(&username))
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+        for room_id in &rooms {
+            self.remove_room_member(&username, room_id);
+        }
+        self.sync_registry(&username);
+        self.metrics.active_connections = self.session_count();
+        self.migrated.insert(username.clone(), (to.clone(), Instant::now()));
+
+        // O destino recebe as sessões antes de qualquer mensagem que as
+        // conexões enviem depois de trocar de relay
+        let session_ids: Vec<String> = sessions.keys().cloned().collect();
+        to.do_send(AttachUser {
+            username: username.clone(),
+            sessions: sessions.clone(),
+            rooms,
+        });
+        for connection in sessions.values() {
+            connection.do_send(SwitchRelay { relay_actor: to.clone() });
+        }
+
+        let bus = self.bus.clone();
+        let relay_id = self.relay_id;
+        let fut = async move {
+            if let Err(e) = bus.move_user_sessions(&username, session_ids, to_relay_id).await {
+                // O heartbeat do novo relay corrige a localização em seguida
+                eprintln!("Relay {}: Falha ao atualizar localização de {} migrado para o relay {}: {}",
+                          relay_id, username, to_relay_id, e);
+            }
+            println!("Relay {}: Usuário {} migrado para o relay {}", relay_id, username, to_relay_id);
+            true
+        };
+
+        Box::pin(fut.into_actor(self))
+    }
+}
+
+impl Handler<AttachUser> for RelayActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: AttachUser, ctx: &mut Self::Context) -> Self::Result {
+        let AttachUser { username, sessions, rooms } = msg;
+
+        self.migrated.remove(&username);
+        self.connections.entry(username.clone()).or_default().extend(sessions);
+        self.sync_registry(&username);
+        // Entra nas salas sem eventos de entrada, como se sempre estivesse aqui
+        for room_id in &rooms {
+            self.add_room_member(&username, room_id);
+        }
+        self.metrics.active_connections = self.session_count();
+        self.save_user_resume_state(&username, ctx);
+
+        println!("Relay {}: Usuário {} recebido por migração ({} sessões locais), total: {}",
+                 self.relay_id, username, self.connections[&username].len(), self.session_count());
+    }
+}
+
+impl Handler<ForwardRedisMessage> for RelayActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForwardRedisMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.handle_redis_message(msg.0, ctx);
+    }
+}
+
 impl Handler<GetMetrics> for RelayActor {
     type Result = actix::MessageResult<GetMetrics>;
 
     fn handle(&mut self, _msg: GetMetrics, _ctx: &mut Self::Context) -> Self::Result {
         actix::MessageResult(self.metrics.clone())
     }
-}
\ No newline at end of file
+}
+
+impl Handler<GetLocalUsers> for RelayActor {
+    type Result = actix::MessageResult<GetLocalUsers>;
+
+    fn handle(&mut self, _msg: GetLocalUsers, _ctx: &mut Self::Context) -> Self::Result {
+        actix::MessageResult(self.connections.keys().cloned().collect())
+    }
+}
+
+impl Handler<DrainConnections> for RelayActor {
+    type Result = usize;
+
+    fn handle(&mut self, msg: DrainConnections, _ctx: &mut Self::Context) -> Self::Result {
+        let mut rng = rand::thread_rng();
+        let mut notified = 0;
+        for sessions in self.connections.values() {
+            for conn in sessions.values() {
+                conn.do_send(Reconnect { retry_after: rng.gen_range(Duration::ZERO..=msg.max_jitter) });
+                notified += 1;
+            }
+        }
+        notified
+    }
+}
+
+impl Handler<ReleaseSessions> for RelayActor {
+    type Result = ResponseActFuture<Self, usize>;
+
+    fn handle(&mut self, _msg: ReleaseSessions, _ctx: &mut Self::Context) -> Self::Result {
+        let relay_id = self.relay_id;
+        let bus = self.bus.clone();
+        let sessions: Vec<(String, String)> = self.connections.iter()
+            .flat_map(|(username, sessions)| {
+                sessions.keys().map(move |session_id| (username.clone(), session_id.clone()))
+            })
+            .collect();
+
+        Box::pin(async move {
+            let mut released = 0;
+            for (username, session_id) in sessions {
+                match bus.remove_user_session(&username, &session_id).await {
+                    Ok(_) => released += 1,
+                    Err(e) => eprintln!("Relay {}: Falha ao remover sessão de {} no encerramento: {}", relay_id, username, e),
+                }
+            }
+            released
+        }.into_actor(self))
+    }
+}
+
+impl Handler<RetireRelay> for RelayActor {
+    type Result = bool;
+
+    fn handle(&mut self, _msg: RetireRelay, ctx: &mut Self::Context) -> Self::Result {
+        if !self.connections.is_empty() {
+            return false;
+        }
+
+        self.retired = true;
+        self.channels.close_all();
+        self.registry.users().clear();
+        ctx.stop();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use crate::actors::testing::{start_relay, TestClient};
+    use crate::bus::memory::InMemoryBus;
+
+    fn memory_bus() -> Arc<dyn MessageBus> {
+        Arc::new(InMemoryBus::new("pod-a".to_string()))
+    }
+
+    fn in_room(room_id: &'static str) -> impl Fn(&Value) -> bool {
+        move |frame| frame["payload"]["room_id"] == room_id
+    }
+
+    #[actix::test]
+    async fn room_messages_reach_only_members_of_the_room() {
+        let relay = start_relay(memory_bus(), 1);
+        let mut alice = TestClient::connect("alice", relay.clone()).await;
+        let mut bob = TestClient::connect("bob", relay.clone()).await;
+        alice.expect_where("room_joined", in_room(DEFAULT_ROOM)).await;
+        bob.expect_where("room_joined", in_room(DEFAULT_ROOM)).await;
+
+        alice.send("join_room", None, json!({ "room_id": "dev" }));
+        alice.expect_where("room_joined", in_room("dev")).await;
+        alice.send("message", None, json!({ "room_id": "dev", "content": "só para dev" }));
+        alice.send("message", None, json!({ "room_id": DEFAULT_ROOM, "content": "para todos" }));
+
+        // A mensagem de dev foi enviada antes e não chegou a bob
+        let received = bob.expect("message").await;
+        assert_eq!(received["payload"]["room_id"], DEFAULT_ROOM);
+        assert_eq!(received["payload"]["content"], "para todos");
+    }
+
+    #[actix::test]
+    async fn messages_to_a_room_the_sender_left_are_rejected() {
+        let relay = start_relay(memory_bus(), 1);
+        let mut alice = TestClient::connect("alice", relay.clone()).await;
+        alice.send("join_room", None, json!({ "room_id": "dev" }));
+        alice.expect_where("room_joined", in_room("dev")).await;
+        alice.send("leave_room", None, json!({ "room_id": "dev" }));
+        alice.expect_where("room_left", in_room("dev")).await;
+
+        alice.send("message", Some("m1"), json!({ "room_id": "dev", "content": "oi" }));
+        let error = alice.expect("error").await;
+        assert_eq!(error["id"], "m1");
+        assert_eq!(error["payload"]["code"], "not_room_member");
+    }
+
+    #[actix::test]
+    async fn direct_message_to_offline_user_is_queued_until_they_connect() {
+        let relay = start_relay(memory_bus(), 1);
+        let mut alice = TestClient::connect("alice", relay.clone()).await;
+
+        alice.send("direct_message", Some("d1"), json!({ "to": "bob", "content": "quando voltar" }));
+        let queued = alice.expect("direct_queued").await;
+        assert_eq!(queued["id"], "d1");
+        assert_eq!(queued["payload"]["to"], "bob");
+
+        let mut bob = TestClient::connect("bob", relay.clone()).await;
+        let received = bob.expect("direct_message").await;
+        assert_eq!(received["payload"]["from"], "alice");
+        assert_eq!(received["payload"]["content"], "quando voltar");
+    }
+
+    #[actix::test]
+    async fn direct_message_reaches_recipient_on_another_relay() {
+        let bus = memory_bus();
+        let mut bob = TestClient::connect("bob", start_relay(bus.clone(), 2)).await;
+        bob.expect_where("room_joined", in_room(DEFAULT_ROOM)).await;
+        let mut alice = TestClient::connect("alice", start_relay(bus.clone(), 1)).await;
+
+        alice.send("direct_message", Some("d1"), json!({ "to": "bob", "content": "oi" }));
+        let received = bob.expect("direct_message").await;
+        assert_eq!(received["payload"]["content"], "oi");
+
+        let ack = alice.expect("ack").await;
+        assert_eq!(ack["id"], "d1");
+        assert_eq!(ack["payload"]["status"], "accepted");
+    }
+
+    fn about(username: &'static str) -> impl Fn(&Value) -> bool {
+        move |frame| frame["payload"]["username"] == username
+    }
+
+    /// Tipos dos frames sobre `username` que chegam em pouco tempo
+    async fn events_about(client: &mut TestClient, username: &str) -> Vec<String> {
+        client.frames_within(Duration::from_millis(200)).await.into_iter()
+            .filter(|frame| frame["payload"]["username"] == username)
+            .map(|frame| frame["type"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[actix::test]
+    async fn only_first_and_last_session_announce_the_user() {
+        let relay = start_relay(memory_bus(), 1);
+        let mut bob = TestClient::connect("bob", relay.clone()).await;
+
+        let mut laptop = TestClient::connect("alice", relay.clone()).await;
+        bob.expect_where("user_joined", about("alice")).await;
+        let mut phone = TestClient::connect("alice", relay.clone()).await;
+        // A segunda sessão herda as salas sem anunciar nada aos outros
+        phone.expect_where("room_joined", in_room(DEFAULT_ROOM)).await;
+        assert!(events_about(&mut bob, "alice").await.is_empty());
+
+        // Os outros dispositivos do remetente também recebem a mensagem
+        laptop.send("message", None, json!({ "room_id": DEFAULT_ROOM, "content": "do laptop" }));
+        assert_eq!(phone.expect("message").await["payload"]["content"], "do laptop");
+        bob.expect("message").await;
+
+        laptop.close();
+        assert!(events_about(&mut bob, "alice").await.is_empty());
+
+        phone.close();
+        bob.expect_where("room_left", about("alice")).await;
+        bob.expect_where("user_left", about("alice")).await;
+    }
+
+    #[actix::test]
+    async fn room_messages_cross_relays_once() {
+        let bus = memory_bus();
+        let mut alice = TestClient::connect("alice", start_relay(bus.clone(), 1)).await;
+        let mut bob = TestClient::connect("bob", start_relay(bus.clone(), 2)).await;
+        alice.expect_where("room_joined", in_room(DEFAULT_ROOM)).await;
+        bob.expect_where("room_joined", in_room(DEFAULT_ROOM)).await;
+
+        alice.send("message", None, json!({ "room_id": DEFAULT_ROOM, "content": "entre relays" }));
+        let frames = bob.frames_within(Duration::from_millis(300)).await;
+        let messages: Vec<&Value> = frames.iter().filter(|frame| frame["type"] == "message").collect();
+        assert_eq!(messages.len(), 1, "frames: {:?}", frames);
+        assert_eq!(messages[0]["payload"]["content"], "entre relays");
+
+        // O relay de origem não recebe de volta a própria publicação
+        let echoed = alice.frames_within(Duration::from_millis(200)).await;
+        assert!(echoed.iter().all(|frame| frame["type"] != "message"), "frames: {:?}", echoed);
+    }
+}
@@ -0,0 +1,171 @@
+// src/actors/testing.rs
+//! Apoio aos testes dos atores: um relay sobre o bus em memória e um cliente
+//! WebSocket que fala com um `WsConn` de verdade, sem servidor HTTP.
+
+use std::sync::Arc;
+use std::time::Duration;
+use actix::{Actor, AsyncContext, Context};
+use actix_web::error::PayloadError;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web_actors::ws::WebsocketContext;
+use futures_util::{stream, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use crate::actors::persistence::PersistenceActor;
+use crate::actors::relay::{RelayActor, RelayRegistry};
+use crate::actors::ws::WsConn;
+use crate::bus::MessageBus;
+use crate::dynamic_relay_balancer::UserDeparture;
+use crate::protocol::PROTOCOL_VERSION;
+
+/// Quanto um teste espera por um frame antes de desistir
+const FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Relay iniciado sobre `bus`; a persistência nunca roda e só acumula mensagens
+pub fn start_relay(bus: Arc<dyn MessageBus>, relay_id: u32) -> actix::Addr<RelayActor> {
+    let persistence = Context::<PersistenceActor>::new().address();
+    // As saídas de usuários não interessam a estes testes
+    let (departures, _) = mpsc::unbounded_channel::<UserDeparture>();
+    RelayActor::new(relay_id, persistence, bus, RelayRegistry::default(), departures).start()
+}
+
+/// Cliente de teste: escreve frames mascarados como um navegador e lê os
+/// frames de texto que o `WsConn` escreve, já como JSON
+pub struct TestClient {
+    input: Option<mpsc::UnboundedSender<Result<Bytes, PayloadError>>>,
+    frames: mpsc::UnboundedReceiver<Value>,
+    pub session_id: String,
+}
+
+impl TestClient {
+    /// Abre a conexão e consome o `welcome`
+    pub async fn connect(username: &str, relay: actix::Addr<RelayActor>) -> Self {
+        let conn = WsConn::new(username.to_string(), relay, PROTOCOL_VERSION, None);
+        Self::connect_with(conn).await
+    }
+
+    pub async fn connect_with(conn: WsConn) -> Self {
+        let (input, mut input_rx) = mpsc::unbounded_channel();
+        let input_stream = stream::poll_fn(move |cx| input_rx.poll_recv(cx));
+        let mut output = Box::pin(WebsocketContext::create(conn, input_stream));
+
+        // O ator só roda enquanto a saída do contexto é consumida
+        let (frames_tx, frames) = mpsc::unbounded_channel();
+        actix::spawn(async move {
+            let mut buffer = BytesMut::new();
+            while let Some(Ok(chunk)) = output.next().await {
+                buffer.extend_from_slice(&chunk);
+                while let Some(frame) = decode_text_frame(&mut buffer) {
+                    if frames_tx.send(frame).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        let mut client = Self { input: Some(input), frames, session_id: String::new() };
+        let welcome = client.expect("welcome").await;
+        client.session_id = welcome["payload"]["session_id"].as_str().unwrap_or_default().to_string();
+        client
+    }
+
+    /// Envia um frame do protocolo com `payload`
+    pub fn send(&self, frame_type: &str, id: Option<&str>, payload: Value) {
+        let mut frame = json!({ "type": frame_type, "version": PROTOCOL_VERSION, "payload": payload });
+        if let Some(id) = id {
+            frame["id"] = json!(id);
+        }
+        self.send_raw(&frame.to_string());
+    }
+
+    pub fn send_raw(&self, text: &str) {
+        if let Some(input) = &self.input {
+            let _ = input.send(Ok(encode_client_text_frame(text)));
+        }
+    }
+
+    /// Próximo frame, ou `None` se nada chegar dentro do prazo
+    pub async fn next_frame(&mut self) -> Option<Value> {
+        tokio::time::timeout(FRAME_TIMEOUT, self.frames.recv()).await.ok().flatten()
+    }
+
+    /// Descarta frames até chegar um do tipo pedido
+    pub async fn expect(&mut self, frame_type: &str) -> Value {
+        self.expect_where(frame_type, |_| true).await
+    }
+
+    /// Descarta frames até chegar um do tipo pedido que satisfaça `matches`
+    pub async fn expect_where(&mut self, frame_type: &str, matches: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let frame = self.next_frame().await
+                .unwrap_or_else(|| panic!("nenhum frame {} recebido", frame_type));
+            if frame["type"] == frame_type && matches(&frame) {
+                return frame;
+            }
+        }
+    }
+
+    /// Frames que chegam em `wait`, sem esperar mais que isso
+    pub async fn frames_within(&mut self, wait: Duration) -> Vec<Value> {
+        let mut frames = Vec::new();
+        let deadline = tokio::time::Instant::now() + wait;
+        while let Ok(Some(frame)) = tokio::time::timeout_at(deadline, self.frames.recv()).await {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// Encerra a entrada do cliente, como um socket fechado: o `WsConn` para
+    pub fn close(&mut self) {
+        self.input = None;
+    }
+}
+
+/// Frame de texto de cliente: sempre mascarado, aqui com a máscara nula
+fn encode_client_text_frame(text: &str) -> Bytes {
+    let payload = text.as_bytes();
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&[0; 4]);
+    frame.extend_from_slice(payload);
+    Bytes::from(frame)
+}
+
+/// Tira do buffer o próximo frame de texto completo, pulando ping e close;
+/// frames do servidor nunca são mascarados
+fn decode_text_frame(buffer: &mut BytesMut) -> Option<Value> {
+    loop {
+        if buffer.len() < 2 {
+            return None;
+        }
+        let opcode = buffer[0] & 0x0F;
+        let (header_len, payload_len) = match buffer[1] & 0x7F {
+            126 if buffer.len() >= 4 => (4, u16::from_be_bytes([buffer[2], buffer[3]]) as usize),
+            127 if buffer.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&buffer[2..10]);
+                (10, u64::from_be_bytes(len) as usize)
+            }
+            126 | 127 => return None,
+            len => (2, len as usize),
+        };
+        if buffer.len() < header_len + payload_len {
+            return None;
+        }
+
+        let frame = buffer.split_to(header_len + payload_len);
+        if opcode == 0x1 {
+            return serde_json::from_slice(&frame[header_len..]).ok();
+        }
+    }
+}
@@ -1,31 +1,83 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::{Duration, Instant};
 use actix::{Actor, ActorContext, AsyncContext, Handler, StreamHandler};
-use actix_web_actors::ws::{Message, ProtocolError, WebsocketContext};
+use actix_web_actors::ws::{CloseCode, CloseReason, Message, ProtocolError, WebsocketContext};
 use bytestring::ByteString;
-use crate::actors::{JoinEvent, RegisterConnection, UnRegisterConnection, UserMessage};
+use crate::actors::{
+    is_valid_room_id, AckStatus, DeliveryReceipt, DirectMessage, DirectMessageQueued, JoinEvent,
+    JoinRoom, LeaveRoom, MessageAck, PendingDelivery, Reconnect, RegisterConnection, ResumeOutcome,
+    ResumeRequest, StoreUnacked, SwitchRelay, UnRegisterConnection, UserMessage
+};
 use crate::actors::relay::RelayActor;
+use crate::protocol::{ClientFrame, Envelope, ErrorCode, ServerFrame};
+use uuid::Uuid;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(6);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(12);
+/// Máximo de entregas sem ack guardadas por conexão; acima disso as mais antigas são descartadas
+const MAX_UNACKED: usize = 500;
+
+/// Mensagem enviada por este cliente aguardando ack do servidor
+struct SentMessage {
+    /// `id` do envelope do cliente, repetido nos acks
+    client_id: Option<String>,
+    /// Mensagens de sala só terminam ao serem persistidas; diretas, ao serem aceitas
+    awaits_persistence: bool,
+}
 
 pub struct WsConn {
     username: String,
+    /// Identifica este dispositivo/aba entre as sessões do mesmo usuário
+    session_id: String,
     relay_actor: actix::Addr<RelayActor>,
-    heartbeat: Instant
+    heartbeat: Instant,
+    heartbeat_interval: Duration,
+    /// Sem ping/pong do cliente por mais que isso, a conexão é encerrada
+    client_timeout: Duration,
+    /// Versão do protocolo negociada no upgrade
+    version: u16,
+    /// Salas em que esta conexão está, mantidas a partir dos eventos do relay
+    rooms: HashSet<String>,
+    /// Mensagens deste cliente ainda sem ack final, por `message_id`
+    sent: HashMap<String, SentMessage>,
+    /// Mensagens entregues a este cliente que ele ainda não confirmou, em ordem
+    unacked: VecDeque<PendingDelivery>,
+    /// Retomada pedida no upgrade, repassada ao relay no registro
+    resume: Option<ResumeRequest>,
 }
 
 impl WsConn {
-    pub fn new(username: String, relay_actor: actix::Addr<RelayActor>) -> Self {
+    pub fn new(
+        username: String,
+        relay_actor: actix::Addr<RelayActor>,
+        version: u16,
+        resume: Option<ResumeRequest>,
+    ) -> Self {
         WsConn {
             username,
+            session_id: Uuid::new_v4().to_string(),
             relay_actor,
-            heartbeat: Instant::now()
+            heartbeat: Instant::now(),
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            client_timeout: CLIENT_TIMEOUT,
+            version,
+            rooms: HashSet::new(),
+            sent: HashMap::new(),
+            unacked: VecDeque::new(),
+            resume,
         }
     }
     
+    #[cfg(test)]
+    fn with_heartbeat(mut self, interval: Duration, client_timeout: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self.client_timeout = client_timeout;
+        self
+    }
+    
     fn heartbeat(&mut self, ctx: &mut <WsConn as Actor>::Context) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if act.heartbeat.duration_since(Instant::now()) > CLIENT_TIMEOUT {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > act.client_timeout {
                 ctx.stop();
                 return;
             }
@@ -33,6 +85,109 @@ impl WsConn {
             ctx.ping(&[])
         });
     }
+
+    fn send_frame(&self, ctx: &mut <WsConn as Actor>::Context, id: Option<String>, frame: ServerFrame) {
+        let envelope = Envelope::new(self.version, id, frame);
+        match serde_json::to_string(&envelope) {
+            Ok(content) => ctx.write_raw(Message::Text(ByteString::from(content))),
+            Err(e) => eprintln!("Falha ao serializar frame para {}: {}", self.username, e),
+        }
+    }
+
+    fn send_error(&self, ctx: &mut <WsConn as Actor>::Context, id: Option<String>, code: ErrorCode, message: &str) {
+        self.send_frame(ctx, id, ServerFrame::error(code, message));
+    }
+
+    /// Registra uma mensagem entregue até o cliente confirmar com `ack`
+    fn track_delivery(&mut self, delivery: PendingDelivery) {
+        if self.unacked.len() >= MAX_UNACKED {
+            self.unacked.pop_front();
+            eprintln!("Conexão de {}: muitas mensagens sem ack, descartando a mais antiga", self.username);
+        }
+        self.unacked.push_back(delivery);
+    }
+
+    fn track_sent(&mut self, client_id: Option<String>, awaits_persistence: bool) -> String {
+        let message_id = Uuid::new_v4().to_string();
+        self.sent.insert(message_id.clone(), SentMessage { client_id, awaits_persistence });
+        message_id
+    }
+
+    fn handle_client_ack(&mut self, message_id: &str) {
+        let Some(position) = self.unacked.iter().position(|delivery| delivery.message_id() == message_id) else {
+            return;
+        };
+
+        if let Some(PendingDelivery::Direct(direct)) = self.unacked.remove(position) {
+            self.relay_actor.do_send(DeliveryReceipt {
+                message_id: direct.message_id,
+                from: direct.from,
+                to: self.username.clone(),
+            });
+        }
+    }
+
+    fn handle_text(&mut self, text: &str, ctx: &mut <WsConn as Actor>::Context) {
+        let envelope = match serde_json::from_str::<Envelope<ClientFrame>>(text) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                // Tenta ao menos recuperar o `id` para o cliente correlacionar o erro
+                let id = serde_json::from_str::<serde_json::Value>(text).ok()
+                    .and_then(|value| value.get("id")?.as_str().map(str::to_string));
+                self.send_error(ctx, id, ErrorCode::MalformedFrame, &e.to_string());
+                return;
+            }
+        };
+
+        let Envelope { version, id, frame } = envelope;
+        if version != self.version {
+            let message = format!("Frame com versão {}, conexão negociou a versão {}", version, self.version);
+            self.send_error(ctx, id, ErrorCode::UnsupportedVersion, &message);
+            return;
+        }
+
+        let username = self.username.clone();
+        match frame {
+            ClientFrame::Message { room_id, content } => {
+                if !self.rooms.contains(&room_id) {
+                    self.send_error(ctx, id, ErrorCode::NotRoomMember, "Entre na sala antes de enviar mensagens");
+                    return;
+                }
+                let message_id = self.track_sent(id, true);
+                self.relay_actor.do_send(UserMessage {
+                    message_id,
+                    seq: 0,
+                    username,
+                    content,
+                    room_id,
+                    origin_session: Some(self.session_id.clone()),
+                });
+            }
+            ClientFrame::JoinRoom { room_id } => {
+                if !is_valid_room_id(&room_id) {
+                    self.send_error(ctx, id, ErrorCode::InvalidRoom, "room_id inválido");
+                    return;
+                }
+                self.relay_actor.do_send(JoinRoom { username, room_id });
+            }
+            ClientFrame::LeaveRoom { room_id } => {
+                self.relay_actor.do_send(LeaveRoom { username, room_id });
+            }
+            ClientFrame::DirectMessage { to, content } => {
+                let message_id = self.track_sent(id, false);
+                self.relay_actor.do_send(DirectMessage {
+                    message_id,
+                    from: username,
+                    to,
+                    content,
+                    origin_session: Some(self.session_id.clone()),
+                });
+            }
+            ClientFrame::Ack { message_id } => {
+                self.handle_client_ack(&message_id);
+            }
+        }
+    }
 }
 
 impl Actor for WsConn {
@@ -41,15 +196,32 @@ impl Actor for WsConn {
     fn started(&mut self, ctx: &mut Self::Context) {
         self.heartbeat(ctx);
 
+        self.send_frame(ctx, None, ServerFrame::Welcome {
+            username: self.username.clone(),
+            session_id: self.session_id.clone(),
+            version: self.version,
+        });
+
         self.relay_actor.do_send(RegisterConnection {
             username: self.username.clone(),
+            session_id: self.session_id.clone(),
             addr: ctx.address(),
+            resume: self.resume.take(),
         });
     }
 
-    fn stopped(&mut self, ctx: &mut Self::Context) {
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        // O que não foi confirmado é reenviado na próxima conexão do usuário
+        if !self.unacked.is_empty() {
+            self.relay_actor.do_send(StoreUnacked {
+                username: self.username.clone(),
+                deliveries: self.unacked.drain(..).collect(),
+            });
+        }
+
         self.relay_actor.do_send(UnRegisterConnection {
             username: self.username.clone(),
+            session_id: self.session_id.clone(),
         });
     }
 }
@@ -58,8 +230,14 @@ impl Handler<UserMessage> for WsConn {
     type Result = ();
 
     fn handle(&mut self, msg: UserMessage, ctx: &mut Self::Context) -> Self::Result {
-        let content = serde_json::to_string(&msg).unwrap();
-        ctx.write_raw(Message::Text(ByteString::from(content)));
+        self.send_frame(ctx, None, ServerFrame::Message {
+            message_id: msg.message_id.clone(),
+            seq: msg.seq,
+            username: msg.username.clone(),
+            room_id: msg.room_id.clone(),
+            content: msg.content.clone(),
+        });
+        self.track_delivery(PendingDelivery::Room(msg));
     }
 }
 
@@ -67,8 +245,7 @@ impl Handler<JoinEvent> for WsConn {
     type Result = ();
 
     fn handle(&mut self, msg: JoinEvent, ctx: &mut Self::Context) -> Self::Result {
-        let content = serde_json::to_string(&msg).unwrap();
-        ctx.write_raw(Message::Text(ByteString::from(content)));
+        self.send_frame(ctx, None, ServerFrame::UserJoined { username: msg.username });
     }
 }
 
@@ -76,8 +253,101 @@ impl Handler<UnRegisterConnection> for WsConn {
     type Result = ();
 
     fn handle(&mut self, msg: UnRegisterConnection, ctx: &mut Self::Context) -> Self::Result {
-        let content = serde_json::to_string(&msg).unwrap();
-        ctx.write_raw(Message::Text(ByteString::from(content)));
+        self.send_frame(ctx, None, ServerFrame::UserLeft { username: msg.username });
+    }
+}
+
+impl Handler<JoinRoom> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: JoinRoom, ctx: &mut Self::Context) -> Self::Result {
+        if msg.username == self.username {
+            self.rooms.insert(msg.room_id.clone());
+        }
+        self.send_frame(ctx, None, ServerFrame::RoomJoined { username: msg.username, room_id: msg.room_id });
+    }
+}
+
+impl Handler<LeaveRoom> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaveRoom, ctx: &mut Self::Context) -> Self::Result {
+        if msg.username == self.username {
+            self.rooms.remove(&msg.room_id);
+        }
+        self.send_frame(ctx, None, ServerFrame::RoomLeft { username: msg.username, room_id: msg.room_id });
+    }
+}
+
+impl Handler<DirectMessage> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: DirectMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.send_frame(ctx, None, ServerFrame::DirectMessage {
+            message_id: msg.message_id.clone(),
+            from: msg.from.clone(),
+            to: msg.to.clone(),
+            content: msg.content.clone(),
+        });
+        self.track_delivery(PendingDelivery::Direct(msg));
+    }
+}
+
+impl Handler<DirectMessageQueued> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: DirectMessageQueued, ctx: &mut Self::Context) -> Self::Result {
+        let client_id = self.sent.remove(&msg.message_id).and_then(|sent| sent.client_id);
+        self.send_frame(ctx, client_id, ServerFrame::DirectQueued { message_id: msg.message_id, to: msg.to });
+    }
+}
+
+impl Handler<MessageAck> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: MessageAck, ctx: &mut Self::Context) -> Self::Result {
+        let Some(sent) = self.sent.get(&msg.message_id) else {
+            return;
+        };
+
+        let client_id = sent.client_id.clone();
+        let is_final = !matches!(msg.status, AckStatus::Accepted) || !sent.awaits_persistence;
+        if is_final {
+            self.sent.remove(&msg.message_id);
+        }
+        self.send_frame(ctx, client_id, ServerFrame::Ack { message_id: msg.message_id, status: msg.status });
+    }
+}
+
+impl Handler<ResumeOutcome> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: ResumeOutcome, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            ResumeOutcome::Resumed { replayed } => {
+                self.send_frame(ctx, None, ServerFrame::Resumed { replayed });
+            }
+            ResumeOutcome::Expired => {
+                self.send_error(ctx, None, ErrorCode::ResumeFailed, "Sessão anterior expirada; conectado como nova sessão");
+            }
+        }
+    }
+}
+
+impl Handler<DeliveryReceipt> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeliveryReceipt, ctx: &mut Self::Context) -> Self::Result {
+        self.send_frame(ctx, None, ServerFrame::Delivered { message_id: msg.message_id, to: msg.to });
+    }
+}
+
+impl Handler<SwitchRelay> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: SwitchRelay, _ctx: &mut Self::Context) -> Self::Result {
+        // O socket e o estado da conexão continuam; só o relay muda
+        self.relay_actor = msg.relay_actor;
     }
 }
 
@@ -92,9 +362,10 @@ impl StreamHandler<Result<Message, ProtocolError>> for WsConn {
                 self.heartbeat = Instant::now();
             },
             Ok(Message::Text(text)) => {
-                if let Ok(message) = serde_json::from_str::<UserMessage>(&text) {
-                    self.relay_actor.do_send(message);
-                }
+                self.handle_text(&text, ctx);
+            },
+            Ok(Message::Binary(_)) => {
+                self.send_error(ctx, None, ErrorCode::UnsupportedFrame, "Frames binários não são suportados");
             },
             Ok(Message::Close(_)) => {
                 ctx.stop();
@@ -102,4 +373,141 @@ impl StreamHandler<Result<Message, ProtocolError>> for WsConn {
             _ => {}
         }
     }
-}
\ No newline at end of file
+}
+
+impl Handler<Reconnect> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reconnect, ctx: &mut Self::Context) -> Self::Result {
+        self.send_frame(ctx, None, ServerFrame::Reconnect {
+            reason: "shutdown".to_string(),
+            retry_after_ms: msg.retry_after.as_millis() as u64,
+            resume_token: self.session_id.clone(),
+        });
+
+        ctx.run_later(msg.retry_after, |_act, ctx| {
+            ctx.close(Some(CloseReason::from((CloseCode::Restart, "pod encerrando"))));
+            ctx.stop();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use actix::Context;
+    use actix_web::error::PayloadError;
+    use actix_web::web::Bytes;
+    use futures_util::{stream, StreamExt};
+    use serde_json::json;
+    use crate::actors::DEFAULT_ROOM;
+    use crate::actors::testing::{start_relay, TestClient};
+    use crate::bus::memory::InMemoryBus;
+
+    #[actix::test]
+    async fn connection_without_pong_is_stopped() {
+        // O relay nunca roda; as mensagens de registro ficam só na caixa de entrada
+        let relay = Context::<RelayActor>::new().address();
+        let conn = WsConn::new("alice".to_string(), relay, 1, None)
+            .with_heartbeat(Duration::from_millis(20), Duration::from_millis(60));
+
+        // Cliente que nunca envia nada, nem pong
+        let mut output = WebsocketContext::create(conn, stream::pending::<Result<Bytes, PayloadError>>());
+        let drained = tokio::time::timeout(Duration::from_secs(2), async {
+            while output.next().await.is_some() {}
+        }).await;
+
+        assert!(drained.is_ok(), "conexão sem pong deveria ter sido encerrada");
+    }
+
+    #[actix::test]
+    async fn frames_with_another_version_are_rejected_with_their_id() {
+        let relay = Context::<RelayActor>::new().address();
+        let mut client = TestClient::connect("alice", relay).await;
+
+        client.send_raw(&json!({
+            "type": "join_room",
+            "version": 2,
+            "id": "c1",
+            "payload": { "room_id": "dev" },
+        }).to_string());
+        let error = client.expect("error").await;
+        assert_eq!(error["id"], "c1");
+        assert_eq!(error["version"], 1);
+        assert_eq!(error["payload"]["code"], "unsupported_version");
+    }
+
+    #[actix::test]
+    async fn malformed_frames_are_answered_with_an_error() {
+        let relay = Context::<RelayActor>::new().address();
+        let mut client = TestClient::connect("alice", relay).await;
+
+        // Envelope válido com payload errado: o `id` ainda é recuperado
+        client.send_raw(&json!({ "type": "message", "version": 1, "id": "c1", "payload": {} }).to_string());
+        let error = client.expect("error").await;
+        assert_eq!(error["id"], "c1");
+        assert_eq!(error["payload"]["code"], "malformed_frame");
+
+        client.send_raw("não é json");
+        let error = client.expect("error").await;
+        assert!(error.get("id").is_none());
+        assert_eq!(error["payload"]["code"], "malformed_frame");
+    }
+
+    /// Bob recebe uma mensagem de Alice e desconecta; Alice vê a saída dele
+    async fn deliver_then_disconnect(ack: bool) -> (TestClient, actix::Addr<RelayActor>, String) {
+        let relay = start_relay(Arc::new(InMemoryBus::new("pod-a".to_string())), 1);
+        let mut bob = TestClient::connect("bob", relay.clone()).await;
+        let mut alice = TestClient::connect("alice", relay.clone()).await;
+
+        alice.send("message", Some("c1"), json!({ "room_id": DEFAULT_ROOM, "content": "oi" }));
+        let accepted = alice.expect("ack").await;
+        assert_eq!(accepted["id"], "c1");
+        assert_eq!(accepted["payload"]["status"], "accepted");
+
+        let delivered = bob.expect("message").await;
+        let message_id = delivered["payload"]["message_id"].as_str().unwrap().to_string();
+        if ack {
+            bob.send("ack", None, json!({ "message_id": message_id }));
+        }
+        bob.close();
+        // A saída só é anunciada depois das entregas pendentes guardadas
+        alice.expect_where("user_left", |frame| frame["payload"]["username"] == "bob").await;
+        (alice, relay, message_id)
+    }
+
+    #[actix::test]
+    async fn unacked_messages_are_redelivered_on_the_next_connection() {
+        let (_alice, relay, message_id) = deliver_then_disconnect(false).await;
+
+        let mut bob = TestClient::connect("bob", relay).await;
+        let redelivered = bob.expect("message").await;
+        assert_eq!(redelivered["payload"]["message_id"], message_id.as_str());
+        assert_eq!(redelivered["payload"]["content"], "oi");
+    }
+
+    #[actix::test]
+    async fn acked_messages_are_not_redelivered() {
+        let (_alice, relay, _) = deliver_then_disconnect(true).await;
+
+        let mut bob = TestClient::connect("bob", relay).await;
+        let frames = bob.frames_within(Duration::from_millis(200)).await;
+        assert!(frames.iter().all(|frame| frame["type"] != "message"), "reenviado: {:?}", frames);
+    }
+
+    #[actix::test]
+    async fn acking_a_direct_message_notifies_the_sender() {
+        let relay = start_relay(Arc::new(InMemoryBus::new("pod-a".to_string())), 1);
+        let mut bob = TestClient::connect("bob", relay.clone()).await;
+        let mut alice = TestClient::connect("alice", relay.clone()).await;
+
+        alice.send("direct_message", Some("d1"), json!({ "to": "bob", "content": "oi" }));
+        let received = bob.expect("direct_message").await;
+        bob.send("ack", None, json!({ "message_id": received["payload"]["message_id"] }));
+
+        let delivered = alice.expect("delivered").await;
+        assert_eq!(delivered["payload"]["message_id"], received["payload"]["message_id"]);
+        assert_eq!(delivered["payload"]["to"], "bob");
+    }
+}
@@ -0,0 +1,157 @@
+use actix_web::HttpRequest;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+/// Claims emitidos pelo `POST /auth/token` do webserver
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub uid: i32,
+    pub exp: i64,
+}
+
+/// Valida os bearer tokens (HS256) com a mesma chave usada pelo webserver
+#[derive(Clone)]
+pub struct TokenVerifier {
+    decoding_key: DecodingKey,
+    validation: Validation,
+}
+
+impl TokenVerifier {
+    pub fn from_env() -> Self {
+        let secret = chat_auth::secret_from_env();
+
+        Self::new(secret.as_bytes())
+    }
+
+    pub fn new(secret: &[u8]) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+
+        Self {
+            decoding_key: DecodingKey::from_secret(secret),
+            validation,
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        decode::<Claims>(token, &self.decoding_key, &self.validation).map(|data| data.claims)
+    }
+}
+
+/// Audiência dos marcadores de redirecionamento; tokens de acesso não a têm
+const REDIRECT_AUDIENCE: &str = "pod_redirect";
+/// Validade do marcador: só o tempo de o cliente seguir o `307`
+const REDIRECT_TTL_SECS: i64 = 30;
+
+/// Claims do `redirect_token` emitido por um pod sobrecarregado
+#[derive(Debug, Serialize, Deserialize)]
+struct RedirectClaims {
+    sub: String,
+    /// Pod de destino; em outro pod o marcador não vale
+    pod: String,
+    aud: String,
+    exp: i64,
+}
+
+/// Emite e confere o marcador de redirecionamento, assinado com a mesma
+/// chave dos tokens de acesso. O pod de destino só deixa de redirecionar
+/// o cliente se o marcador foi emitido por outro pod, para este pod e para
+/// o mesmo usuário, o que evita ciclos sem deixar o cliente pular o
+/// balanceamento por conta própria.
+#[derive(Clone)]
+pub struct RedirectSigner {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    validation: Validation,
+}
+
+impl RedirectSigner {
+    pub fn from_env() -> Self {
+        Self::new(chat_auth::secret_from_env().as_bytes())
+    }
+
+    pub fn new(secret: &[u8]) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["exp", "sub", "aud"]);
+        validation.set_audience(&[REDIRECT_AUDIENCE]);
+
+        Self {
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            validation,
+        }
+    }
+
+    pub fn issue(&self, username: &str, target_pod_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+        let claims = RedirectClaims {
+            sub: username.to_string(),
+            pod: target_pod_id.to_string(),
+            aud: REDIRECT_AUDIENCE.to_string(),
+            exp: jsonwebtoken::get_current_timestamp() as i64 + REDIRECT_TTL_SECS,
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+    }
+
+    /// O marcador é válido, de `username` e aponta para `own_pod_id`
+    pub fn accepts(&self, token: &str, username: &str, own_pod_id: &str) -> bool {
+        decode::<RedirectClaims>(token, &self.decoding_key, &self.validation)
+            .is_ok_and(|data| data.claims.sub == username && data.claims.pod == own_pod_id)
+    }
+}
+
+/// Extrai o token do header `Authorization: Bearer ...` ou, para clientes de
+/// navegador que não conseguem definir headers no upgrade, de `?access_token=`
+pub fn bearer_token(req: &HttpRequest, query_token: Option<&str>) -> Option<String> {
+    let from_header = req.headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+
+    from_header.or_else(|| query_token.map(str::to_string))
+}
+
+/// Endpoints administrativos exigem `Authorization: Bearer <ADMIN_TOKEN>`;
+/// sem `ADMIN_TOKEN` definido eles ficam desabilitados. A comparação é em
+/// tempo constante para não vazar o token pelo tempo de resposta
+pub fn is_admin(req: &HttpRequest, admin_token: Option<&str>) -> bool {
+    let Some(expected) = admin_token.filter(|token| !token.is_empty()) else {
+        return false;
+    };
+
+    bearer_token(req, None).is_some_and(|token| bool::from(token.as_bytes().ct_eq(expected.as_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"3f9c1a7be0d24f6a8c5e91b7d2a40f6e";
+
+    #[test]
+    fn redirect_token_is_bound_to_user_and_target_pod() {
+        let signer = RedirectSigner::new(SECRET);
+        let token = signer.issue("alice", "pod-b").unwrap();
+
+        assert!(signer.accepts(&token, "alice", "pod-b"));
+        assert!(!signer.accepts(&token, "bob", "pod-b"));
+        assert!(!signer.accepts(&token, "alice", "pod-c"));
+        assert!(!signer.accepts("true", "alice", "pod-b"));
+    }
+
+    #[test]
+    fn redirect_token_signed_with_another_key_is_rejected() {
+        let forged = RedirectSigner::new(b"another-key-another-key-another-key").issue("alice", "pod-b").unwrap();
+
+        assert!(!RedirectSigner::new(SECRET).accepts(&forged, "alice", "pod-b"));
+    }
+
+    #[test]
+    fn redirect_token_is_not_an_access_token() {
+        let token = RedirectSigner::new(SECRET).issue("alice", "pod-b").unwrap();
+
+        assert!(TokenVerifier::new(SECRET).verify(&token).is_err());
+    }
+}
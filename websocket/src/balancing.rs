@@ -0,0 +1,488 @@
+// src/balancing.rs
+//! Estratégias de escolha usadas pelo `LoadBalancer` (entre pods) e pelo
+//! `DynamicRelayBalancer` (entre relays do pod). Cada balanceador monta os
+//! candidatos com o próprio score e a estratégia configurada escolhe um.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::seq::index;
+use rand::SeedableRng;
+use crate::hash_ring::HashRing;
+
+/// Peso mínimo no sorteio ponderado, para nenhum candidato ficar de fora
+const MIN_WEIGHT: f64 = 0.1;
+/// Nós virtuais por candidato no hash consistente
+const CONSISTENT_HASH_VNODES: usize = 100;
+
+/// Um pod ou relay que pode receber uma conexão
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub id: String,
+    pub active_connections: usize,
+    /// Score do balanceador, maior é melhor
+    pub score: f64,
+}
+
+pub trait BalancingStrategy: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    /// Índice do candidato escolhido; `key` (o username) serve às
+    /// estratégias com afinidade
+    fn select(&self, candidates: &[Candidate], key: Option<&str>) -> Option<usize>;
+}
+
+/// Pesos dos três fatores do score: conexões e os outros dois de cada
+/// balanceador (vazão e latência nos relays, CPU e memória nos pods)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreWeights {
+    pub connections: f64,
+    pub second: f64,
+    pub third: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self { connections: 0.5, second: 0.3, third: 0.2 }
+    }
+}
+
+impl ScoreWeights {
+    /// Lê `"conexões,segundo,terceiro"` de `key`
+    pub fn from_env(key: &str) -> Self {
+        let Ok(value) = std::env::var(key) else {
+            return Self::default();
+        };
+
+        match Self::parse(&value) {
+            Some(weights) => weights,
+            None => {
+                eprintln!("{} inválido '{}', usando os pesos padrão", key, value);
+                Self::default()
+            }
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let weights: Vec<f64> = value.split(',')
+            .map(|weight| weight.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        let [connections, second, third] = weights[..] else {
+            return None;
+        };
+
+        Some(Self { connections, second, third })
+    }
+
+    /// Combina fatores em `[0, 1]` (1 = mais folga)
+    pub fn score(&self, connections: f64, second: f64, third: f64) -> f64 {
+        connections * self.connections + second * self.second + third * self.third
+    }
+}
+
+/// Gerador das estratégias aleatórias: da entropia do sistema em produção,
+/// com semente fixa para sorteios reproduzíveis nos testes
+#[derive(Debug)]
+pub struct StrategyRng(Mutex<StdRng>);
+
+impl StrategyRng {
+    pub fn from_entropy() -> Self {
+        Self(Mutex::new(StdRng::from_entropy()))
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut StdRng) -> T) -> T {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Default for StrategyRng {
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
+
+fn least_connections(candidates: &[Candidate], indices: impl Iterator<Item = usize>) -> Option<usize> {
+    indices.min_by(|a, b| {
+        candidates[*a].active_connections.cmp(&candidates[*b].active_connections)
+            .then_with(|| candidates[*a].id.cmp(&candidates[*b].id))
+    })
+}
+
+/// Sorteio com probabilidade proporcional ao score
+#[derive(Debug, Default)]
+pub struct WeightedRandom {
+    rng: StrategyRng,
+}
+
+impl WeightedRandom {
+    pub fn new(rng: StrategyRng) -> Self {
+        Self { rng }
+    }
+}
+
+impl BalancingStrategy for WeightedRandom {
+    fn name(&self) -> &'static str {
+        "weighted_random"
+    }
+
+    fn select(&self, candidates: &[Candidate], _key: Option<&str>) -> Option<usize> {
+        // NaN vira o peso mínimo, então só falha sem candidatos
+        let weights = WeightedIndex::new(candidates.iter().map(|candidate| candidate.score.max(MIN_WEIGHT))).ok()?;
+        Some(self.rng.with(|rng| weights.sample(rng)))
+    }
+}
+
+/// Sempre o maior score
+#[derive(Debug)]
+pub struct HighestScore;
+
+impl BalancingStrategy for HighestScore {
+    fn name(&self) -> &'static str {
+        "highest_score"
+    }
+
+    fn select(&self, candidates: &[Candidate], _key: Option<&str>) -> Option<usize> {
+        (0..candidates.len()).max_by(|a, b| {
+            candidates[*a].score.total_cmp(&candidates[*b].score)
+                .then_with(|| candidates[*b].id.cmp(&candidates[*a].id))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct LeastConnections;
+
+impl BalancingStrategy for LeastConnections {
+    fn name(&self) -> &'static str {
+        "least_connections"
+    }
+
+    fn select(&self, candidates: &[Candidate], _key: Option<&str>) -> Option<usize> {
+        least_connections(candidates, 0..candidates.len())
+    }
+}
+
+/// Sorteia dois candidatos distintos e fica com o de menos conexões
+#[derive(Debug, Default)]
+pub struct PowerOfTwoChoices {
+    rng: StrategyRng,
+}
+
+impl PowerOfTwoChoices {
+    pub fn new(rng: StrategyRng) -> Self {
+        Self { rng }
+    }
+}
+
+impl BalancingStrategy for PowerOfTwoChoices {
+    fn name(&self) -> &'static str {
+        "power_of_two"
+    }
+
+    fn select(&self, candidates: &[Candidate], _key: Option<&str>) -> Option<usize> {
+        match candidates.len() {
+            0 => None,
+            1 => Some(0),
+            len => {
+                let pair = self.rng.with(|rng| index::sample(rng, len, 2));
+                least_connections(candidates, pair.into_iter())
+            }
+        }
+    }
+}
+
+/// Percorre os candidatos em ordem de id
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl BalancingStrategy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round_robin"
+    }
+
+    fn select(&self, candidates: &[Candidate], _key: Option<&str>) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by(|a, b| candidates[*a].id.cmp(&candidates[*b].id));
+        Some(order[self.next.fetch_add(1, Ordering::Relaxed) % order.len()])
+    }
+}
+
+/// Mesmo username, mesmo candidato enquanto ele existir; sem chave, o de
+/// menos conexões
+#[derive(Debug)]
+pub struct ConsistentHash;
+
+impl BalancingStrategy for ConsistentHash {
+    fn name(&self) -> &'static str {
+        "consistent_hash"
+    }
+
+    fn select(&self, candidates: &[Candidate], key: Option<&str>) -> Option<usize> {
+        let Some(key) = key else {
+            return least_connections(candidates, 0..candidates.len());
+        };
+
+        let mut ring = HashRing::new(CONSISTENT_HASH_VNODES);
+        for (index, candidate) in candidates.iter().enumerate() {
+            ring.add(index, &candidate.id);
+        }
+        ring.get(key)
+    }
+}
+
+pub fn strategy_from_name(name: &str) -> Option<Arc<dyn BalancingStrategy>> {
+    let strategy: Arc<dyn BalancingStrategy> = match name {
+        "weighted_random" => Arc::new(WeightedRandom::default()),
+        "highest_score" => Arc::new(HighestScore),
+        "least_connections" => Arc::new(LeastConnections),
+        "power_of_two" => Arc::new(PowerOfTwoChoices::default()),
+        "round_robin" => Arc::new(RoundRobin::default()),
+        "consistent_hash" => Arc::new(ConsistentHash),
+        _ => return None,
+    };
+    Some(strategy)
+}
+
+/// Estratégia nomeada em `key`, ou `default` se ausente ou desconhecida
+pub fn strategy_from_env(key: &str, default: &str) -> Arc<dyn BalancingStrategy> {
+    let name = std::env::var(key).unwrap_or_else(|_| default.to_string());
+    strategy_from_name(&name).unwrap_or_else(|| {
+        eprintln!("{} desconhecida '{}', usando {}", key, name, default);
+        strategy_from_name(default).expect("estratégia padrão deve existir")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn candidate(id: &str, active_connections: usize, score: f64) -> Candidate {
+        Candidate { id: id.to_string(), active_connections, score }
+    }
+
+    /// Relays com carga crescente: `relay-1` é o mais folgado
+    fn simulated_relays() -> Vec<Candidate> {
+        vec![
+            candidate("relay-3", 700, 0.2),
+            candidate("relay-1", 50, 0.9),
+            candidate("relay-2", 300, 0.6),
+        ]
+    }
+
+    fn pick_counts(strategy: &dyn BalancingStrategy, candidates: &[Candidate], rounds: usize) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..rounds {
+            let index = strategy.select(candidates, None).unwrap();
+            *counts.entry(candidates[index].id.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn every_strategy_handles_empty_and_single_candidate() {
+        let single = vec![candidate("relay-1", 10, 0.5)];
+        for name in ["weighted_random", "highest_score", "least_connections", "power_of_two", "round_robin", "consistent_hash"] {
+            let strategy = strategy_from_name(name).unwrap();
+            assert_eq!(strategy.name(), name);
+            assert_eq!(strategy.select(&[], Some("alice")), None, "{}", name);
+            assert_eq!(strategy.select(&single, Some("alice")), Some(0), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_strategy_name_is_rejected() {
+        assert!(strategy_from_name("random").is_none());
+    }
+
+    #[test]
+    fn highest_score_picks_best_score() {
+        let relays = simulated_relays();
+        assert_eq!(relays[HighestScore.select(&relays, None).unwrap()].id, "relay-1");
+    }
+
+    #[test]
+    fn least_connections_picks_least_loaded() {
+        let relays = simulated_relays();
+        assert_eq!(relays[LeastConnections.select(&relays, None).unwrap()].id, "relay-1");
+    }
+
+    #[test]
+    fn least_connections_breaks_ties_by_id() {
+        let relays = vec![candidate("relay-2", 10, 0.5), candidate("relay-1", 10, 0.5)];
+        assert_eq!(relays[LeastConnections.select(&relays, None).unwrap()].id, "relay-1");
+    }
+
+    const SEED: u64 = 42;
+    const DRAWS: usize = 60_000;
+
+    fn seeded_weighted() -> WeightedRandom {
+        WeightedRandom::new(StrategyRng::seeded(SEED))
+    }
+
+    /// Fração esperada de cada candidato no sorteio ponderado
+    fn expected_shares(candidates: &[Candidate]) -> HashMap<String, f64> {
+        let total: f64 = candidates.iter().map(|candidate| candidate.score.max(MIN_WEIGHT)).sum();
+        candidates.iter()
+            .map(|candidate| (candidate.id.clone(), candidate.score.max(MIN_WEIGHT) / total))
+            .collect()
+    }
+
+    fn assert_shares(counts: &HashMap<String, usize>, expected: &HashMap<String, f64>, tolerance: f64) {
+        let draws: usize = counts.values().sum();
+        for (id, share) in expected {
+            let observed = counts.get(id).copied().unwrap_or(0) as f64 / draws as f64;
+            assert!((observed - share).abs() < tolerance, "{}: observado {:.4}, esperado {:.4}", id, observed, share);
+        }
+    }
+
+    #[test]
+    fn weighted_random_honours_score_shares() {
+        let relays = simulated_relays();
+        let counts = pick_counts(&seeded_weighted(), &relays, DRAWS);
+
+        assert_shares(&counts, &expected_shares(&relays), 0.01);
+    }
+
+    #[test]
+    fn weighted_random_passes_chi_square() {
+        let pods = vec![
+            candidate("pod-a", 0, 1.0),
+            candidate("pod-b", 0, 0.7),
+            candidate("pod-c", 0, 0.45),
+            candidate("pod-d", 0, 0.3),
+            candidate("pod-e", 0, 0.15),
+        ];
+        let counts = pick_counts(&seeded_weighted(), &pods, DRAWS);
+
+        let chi_square: f64 = expected_shares(&pods).iter()
+            .map(|(id, share)| {
+                let expected = share * DRAWS as f64;
+                let observed = counts.get(id).copied().unwrap_or(0) as f64;
+                (observed - expected).powi(2) / expected
+            })
+            .sum();
+        // Valor crítico para 4 graus de liberdade com p = 0,001
+        assert!(chi_square < 18.47, "qui-quadrado {:.2}", chi_square);
+    }
+
+    #[test]
+    fn weighted_random_ignores_candidate_order() {
+        let relays = simulated_relays();
+        let mut reversed = relays.clone();
+        reversed.reverse();
+
+        let expected = expected_shares(&relays);
+        assert_shares(&pick_counts(&seeded_weighted(), &relays, DRAWS), &expected, 0.01);
+        assert_shares(&pick_counts(&seeded_weighted(), &reversed, DRAWS), &expected, 0.01);
+    }
+
+    #[test]
+    fn weighted_random_floors_low_scores() {
+        let relays = vec![candidate("relay-1", 0, 1.0), candidate("relay-2", 800, 0.0), candidate("relay-3", 900, f64::NAN)];
+        let counts = pick_counts(&seeded_weighted(), &relays, DRAWS);
+
+        assert_shares(&counts, &expected_shares(&relays), 0.01);
+        assert!(counts["relay-2"] > 0 && counts["relay-3"] > 0);
+    }
+
+    #[test]
+    fn same_seed_reproduces_choices() {
+        let relays = simulated_relays();
+        let sequence = |strategy: &dyn BalancingStrategy| -> Vec<usize> {
+            (0..50).map(|_| strategy.select(&relays, None).unwrap()).collect()
+        };
+
+        assert_eq!(sequence(&seeded_weighted()), sequence(&seeded_weighted()));
+        assert_ne!(sequence(&seeded_weighted()), sequence(&WeightedRandom::new(StrategyRng::seeded(SEED + 1))));
+        assert_eq!(
+            sequence(&PowerOfTwoChoices::new(StrategyRng::seeded(SEED))),
+            sequence(&PowerOfTwoChoices::new(StrategyRng::seeded(SEED))),
+        );
+    }
+
+    #[test]
+    fn power_of_two_never_picks_most_loaded() {
+        let relays = simulated_relays();
+        let counts = pick_counts(&PowerOfTwoChoices::new(StrategyRng::seeded(SEED)), &relays, DRAWS);
+
+        // Os três pares são equiprováveis: o menos carregado vence dois deles
+        assert!(!counts.contains_key("relay-3"));
+        let expected = HashMap::from([
+            ("relay-1".to_string(), 2.0 / 3.0),
+            ("relay-2".to_string(), 1.0 / 3.0),
+        ]);
+        assert_shares(&counts, &expected, 0.01);
+    }
+
+    #[test]
+    fn round_robin_cycles_in_id_order() {
+        let relays = simulated_relays();
+        let strategy = RoundRobin::default();
+
+        let picked: Vec<&str> = (0..6)
+            .map(|_| relays[strategy.select(&relays, None).unwrap()].id.as_str())
+            .collect();
+        assert_eq!(picked, ["relay-1", "relay-2", "relay-3", "relay-1", "relay-2", "relay-3"]);
+    }
+
+    #[test]
+    fn consistent_hash_is_sticky_per_username() {
+        let relays = simulated_relays();
+        let first = ConsistentHash.select(&relays, Some("alice")).unwrap();
+
+        // Carga e ordem dos candidatos não mudam o destino
+        let mut reloaded = relays.clone();
+        reloaded.reverse();
+        for relay in &mut reloaded {
+            relay.active_connections += 100;
+        }
+        let again = ConsistentHash.select(&reloaded, Some("alice")).unwrap();
+        assert_eq!(relays[first].id, reloaded[again].id);
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_keys_of_removed_candidate() {
+        let relays = simulated_relays();
+        let without_relay_3: Vec<Candidate> = relays.iter().filter(|relay| relay.id != "relay-3").cloned().collect();
+
+        for user in 0..500 {
+            let username = format!("user-{}", user);
+            let before = &relays[ConsistentHash.select(&relays, Some(&username)).unwrap()].id;
+            let after = &without_relay_3[ConsistentHash.select(&without_relay_3, Some(&username)).unwrap()].id;
+            if before != "relay-3" {
+                assert_eq!(before, after, "{} mudou de relay sem necessidade", username);
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hash_without_key_uses_least_connections() {
+        let relays = simulated_relays();
+        assert_eq!(relays[ConsistentHash.select(&relays, None).unwrap()].id, "relay-1");
+    }
+
+    #[test]
+    fn score_weights_parse_from_list() {
+        assert_eq!(ScoreWeights::parse("0.6, 0.2,0.2"), Some(ScoreWeights { connections: 0.6, second: 0.2, third: 0.2 }));
+        assert_eq!(ScoreWeights::parse("0.6,0.4"), None);
+        assert_eq!(ScoreWeights::parse("a,b,c"), None);
+    }
+
+    #[test]
+    fn default_weights_match_previous_formula() {
+        let weights = ScoreWeights::default();
+        assert!((weights.score(1.0, 0.5, 0.25) - (0.5 + 0.15 + 0.05)).abs() < 1e-9);
+    }
+}
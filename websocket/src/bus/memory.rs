@@ -0,0 +1,390 @@
+// src/bus/memory.rs
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use tokio::sync::{broadcast, mpsc};
+use crate::actors::{DirectMessage, PendingDelivery, RedisMessage, RedisMessageType, ResumeState, UserMessage};
+use super::{
+    BusResult, MessageBus, Subscription, unix_now, OFFLINE_QUEUE_MAX_LEN, PENDING_DELIVERY_MAX_LEN,
+    RESUME_TTL_SECS, ROOM_BUFFER_MAX_LEN, ROOM_BUFFER_TTL_SECS, USER_LOCATION_TTL_SECS,
+};
+
+/// Publicações que um assinante lento pode acumular antes de perder as mais antigas
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Message bus dentro do processo: para testes e para implantações de um
+/// único pod, sem Redis. Nada sobrevive a um restart.
+pub struct InMemoryBus {
+    pod_id: String,
+    channels: Mutex<HashMap<String, broadcast::Sender<RedisMessage>>>,
+    state: Mutex<State>,
+    /// Sem escrita por esse tempo, o buffer da sala expira
+    room_buffer_ttl: Duration,
+    resume_ttl: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    /// username -> session_id -> (relay_id, visto em)
+    sessions: HashMap<String, HashMap<String, (u32, u64)>>,
+    offline_messages: HashMap<String, VecDeque<DirectMessage>>,
+    pending_deliveries: HashMap<String, VecDeque<PendingDelivery>>,
+    /// Buffer de cada sala e quando foi escrito por último
+    room_buffers: HashMap<String, (VecDeque<UserMessage>, Instant)>,
+    /// Última `seq` de cada sala; não expira junto com o buffer
+    room_seqs: HashMap<String, u64>,
+    resume_states: HashMap<String, (ResumeState, Instant)>,
+}
+
+impl InMemoryBus {
+    pub fn new(pod_id: String) -> Self {
+        println!("🎯 Message bus em memória inicializado (pod {})", pod_id);
+
+        Self {
+            pod_id,
+            channels: Mutex::new(HashMap::new()),
+            state: Mutex::new(State::default()),
+            room_buffer_ttl: Duration::from_secs(ROOM_BUFFER_TTL_SECS),
+            resume_ttl: Duration::from_secs(RESUME_TTL_SECS),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_ttls(mut self, room_buffer_ttl: Duration, resume_ttl: Duration) -> Self {
+        self.room_buffer_ttl = room_buffer_ttl;
+        self.resume_ttl = resume_ttl;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn channel(&self, channel: &str) -> broadcast::Sender<RedisMessage> {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+        // Canais sem assinantes (salas que esvaziaram) não guardam nada
+        channels.retain(|name, sender| name == channel || sender.receiver_count() > 0);
+        channels.entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .clone()
+    }
+
+    fn live_sessions(state: &State, username: &str) -> usize {
+        let now = unix_now();
+        state.sessions.get(username).map_or(0, |sessions| {
+            sessions.values()
+                .filter(|(_, seen_at)| now.saturating_sub(*seen_at) <= USER_LOCATION_TTL_SECS)
+                .count()
+        })
+    }
+}
+
+/// Acrescenta ao fim da fila descartando as mais antigas acima de `max_len`
+fn push_capped<T>(queue: &mut VecDeque<T>, items: impl IntoIterator<Item = T>, max_len: usize) {
+    queue.extend(items);
+    while queue.len() > max_len {
+        queue.pop_front();
+    }
+}
+
+#[async_trait]
+impl MessageBus for InMemoryBus {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn pod_id(&self) -> &str {
+        &self.pod_id
+    }
+
+    async fn publish(&self, channel: &str, from_relay_id: u32, message_type: RedisMessageType) -> BusResult<()> {
+        let message = RedisMessage {
+            from_pod_id: self.pod_id.clone(),
+            from_relay_id,
+            message_type,
+            timestamp: unix_now(),
+        };
+
+        // Sem assinantes a publicação simplesmente se perde, como no Pub/Sub
+        let _ = self.channel(channel).send(message);
+        Ok(())
+    }
+
+    fn subscribe(&self, channel: &str, relay_id: u32, tx: mpsc::UnboundedSender<RedisMessage>) -> Subscription {
+        let mut rx = self.channel(channel).subscribe();
+        let pod_id = self.pod_id.clone();
+        let name = channel.to_string();
+
+        let task = tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(message) => {
+                        let is_own = message.from_pod_id == pod_id && message.from_relay_id == relay_id;
+                        if !is_own && tx.send(message).is_err() {
+                            return;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        eprintln!("Canal {}: assinante atrasado, {} mensagens descartadas", name, skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+
+        Subscription::new(channel.to_string(), 0, task)
+    }
+
+    async fn health_check(&self) -> bool {
+        true
+    }
+
+    async fn add_user_session(&self, username: &str, session_id: &str, relay_id: u32) -> BusResult<usize> {
+        let mut state = self.state();
+        state.sessions.entry(username.to_string())
+            .or_default()
+            .insert(session_id.to_string(), (relay_id, unix_now()));
+        Ok(Self::live_sessions(&state, username))
+    }
+
+    async fn remove_user_session(&self, username: &str, session_id: &str) -> BusResult<usize> {
+        let mut state = self.state();
+        if let Some(sessions) = state.sessions.get_mut(username) {
+            sessions.remove(session_id);
+            if sessions.is_empty() {
+                state.sessions.remove(username);
+            }
+        }
+        Ok(Self::live_sessions(&state, username))
+    }
+
+    async fn refresh_user_sessions(&self, sessions: Vec<(String, String)>, relay_id: u32) -> BusResult<()> {
+        let now = unix_now();
+        let mut state = self.state();
+        for (username, session_id) in sessions {
+            state.sessions.entry(username).or_default().insert(session_id, (relay_id, now));
+        }
+        Ok(())
+    }
+
+    async fn move_user_sessions(&self, username: &str, session_ids: Vec<String>, relay_id: u32) -> BusResult<()> {
+        let now = unix_now();
+        let mut state = self.state();
+        let sessions = state.sessions.entry(username.to_string()).or_default();
+        for session_id in session_ids {
+            sessions.insert(session_id, (relay_id, now));
+        }
+        Ok(())
+    }
+
+    async fn get_user_locations(&self, username: &str) -> BusResult<Vec<(String, u32)>> {
+        let now = unix_now();
+        let state = self.state();
+        let mut relays: Vec<u32> = state.sessions.get(username)
+            .map(|sessions| {
+                sessions.values()
+                    .filter(|(_, seen_at)| now.saturating_sub(*seen_at) <= USER_LOCATION_TTL_SECS)
+                    .map(|(relay_id, _)| *relay_id)
+                    .collect()
+            })
+            .unwrap_or_default();
+        relays.sort();
+        relays.dedup();
+
+        Ok(relays.into_iter().map(|relay_id| (self.pod_id.clone(), relay_id)).collect())
+    }
+
+    async fn push_offline_message(&self, message: &DirectMessage) -> BusResult<()> {
+        let mut state = self.state();
+        let queue = state.offline_messages.entry(message.to.clone()).or_default();
+        push_capped(queue, [message.clone()], OFFLINE_QUEUE_MAX_LEN);
+        Ok(())
+    }
+
+    async fn take_offline_messages(&self, username: &str) -> BusResult<Vec<DirectMessage>> {
+        let queue = self.state().offline_messages.remove(username).unwrap_or_default();
+        Ok(queue.into())
+    }
+
+    async fn push_pending_deliveries(&self, username: &str, deliveries: &[PendingDelivery]) -> BusResult<()> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.state();
+        let queue = state.pending_deliveries.entry(username.to_string()).or_default();
+        push_capped(queue, deliveries.iter().cloned(), PENDING_DELIVERY_MAX_LEN);
+        Ok(())
+    }
+
+    async fn take_pending_deliveries(&self, username: &str) -> BusResult<Vec<PendingDelivery>> {
+        let queue = self.state().pending_deliveries.remove(username).unwrap_or_default();
+        Ok(queue.into())
+    }
+
+    async fn buffer_room_message(&self, mut message: UserMessage) -> BusResult<UserMessage> {
+        let now = Instant::now();
+        let mut state = self.state();
+        let seq = state.room_seqs.entry(message.room_id.clone()).or_default();
+        *seq += 1;
+        message.seq = *seq;
+
+        state.room_buffers.retain(|_, (_, written_at)| now.duration_since(*written_at) < self.room_buffer_ttl);
+        let (buffer, written_at) = state.room_buffers.entry(message.room_id.clone())
+            .or_insert_with(|| (VecDeque::new(), now));
+        push_capped(buffer, [message.clone()], ROOM_BUFFER_MAX_LEN);
+        *written_at = now;
+        Ok(message)
+    }
+
+    async fn read_room_buffer(&self, room_id: &str, after_seq: u64) -> BusResult<Vec<UserMessage>> {
+        let state = self.state();
+        Ok(state.room_buffers.get(room_id)
+            .filter(|(_, written_at)| written_at.elapsed() < self.room_buffer_ttl)
+            .map(|(buffer, _)| buffer.iter().filter(|message| message.seq > after_seq).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn save_resume_states(&self, states: Vec<(String, ResumeState)>) -> BusResult<()> {
+        let expires_at = Instant::now() + self.resume_ttl;
+        let mut state = self.state();
+        state.resume_states.retain(|_, (_, expires)| *expires > Instant::now());
+        for (session_id, resume_state) in states {
+            state.resume_states.insert(session_id, (resume_state, expires_at));
+        }
+        Ok(())
+    }
+
+    async fn take_resume_state(&self, token: &str) -> BusResult<Option<ResumeState>> {
+        let entry = self.state().resume_states.remove(token);
+        Ok(entry
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(resume_state, _)| resume_state))
+    }
+
+    fn info(&self) -> HashMap<String, String> {
+        let state = self.state();
+        let mut info = HashMap::new();
+        info.insert("pod_id".to_string(), self.pod_id.clone());
+        info.insert("backend".to_string(), self.name().to_string());
+        info.insert("channels".to_string(), self.channels.lock().unwrap_or_else(|e| e.into_inner()).len().to_string());
+        info.insert("users_online".to_string(), state.sessions.len().to_string());
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room_message(room_id: &str, content: &str) -> UserMessage {
+        UserMessage {
+            message_id: content.to_string(),
+            seq: 0,
+            username: "alice".to_string(),
+            content: content.to_string(),
+            room_id: room_id.to_string(),
+            origin_session: None,
+        }
+    }
+
+    fn heartbeat(relay_id: u32) -> RedisMessageType {
+        RedisMessageType::RelayHeartbeat { relay_id, active_connections: 0 }
+    }
+
+    async fn next_message(rx: &mut mpsc::UnboundedReceiver<RedisMessage>) -> RedisMessage {
+        tokio::time::timeout(Duration::from_secs(1), rx.recv()).await
+            .expect("nenhuma mensagem recebida")
+            .expect("canal fechado")
+    }
+
+    #[tokio::test]
+    async fn subscribers_do_not_receive_their_own_publications() {
+        let bus = InMemoryBus::new("pod-a".to_string());
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        let _sub1 = bus.subscribe("room:general", 1, tx1);
+        let _sub2 = bus.subscribe("room:general", 2, tx2);
+
+        bus.publish("room:general", 1, heartbeat(1)).await.unwrap();
+        bus.publish("room:general", 2, heartbeat(2)).await.unwrap();
+
+        // A ordem do canal é preservada: se a do relay 1 não foi filtrada,
+        // ela chegaria antes da do relay 2
+        assert_eq!(next_message(&mut rx1).await.from_relay_id, 2);
+        assert_eq!(next_message(&mut rx2).await.from_relay_id, 1);
+        assert!(rx1.try_recv().is_err());
+        assert!(rx2.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn room_buffer_returns_messages_after_seq() {
+        let bus = InMemoryBus::new("pod-a".to_string());
+
+        let first = bus.buffer_room_message(room_message("general", "a")).await.unwrap();
+        bus.buffer_room_message(room_message("random", "b")).await.unwrap();
+        let third = bus.buffer_room_message(room_message("general", "c")).await.unwrap();
+        assert!(first.seq > 0 && third.seq > first.seq);
+
+        let all: Vec<String> = bus.read_room_buffer("general", 0).await.unwrap()
+            .into_iter().map(|message| message.content).collect();
+        assert_eq!(all, ["a", "c"]);
+
+        let after_first = bus.read_room_buffer("general", first.seq).await.unwrap();
+        assert_eq!(after_first.len(), 1);
+        assert_eq!(after_first[0].seq, third.seq);
+        assert!(bus.read_room_buffer("general", third.seq).await.unwrap().is_empty());
+        assert!(bus.read_room_buffer("empty", 0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn seq_is_counted_per_room_and_survives_buffer_expiry() {
+        let ttl = Duration::from_millis(50);
+        let bus = InMemoryBus::new("pod-a".to_string()).with_ttls(ttl, Duration::from_secs(60));
+
+        let general: Vec<u64> = [
+            bus.buffer_room_message(room_message("general", "a")).await.unwrap().seq,
+            bus.buffer_room_message(room_message("general", "b")).await.unwrap().seq,
+        ].into();
+        let random = bus.buffer_room_message(room_message("random", "c")).await.unwrap().seq;
+        assert_eq!(general, [1, 2]);
+        assert_eq!(random, 1);
+
+        // O buffer expira, mas a sala continua de onde parou
+        tokio::time::sleep(ttl * 2).await;
+        assert_eq!(bus.buffer_room_message(room_message("general", "d")).await.unwrap().seq, 3);
+    }
+
+    #[tokio::test]
+    async fn resume_state_is_taken_once_and_expires() {
+        let ttl = Duration::from_millis(50);
+        let bus = InMemoryBus::new("pod-a".to_string()).with_ttls(Duration::from_secs(60), ttl);
+        let state = || ResumeState { username: "alice".to_string(), rooms: vec!["general".to_string()] };
+
+        bus.save_resume_states(vec![("fresh".to_string(), state()), ("stale".to_string(), state())]).await.unwrap();
+        let taken = bus.take_resume_state("fresh").await.unwrap().expect("token recém-salvo");
+        assert_eq!(taken.username, "alice");
+        assert_eq!(taken.rooms, ["general"]);
+        // Cada token só vale uma vez
+        assert!(bus.take_resume_state("fresh").await.unwrap().is_none());
+
+        tokio::time::sleep(ttl * 2).await;
+        assert!(bus.take_resume_state("stale").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn room_buffer_expires_without_writes() {
+        let ttl = Duration::from_millis(50);
+        let bus = InMemoryBus::new("pod-a".to_string()).with_ttls(ttl, Duration::from_secs(60));
+
+        bus.buffer_room_message(room_message("general", "old")).await.unwrap();
+        tokio::time::sleep(ttl * 2).await;
+        assert!(bus.read_room_buffer("general", 0).await.unwrap().is_empty());
+
+        // A escrita em outra sala descarta o buffer expirado
+        bus.buffer_room_message(room_message("random", "new")).await.unwrap();
+        assert!(!bus.state().room_buffers.contains_key("general"));
+        assert_eq!(bus.read_room_buffer("random", 0).await.unwrap().len(), 1);
+    }
+}
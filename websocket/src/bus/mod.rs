@@ -0,0 +1,216 @@
+// src/bus/mod.rs
+//! Message bus entre relays: pub/sub dos canais (ver `channels`), presença
+//! dos usuários e o estado que precisa sobreviver a uma conexão (caixas de
+//! entrada, buffer das salas, resume tokens). O backend é escolhido na
+//! inicialização por `MESSAGE_BUS`.
+
+pub mod memory;
+#[cfg(feature = "nats")]
+pub mod nats;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use crate::actors::{DirectMessage, PendingDelivery, RedisMessage, RedisMessageType, ResumeState, UserMessage};
+use crate::redis_cluster::RedisClusterManager;
+
+/// Validade de uma sessão em `user_location`; renovada pelo heartbeat do relay
+pub const USER_LOCATION_TTL_SECS: u64 = 300;
+/// Mensagens diretas guardadas para usuários offline
+pub const OFFLINE_QUEUE_MAX_LEN: usize = 100;
+pub const OFFLINE_QUEUE_TTL_SECS: u64 = 7 * 24 * 3600;
+/// Entregas sem ack guardadas para retransmissão na reconexão
+pub const PENDING_DELIVERY_MAX_LEN: usize = 500;
+pub const PENDING_DELIVERY_TTL_SECS: u64 = 24 * 3600;
+/// Buffer por sala usado para reenviar o que um cliente perdeu entre a
+/// queda da conexão e a retomada
+pub const ROOM_BUFFER_MAX_LEN: usize = 1000;
+pub const ROOM_BUFFER_TTL_SECS: u64 = 600;
+/// Por quanto tempo um resume token continua válido sem ser renovado
+pub const RESUME_TTL_SECS: u64 = 300;
+
+#[derive(Debug)]
+pub struct BusError(String);
+
+impl BusError {
+    pub fn new(message: impl Into<String>) -> Self {
+        BusError(message.into())
+    }
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl From<redis::RedisError> for BusError {
+    fn from(e: redis::RedisError) -> Self {
+        BusError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for BusError {
+    fn from(e: serde_json::Error) -> Self {
+        BusError(format!("Serialização: {}", e))
+    }
+}
+
+pub type BusResult<T> = Result<T, BusError>;
+
+/// Assinatura ativa de um canal. A task de leitura é cancelada quando o
+/// valor é descartado.
+pub struct Subscription {
+    task: tokio::task::JoinHandle<()>,
+    channel: String,
+    /// Nó do backend que atendia o canal quando a assinatura foi feita
+    node: usize,
+    /// Limpeza extra de `close` (ex.: remover o consumer group do Redis Streams)
+    on_close: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Subscription {
+    pub fn new(channel: String, node: usize, task: tokio::task::JoinHandle<()>) -> Self {
+        Self { task, channel, node, on_close: None }
+    }
+
+    pub fn on_close(mut self, on_close: impl FnOnce() + Send + 'static) -> Self {
+        self.on_close = Some(Box::new(on_close));
+        self
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    pub fn node(&self) -> usize {
+        self.node
+    }
+
+    /// Encerra a assinatura de vez; descartar o valor sem `close` mantém o
+    /// estado do backend para uma reassinatura continuar de onde parou
+    pub fn close(mut self) {
+        if let Some(on_close) = self.on_close.take() {
+            on_close();
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+pub trait MessageBus: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn pod_id(&self) -> &str;
+
+    /// Publica em `channel`; os assinantes ignoram o que o próprio relay
+    /// (`from_relay_id` neste pod) publicou
+    async fn publish(&self, channel: &str, from_relay_id: u32, message_type: RedisMessageType) -> BusResult<()>;
+
+    /// Assina `channel` e encaminha as mensagens de outros relays para `tx`
+    /// enquanto o `Subscription` retornado existir
+    fn subscribe(&self, channel: &str, relay_id: u32, tx: mpsc::UnboundedSender<RedisMessage>) -> Subscription;
+
+    /// Muda quando canais podem ter trocado de nó no backend
+    fn topology_generation(&self) -> u64 {
+        0
+    }
+
+    /// Se o canal da assinatura passou para outro nó desde que ela foi feita
+    fn subscription_moved(&self, _subscription: &Subscription) -> bool {
+        false
+    }
+
+    async fn health_check(&self) -> bool;
+
+    /// Registra uma sessão e retorna quantas sessões vivas o usuário tem em
+    /// todo o cluster (incluindo esta)
+    async fn add_user_session(&self, username: &str, session_id: &str, relay_id: u32) -> BusResult<usize>;
+
+    /// Remove uma sessão e retorna quantas sessões vivas o usuário ainda tem
+    async fn remove_user_session(&self, username: &str, session_id: &str) -> BusResult<usize>;
+
+    /// Renova as sessões `(username, session_id)` de um relay
+    async fn refresh_user_sessions(&self, sessions: Vec<(String, String)>, relay_id: u32) -> BusResult<()>;
+
+    /// Passa as sessões de `username` para o relay `relay_id` deste pod numa
+    /// única escrita, sem momento em que parte delas aponte para o relay antigo
+    async fn move_user_sessions(&self, username: &str, session_ids: Vec<String>, relay_id: u32) -> BusResult<()>;
+
+    /// `(pod_id, relay_id)` distintos onde o usuário tem sessões vivas
+    async fn get_user_locations(&self, username: &str) -> BusResult<Vec<(String, u32)>>;
+
+    /// Guarda uma mensagem direta para entrega quando o destinatário se conectar
+    async fn push_offline_message(&self, message: &DirectMessage) -> BusResult<()>;
+
+    /// Remove e retorna as mensagens diretas pendentes de um usuário
+    async fn take_offline_messages(&self, username: &str) -> BusResult<Vec<DirectMessage>>;
+
+    /// Guarda entregas não confirmadas de uma sessão encerrada
+    async fn push_pending_deliveries(&self, username: &str, deliveries: &[PendingDelivery]) -> BusResult<()>;
+
+    /// Remove e retorna as entregas não confirmadas de um usuário, na ordem original
+    async fn take_pending_deliveries(&self, username: &str) -> BusResult<Vec<PendingDelivery>>;
+
+    /// Atribui à mensagem a próxima `seq` da sala e a guarda no buffer da
+    /// sala. Em todos os backends a `seq` é contada por sala e nunca volta
+    /// atrás; valores de salas diferentes não são comparáveis
+    async fn buffer_room_message(&self, message: UserMessage) -> BusResult<UserMessage>;
+
+    /// Mensagens ainda no buffer da sala com `seq` maior que `after_seq`
+    async fn read_room_buffer(&self, room_id: &str, after_seq: u64) -> BusResult<Vec<UserMessage>>;
+
+    /// Grava o estado de retomada de várias sessões (`session_id -> estado`)
+    async fn save_resume_states(&self, states: Vec<(String, ResumeState)>) -> BusResult<()>;
+
+    /// Lê e invalida um resume token; cada token só pode ser usado uma vez
+    async fn take_resume_state(&self, token: &str) -> BusResult<Option<ResumeState>>;
+
+    /// Estatísticas expostas em `/metrics`
+    fn info(&self) -> HashMap<String, String>;
+}
+
+pub fn pod_id_from_env() -> String {
+    std::env::var("POD_NAME")
+        .unwrap_or_else(|_| format!("pod-{}", std::process::id()))
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Conecta ao backend escolhido por `MESSAGE_BUS`: `redis` (padrão),
+/// `memory` (um único pod, sem dependências externas) ou `nats` (requer a
+/// feature `nats`)
+pub async fn from_env() -> BusResult<Arc<dyn MessageBus>> {
+    match std::env::var("MESSAGE_BUS").as_deref() {
+        Ok("memory") => Ok(Arc::new(memory::InMemoryBus::new(pod_id_from_env()))),
+        #[cfg(feature = "nats")]
+        Ok("nats") => Ok(Arc::new(nats::NatsBus::connect().await?)),
+        #[cfg(not(feature = "nats"))]
+        Ok("nats") => Err(BusError::new("backend NATS não compilado; recompile com a feature `nats`")),
+        Ok("redis") | Err(_) => connect_redis().await,
+        Ok(other) => {
+            eprintln!("MESSAGE_BUS desconhecido '{}', usando redis", other);
+            connect_redis().await
+        }
+    }
+}
+
+async fn connect_redis() -> BusResult<Arc<dyn MessageBus>> {
+    let redis_manager = RedisClusterManager::new().await?;
+    redis_manager.start_membership_monitor();
+    Ok(Arc::new(redis_manager))
+}
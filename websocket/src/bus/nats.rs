@@ -0,0 +1,391 @@
+// src/bus/nats.rs
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use async_nats::jetstream::{self, kv};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use crate::actors::{DirectMessage, PendingDelivery, RedisMessage, RedisMessageType, ResumeState, UserMessage};
+use super::{
+    pod_id_from_env, BusError, BusResult, MessageBus, Subscription, unix_now, OFFLINE_QUEUE_MAX_LEN,
+    OFFLINE_QUEUE_TTL_SECS, PENDING_DELIVERY_MAX_LEN, PENDING_DELIVERY_TTL_SECS, RESUME_TTL_SECS,
+    ROOM_BUFFER_MAX_LEN, ROOM_BUFFER_TTL_SECS, USER_LOCATION_TTL_SECS,
+};
+
+const SUBJECT_PREFIX: &str = "chat.";
+/// Tentativas de uma atualização compare-and-swap antes de desistir
+const CAS_ATTEMPTS: usize = 10;
+
+/// username -> session_id -> (pod_id, relay_id, visto em)
+type UserSessions = HashMap<String, (String, u32, u64)>;
+
+/// Message bus sobre NATS: os canais são subjects do Core NATS (at-most-once,
+/// como o Pub/Sub do Redis) e o estado fica em buckets do JetStream KV,
+/// alterados por compare-and-swap na revisão da chave.
+pub struct NatsBus {
+    pod_id: String,
+    client: async_nats::Client,
+    presence: kv::Store,
+    mailboxes: kv::Store,
+    rooms: kv::Store,
+    /// Contador de `seq` de cada sala; sem expiração, ao contrário dos buffers
+    sequences: kv::Store,
+    resume: kv::Store,
+    operations: AtomicU64,
+    errors: AtomicU64,
+}
+
+fn nats_error(context: &str, e: impl Display) -> BusError {
+    BusError::new(format!("NATS {}: {}", context, e))
+}
+
+/// Chaves do KV e subjects só aceitam `[-/_=.a-zA-Z0-9]`; nomes de usuário
+/// e de sala são codificados em hex
+fn encode_key(raw: &str) -> String {
+    raw.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn subject_for(channel: &str) -> String {
+    format!("{}{}", SUBJECT_PREFIX, encode_key(channel))
+}
+
+/// `max_age_secs` zero cria um bucket sem expiração
+async fn open_bucket(jetstream: &jetstream::Context, bucket: &str, max_age_secs: u64) -> BusResult<kv::Store> {
+    let config = kv::Config {
+        bucket: bucket.to_string(),
+        history: 1,
+        max_age: Duration::from_secs(max_age_secs),
+        ..Default::default()
+    };
+
+    match jetstream.create_key_value(config).await {
+        Ok(store) => Ok(store),
+        // Já existe com outra configuração (ex.: criado por outra versão)
+        Err(create_error) => jetstream.get_key_value(bucket).await
+            .map_err(|_| nats_error(&format!("bucket {}", bucket), create_error)),
+    }
+}
+
+impl NatsBus {
+    pub async fn connect() -> BusResult<Self> {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "nats://127.0.0.1:4222".to_string());
+        let client = async_nats::connect(&url).await
+            .map_err(|e| nats_error(&format!("conexão a {}", url), e))?;
+        let jetstream = jetstream::new(client.clone());
+
+        let presence = open_bucket(&jetstream, "chat_presence", USER_LOCATION_TTL_SECS).await?;
+        let mailboxes = open_bucket(&jetstream, "chat_mailboxes", OFFLINE_QUEUE_TTL_SECS.max(PENDING_DELIVERY_TTL_SECS)).await?;
+        let rooms = open_bucket(&jetstream, "chat_rooms", ROOM_BUFFER_TTL_SECS).await?;
+        let sequences = open_bucket(&jetstream, "chat_sequences", 0).await?;
+        let resume = open_bucket(&jetstream, "chat_resume", RESUME_TTL_SECS).await?;
+
+        let pod_id = pod_id_from_env();
+        println!("🎯 Message bus NATS inicializado:");
+        println!("  📦 Pod ID: {}", pod_id);
+        println!("  🔗 Servidor: {}", url);
+
+        Ok(Self {
+            pod_id,
+            client,
+            presence,
+            mailboxes,
+            rooms,
+            sequences,
+            resume,
+            operations: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        })
+    }
+
+    fn record<T>(&self, result: BusResult<T>) -> BusResult<T> {
+        self.operations.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Lê o valor de `key`, aplica `change` e grava condicionado à revisão
+    /// lida, repetindo se outro pod alterou a chave no meio. `change` recebe
+    /// o valor atual (ou o padrão) e retorna `false` para apagar a chave.
+    async fn update<T, R>(
+        &self,
+        store: &kv::Store,
+        key: &str,
+        mut change: impl FnMut(&mut T) -> (bool, R),
+    ) -> BusResult<R>
+    where
+        T: Serialize + DeserializeOwned + Default,
+    {
+        let mut last_error = None;
+
+        for _ in 0..CAS_ATTEMPTS {
+            let entry = store.entry(key).await.map_err(|e| nats_error("leitura", e))?;
+            let revision = entry.as_ref()
+                .filter(|entry| entry.operation == kv::Operation::Put)
+                .map(|entry| entry.revision);
+            let mut value: T = match (&entry, revision) {
+                (Some(entry), Some(_)) => serde_json::from_slice(&entry.value).unwrap_or_default(),
+                _ => T::default(),
+            };
+
+            let (keep, result) = change(&mut value);
+            let written = match (keep, revision) {
+                (true, Some(revision)) => store.update(key, serde_json::to_vec(&value)?.into(), revision).await
+                    .map(|_| ()).map_err(|e| e.to_string()),
+                (true, None) => store.create(key, serde_json::to_vec(&value)?.into()).await
+                    .map(|_| ()).map_err(|e| e.to_string()),
+                (false, Some(revision)) => store.purge_expect_revision(key, Some(revision)).await
+                    .map_err(|e| e.to_string()),
+                (false, None) => Ok(()),
+            };
+
+            match written {
+                Ok(()) => return Ok(result),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(nats_error(&format!("compare-and-swap de {}", key), last_error.unwrap_or_default()))
+    }
+
+    async fn read<T: DeserializeOwned>(&self, store: &kv::Store, key: &str) -> BusResult<Option<T>> {
+        let value = store.get(key).await.map_err(|e| nats_error("leitura", e))?;
+        Ok(value.and_then(|value| serde_json::from_slice(&value).ok()))
+    }
+
+    async fn take_mailbox<T>(&self, key: String) -> BusResult<Vec<T>>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let result = self.update(&self.mailboxes, &key, |queue: &mut Vec<T>| {
+            (false, std::mem::take(queue))
+        }).await;
+        self.record(result)
+    }
+
+    async fn push_mailbox<T>(&self, key: String, items: Vec<T>, max_len: usize) -> BusResult<()>
+    where
+        T: Serialize + DeserializeOwned + Clone,
+    {
+        let result = self.update(&self.mailboxes, &key, |queue: &mut Vec<T>| {
+            queue.extend(items.iter().cloned());
+            let excess = queue.len().saturating_sub(max_len);
+            queue.drain(..excess);
+            (true, ())
+        }).await;
+        self.record(result)
+    }
+}
+
+fn live_sessions(sessions: &UserSessions) -> usize {
+    let now = unix_now();
+    sessions.values()
+        .filter(|(_, _, seen_at)| now.saturating_sub(*seen_at) <= USER_LOCATION_TTL_SECS)
+        .count()
+}
+
+#[async_trait]
+impl MessageBus for NatsBus {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    fn pod_id(&self) -> &str {
+        &self.pod_id
+    }
+
+    async fn publish(&self, channel: &str, from_relay_id: u32, message_type: RedisMessageType) -> BusResult<()> {
+        let message = RedisMessage {
+            from_pod_id: self.pod_id.clone(),
+            from_relay_id,
+            message_type,
+            timestamp: unix_now(),
+        };
+        let payload = serde_json::to_vec(&message)?;
+
+        let result = self.client.publish(subject_for(channel), payload.into()).await
+            .map_err(|e| nats_error("publish", e));
+        self.record(result)
+    }
+
+    fn subscribe(&self, channel: &str, relay_id: u32, tx: mpsc::UnboundedSender<RedisMessage>) -> Subscription {
+        let client = self.client.clone();
+        let pod_id = self.pod_id.clone();
+        let name = channel.to_string();
+
+        // O cliente NATS reconecta e refaz as assinaturas sozinho
+        let task = tokio::spawn(async move {
+            let mut subscriber = match client.subscribe(subject_for(&name)).await {
+                Ok(subscriber) => subscriber,
+                Err(e) => {
+                    eprintln!("❌ Falha ao assinar {} no NATS: {}", name, e);
+                    return;
+                }
+            };
+
+            while let Some(message) = subscriber.next().await {
+                let Ok(message) = serde_json::from_slice::<RedisMessage>(&message.payload) else {
+                    continue;
+                };
+                let is_own = message.from_pod_id == pod_id && message.from_relay_id == relay_id;
+                if !is_own && tx.send(message).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Subscription::new(channel.to_string(), 0, task)
+    }
+
+    async fn health_check(&self) -> bool {
+        self.client.flush().await.is_ok()
+    }
+
+    async fn add_user_session(&self, username: &str, session_id: &str, relay_id: u32) -> BusResult<usize> {
+        let location = (self.pod_id.clone(), relay_id, unix_now());
+        let result = self.update(&self.presence, &encode_key(username), |sessions: &mut UserSessions| {
+            sessions.insert(session_id.to_string(), location.clone());
+            (true, live_sessions(sessions))
+        }).await;
+        self.record(result)
+    }
+
+    async fn remove_user_session(&self, username: &str, session_id: &str) -> BusResult<usize> {
+        let result = self.update(&self.presence, &encode_key(username), |sessions: &mut UserSessions| {
+            sessions.remove(session_id);
+            let live = live_sessions(sessions);
+            (live > 0, live)
+        }).await;
+        self.record(result)
+    }
+
+    async fn refresh_user_sessions(&self, sessions: Vec<(String, String)>, relay_id: u32) -> BusResult<()> {
+        let mut by_user: HashMap<String, Vec<String>> = HashMap::new();
+        for (username, session_id) in sessions {
+            by_user.entry(username).or_default().push(session_id);
+        }
+
+        let now = unix_now();
+        for (username, session_ids) in by_user {
+            let result = self.update(&self.presence, &encode_key(&username), |sessions: &mut UserSessions| {
+                for session_id in &session_ids {
+                    sessions.insert(session_id.clone(), (self.pod_id.clone(), relay_id, now));
+                }
+                // Aproveita para descartar sessões de pods que morreram
+                sessions.retain(|_, (_, _, seen_at)| now.saturating_sub(*seen_at) <= USER_LOCATION_TTL_SECS);
+                (true, ())
+            }).await;
+            self.record(result)?;
+        }
+        Ok(())
+    }
+
+    async fn move_user_sessions(&self, username: &str, session_ids: Vec<String>, relay_id: u32) -> BusResult<()> {
+        let location = (self.pod_id.clone(), relay_id, unix_now());
+        let result = self.update(&self.presence, &encode_key(username), |sessions: &mut UserSessions| {
+            for session_id in &session_ids {
+                sessions.insert(session_id.clone(), location.clone());
+            }
+            (true, ())
+        }).await;
+        self.record(result)
+    }
+
+    async fn get_user_locations(&self, username: &str) -> BusResult<Vec<(String, u32)>> {
+        let result = self.read::<UserSessions>(&self.presence, &encode_key(username)).await;
+        let sessions = self.record(result)?.unwrap_or_default();
+
+        let now = unix_now();
+        let mut locations: Vec<(String, u32)> = sessions.into_values()
+            .filter(|(_, _, seen_at)| now.saturating_sub(*seen_at) <= USER_LOCATION_TTL_SECS)
+            .map(|(pod_id, relay_id, _)| (pod_id, relay_id))
+            .collect();
+        locations.sort();
+        locations.dedup();
+        Ok(locations)
+    }
+
+    async fn push_offline_message(&self, message: &DirectMessage) -> BusResult<()> {
+        let key = format!("offline.{}", encode_key(&message.to));
+        self.push_mailbox(key, vec![message.clone()], OFFLINE_QUEUE_MAX_LEN).await
+    }
+
+    async fn take_offline_messages(&self, username: &str) -> BusResult<Vec<DirectMessage>> {
+        self.take_mailbox(format!("offline.{}", encode_key(username))).await
+    }
+
+    async fn push_pending_deliveries(&self, username: &str, deliveries: &[PendingDelivery]) -> BusResult<()> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        let key = format!("pending.{}", encode_key(username));
+        self.push_mailbox(key, deliveries.to_vec(), PENDING_DELIVERY_MAX_LEN).await
+    }
+
+    async fn take_pending_deliveries(&self, username: &str) -> BusResult<Vec<PendingDelivery>> {
+        self.take_mailbox(format!("pending.{}", encode_key(username))).await
+    }
+
+    async fn buffer_room_message(&self, mut message: UserMessage) -> BusResult<UserMessage> {
+        // Um contador por sala: sem uma chave global disputada por todos os pods
+        let seq = self.update(&self.sequences, &encode_key(&message.room_id), |seq: &mut u64| {
+            *seq += 1;
+            (true, *seq)
+        }).await;
+        message.seq = self.record(seq)?;
+
+        let result = self.update(&self.rooms, &encode_key(&message.room_id), |buffer: &mut Vec<UserMessage>| {
+            buffer.push(message.clone());
+            let excess = buffer.len().saturating_sub(ROOM_BUFFER_MAX_LEN);
+            buffer.drain(..excess);
+            (true, ())
+        }).await;
+        self.record(result)?;
+
+        Ok(message)
+    }
+
+    async fn read_room_buffer(&self, room_id: &str, after_seq: u64) -> BusResult<Vec<UserMessage>> {
+        let result = self.read::<Vec<UserMessage>>(&self.rooms, &encode_key(room_id)).await;
+        Ok(self.record(result)?
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|message| message.seq > after_seq)
+            .collect())
+    }
+
+    async fn save_resume_states(&self, states: Vec<(String, ResumeState)>) -> BusResult<()> {
+        for (session_id, resume_state) in states {
+            let payload = serde_json::to_vec(&resume_state)?;
+            let result = self.resume.put(encode_key(&session_id), payload.into()).await
+                .map(|_| ())
+                .map_err(|e| nats_error("gravação", e));
+            self.record(result)?;
+        }
+        Ok(())
+    }
+
+    async fn take_resume_state(&self, token: &str) -> BusResult<Option<ResumeState>> {
+        // Apagar com a revisão lida garante que só um pod usa o token
+        let result = self.update(&self.resume, &encode_key(token), |state: &mut Option<ResumeState>| {
+            (false, state.take())
+        }).await;
+        self.record(result)
+    }
+
+    fn info(&self) -> HashMap<String, String> {
+        let mut info = HashMap::new();
+        info.insert("pod_id".to_string(), self.pod_id.clone());
+        info.insert("backend".to_string(), self.name().to_string());
+        info.insert("connection_state".to_string(), self.client.connection_state().to_string());
+        info.insert("commands".to_string(), self.operations.load(Ordering::Relaxed).to_string());
+        info.insert("command_errors".to_string(), self.errors.load(Ordering::Relaxed).to_string());
+        info
+    }
+}
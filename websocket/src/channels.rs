@@ -0,0 +1,225 @@
+// src/channels.rs
+//! Topologia dos canais do message bus entre relays.
+//!
+//! | Canal                             | Quem publica                   | Quem assina                          | Conteúdo                                        |
+//! |-----------------------------------|--------------------------------|--------------------------------------|-------------------------------------------------|
+//! | `room_messages_{room_id}`         | o relay onde o evento nasceu   | relays com algum membro local na sala | `UserMessage`, `JoinRoom`, `LeaveRoom`          |
+//! | `relay_inbox_{pod_id}_{relay_id}` | quem entrega a um usuário cujas sessões estão nesse relay (via `user_location`) | só o relay dono | `DirectMessage`, `DeliveryReceipt` |
+//! | `relay_control`                   | todos os relays                | todos os relays                      | `JoinEvent`, saída de usuário, `RelayHeartbeat` |
+//! | `pod_metrics`                     | o ciclo de métricas de cada pod | o `LoadBalancer` de cada pod        | `PodMetrics`                                    |
+//!
+//! Garantias:
+//! - O nome do canal depende só do destino (sala, relay ou todos), nunca de
+//!   IDs coincidirem entre pods: toda publicação tem um assinante definido.
+//! - O relay entrega diretamente às suas sessões locais e ignora as próprias
+//!   publicações (mesmo pod e relay), então nada é entregue duas vezes.
+//! - A ordem é preservada por canal e por publicador; entre canais não há ordem.
+//! - Com `REDIS_TRANSPORT=pubsub` a entrega é at-most-once: o que for
+//!   publicado enquanto uma assinatura reconecta ou troca de nó se perde.
+//!   Com `streams`, o consumer group do relay continua de onde parou.
+//! - Uma sala só é recebida depois que a assinatura existe; o buffer da sala
+//!   cobre esse intervalo para clientes que retomam a sessão.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use crate::actors::RedisMessage;
+use crate::bus::{MessageBus, Subscription};
+
+const CONTROL_CHANNEL: &str = "relay_control";
+const POD_METRICS_CHANNEL: &str = "pod_metrics";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Room(String),
+    RelayInbox { pod_id: String, relay_id: u32 },
+    Control,
+    PodMetrics,
+}
+
+impl Channel {
+    pub fn room(room_id: &str) -> Self {
+        Channel::Room(room_id.to_string())
+    }
+
+    pub fn relay_inbox(pod_id: &str, relay_id: u32) -> Self {
+        Channel::RelayInbox { pod_id: pod_id.to_string(), relay_id }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Channel::Room(room_id) => format!("room_messages_{}", room_id),
+            Channel::RelayInbox { pod_id, relay_id } => format!("relay_inbox_{}_{}", pod_id, relay_id),
+            Channel::Control => CONTROL_CHANNEL.to_string(),
+            Channel::PodMetrics => POD_METRICS_CHANNEL.to_string(),
+        }
+    }
+}
+
+/// Todas as assinaturas de um relay no bus: controle e inbox sempre, salas
+/// enquanto houver membros locais. As mensagens recebidas vão para o mesmo
+/// `sender`.
+pub struct ChannelSubscriptions {
+    bus: Arc<dyn MessageBus>,
+    relay_id: u32,
+    sender: mpsc::UnboundedSender<RedisMessage>,
+    subscriptions: HashMap<Channel, Subscription>,
+    /// Versão da topologia do bus com que as assinaturas foram feitas
+    topology_generation: u64,
+}
+
+impl ChannelSubscriptions {
+    pub fn new(
+        bus: Arc<dyn MessageBus>,
+        relay_id: u32,
+        sender: mpsc::UnboundedSender<RedisMessage>,
+    ) -> Self {
+        let topology_generation = bus.topology_generation();
+
+        Self {
+            bus,
+            relay_id,
+            sender,
+            subscriptions: HashMap::new(),
+            topology_generation,
+        }
+    }
+
+    /// Canais que todo relay assina enquanto existir
+    fn required_channels(&self) -> [Channel; 2] {
+        [
+            Channel::Control,
+            Channel::relay_inbox(self.bus.pod_id(), self.relay_id),
+        ]
+    }
+
+    /// Assina os canais obrigatórios e refaz as assinaturas existentes; as
+    /// antigas são canceladas ao serem substituídas
+    pub fn subscribe_all(&mut self) {
+        let mut channels: Vec<Channel> = self.subscriptions.keys().cloned().collect();
+        for channel in self.required_channels() {
+            if !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        }
+
+        for channel in channels {
+            self.subscribe(channel);
+        }
+    }
+
+    pub fn subscribe(&mut self, channel: Channel) {
+        let subscription = self.bus.subscribe(
+            &channel.name(),
+            self.relay_id,
+            self.sender.clone(),
+        );
+        self.subscriptions.insert(channel, subscription);
+    }
+
+    pub fn is_subscribed(&self, channel: &Channel) -> bool {
+        self.subscriptions.contains_key(channel)
+    }
+
+    /// Encerra a assinatura de vez (ver `Subscription::close`)
+    pub fn unsubscribe(&mut self, channel: &Channel) {
+        if let Some(subscription) = self.subscriptions.remove(channel) {
+            subscription.close();
+        }
+    }
+
+    /// Encerra todas as assinaturas, inclusive as obrigatórias
+    pub fn close_all(&mut self) {
+        for (_, subscription) in self.subscriptions.drain() {
+            subscription.close();
+        }
+    }
+
+    /// Se a topologia do bus mudou, reassina só os canais que mudaram de nó e
+    /// retorna quantos foram
+    pub fn resubscribe_moved(&mut self) -> Option<usize> {
+        let generation = self.bus.topology_generation();
+        if generation == self.topology_generation {
+            return None;
+        }
+        self.topology_generation = generation;
+
+        let moved: Vec<Channel> = self.subscriptions.iter()
+            .filter(|(_, subscription)| self.bus.subscription_moved(subscription))
+            .map(|(channel, _)| channel.clone())
+            .collect();
+        let count = moved.len();
+        for channel in moved {
+            self.subscribe(channel);
+        }
+
+        Some(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::actors::RedisMessageType;
+    use crate::bus::memory::InMemoryBus;
+
+    fn heartbeat(relay_id: u32) -> RedisMessageType {
+        RedisMessageType::RelayHeartbeat { relay_id, active_connections: 0 }
+    }
+
+    /// Relays de origem do que chegou, ordenados: entre canais não há ordem
+    async fn received(rx: &mut mpsc::UnboundedReceiver<RedisMessage>) -> Vec<u32> {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut from: Vec<u32> = std::iter::from_fn(|| rx.try_recv().ok()).map(|message| message.from_relay_id).collect();
+        from.sort();
+        from
+    }
+
+    #[test]
+    fn channel_names_depend_only_on_the_destination() {
+        assert_eq!(Channel::room("general").name(), "room_messages_general");
+        assert_eq!(Channel::relay_inbox("pod-a", 1).name(), "relay_inbox_pod-a_1");
+        assert_eq!(Channel::Control.name(), "relay_control");
+        assert_eq!(Channel::PodMetrics.name(), "pod_metrics");
+        // O mesmo relay_id em pods diferentes são inboxes diferentes
+        assert_ne!(Channel::relay_inbox("pod-a", 1).name(), Channel::relay_inbox("pod-b", 1).name());
+    }
+
+    #[tokio::test]
+    async fn inbox_reaches_only_its_relay_and_control_reaches_all() {
+        let bus: Arc<dyn MessageBus> = Arc::new(InMemoryBus::new("pod-a".to_string()));
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        let mut relay1 = ChannelSubscriptions::new(bus.clone(), 1, tx1);
+        let mut relay2 = ChannelSubscriptions::new(bus.clone(), 2, tx2);
+        relay1.subscribe_all();
+        relay2.subscribe_all();
+        assert!(relay1.is_subscribed(&Channel::Control));
+        assert!(relay1.is_subscribed(&Channel::relay_inbox("pod-a", 1)));
+        assert!(!relay1.is_subscribed(&Channel::relay_inbox("pod-a", 2)));
+
+        bus.publish(&Channel::relay_inbox("pod-a", 2).name(), 3, heartbeat(3)).await.unwrap();
+        bus.publish(&Channel::Control.name(), 4, heartbeat(4)).await.unwrap();
+
+        assert_eq!(received(&mut rx1).await, [4]);
+        assert_eq!(received(&mut rx2).await, [3, 4]);
+    }
+
+    #[tokio::test]
+    async fn room_is_received_only_while_subscribed() {
+        let bus: Arc<dyn MessageBus> = Arc::new(InMemoryBus::new("pod-a".to_string()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut subscriptions = ChannelSubscriptions::new(bus.clone(), 1, tx);
+        let room = Channel::room("dev");
+
+        subscriptions.subscribe(room.clone());
+        bus.publish(&room.name(), 2, heartbeat(2)).await.unwrap();
+        assert_eq!(received(&mut rx).await, [2]);
+
+        subscriptions.unsubscribe(&room);
+        assert!(!subscriptions.is_subscribed(&room));
+        bus.publish(&room.name(), 2, heartbeat(2)).await.unwrap();
+        assert!(received(&mut rx).await.is_empty());
+    }
+}
@@ -1,9 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, RwLock};
 use actix::Addr;
 use serde::Serialize;
+use crate::actors::{GetLocalUsers, MigrateUser};
 use crate::actors::relay::RelayActor;
+use crate::actors::persistence::env_or;
+use crate::hash_ring::HashRing;
+use crate::balancing::{BalancingStrategy, Candidate, ScoreWeights};
+use crate::bus::MessageBus;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct RelayMetrics {
@@ -14,29 +21,227 @@ pub struct RelayMetrics {
     pub last_updated: u64,
 }
 
+/// Nós virtuais por relay no anel de afinidade
+const AFFINITY_RING_VNODES: usize = 100;
+
+/// Como o relay de um usuário sem sessões é escolhido
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayAffinity {
+    /// Pela estratégia de balanceamento, lembrando a escolha por usuário
+    Mapping,
+    /// Por hash consistente da chave de afinidade (o username, ou a chave
+    /// enviada pelo cliente), sem estado: quem reconecta cai no mesmo relay.
+    /// Um relay com mais de `load_factor` vezes a carga média passa o
+    /// usuário ao próximo do anel.
+    ConsistentHash { load_factor: f64 },
+}
+
+impl RelayAffinity {
+    pub fn from_env() -> Self {
+        match std::env::var("RELAY_AFFINITY").as_deref() {
+            Ok("consistent_hash") => RelayAffinity::ConsistentHash {
+                load_factor: env_or("RELAY_AFFINITY_LOAD_FACTOR", 1.25_f64).max(1.0),
+            },
+            Ok("mapping") | Err(_) => RelayAffinity::Mapping,
+            Ok(other) => {
+                eprintln!("RELAY_AFFINITY desconhecida '{}', usando mapping", other);
+                RelayAffinity::Mapping
+            }
+        }
+    }
+}
+
+/// Limites do mapeamento usuário → relay
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MappingConfig {
+    /// Quanto tempo uma entrada sobrevive sem o usuário ser visto conectado
+    #[serde(serialize_with = "serialize_secs")]
+    pub ttl: Duration,
+    /// Acima disso, a entrada vista há mais tempo sai para dar lugar à nova
+    pub max_entries: usize,
+}
+
+fn serialize_secs<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+impl MappingConfig {
+    pub fn from_env() -> Self {
+        Self {
+            ttl: Duration::from_secs(env_or("RELAY_MAPPING_TTL_SECS", 600)),
+            max_entries: env_or("RELAY_MAPPING_MAX_ENTRIES", 100_000).max(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct UserAssignment {
+    relay_id: u32,
+    /// Última vez que o usuário escolheu relay ou foi visto conectado nele
+    last_seen: Instant,
+}
+
+/// Mapeamento usuário → relay com um índice por `last_seen`, para achar a
+/// entrada vista há mais tempo sem percorrer o mapa inteiro
+#[derive(Debug, Default)]
+struct UserMapping {
+    assignments: HashMap<String, UserAssignment>,
+    by_last_seen: BTreeSet<(Instant, String)>,
+}
+
+impl UserMapping {
+    fn len(&self) -> usize {
+        self.assignments.len()
+    }
+
+    fn contains_key(&self, username: &str) -> bool {
+        self.assignments.contains_key(username)
+    }
+
+    fn get(&self, username: &str) -> Option<&UserAssignment> {
+        self.assignments.get(username)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, &UserAssignment)> {
+        self.assignments.iter()
+    }
+
+    fn insert(&mut self, username: String, relay_id: u32, now: Instant) {
+        self.remove(&username);
+        self.by_last_seen.insert((now, username.clone()));
+        self.assignments.insert(username, UserAssignment { relay_id, last_seen: now });
+    }
+
+    /// Marca o usuário como visto em `now`
+    fn touch(&mut self, username: &str, now: Instant) {
+        if let Some(assignment) = self.assignments.get(username) {
+            self.insert(username.to_string(), assignment.relay_id, now);
+        }
+    }
+
+    fn remove(&mut self, username: &str) -> Option<UserAssignment> {
+        let assignment = self.assignments.remove(username)?;
+        self.by_last_seen.remove(&(assignment.last_seen, username.to_string()));
+        Some(assignment)
+    }
+
+    /// Remove a entrada vista há mais tempo
+    fn pop_oldest(&mut self) -> Option<String> {
+        let (_, username) = self.by_last_seen.pop_first()?;
+        self.assignments.remove(&username);
+        Some(username)
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&str, &UserAssignment) -> bool) {
+        let removed: Vec<String> = self.assignments.iter()
+            .filter(|(username, assignment)| !keep(username, assignment))
+            .map(|(username, _)| username.clone())
+            .collect();
+        for username in removed {
+            self.remove(&username);
+        }
+    }
+}
+
+/// Usuário que encerrou a última sessão que tinha num relay
+#[derive(Debug)]
+pub struct UserDeparture {
+    pub username: String,
+    pub relay_id: u32,
+    pub at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct MappingCounters {
+    released: AtomicU64,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MappingStats {
+    pub entries: usize,
+    #[serde(flatten)]
+    pub config: MappingConfig,
+    /// Removidas quando a última sessão do usuário terminou
+    pub released: u64,
+    /// Removidas por `ttl`
+    pub expired: u64,
+    /// Removidas por `max_entries`
+    pub evicted: u64,
+}
+
 #[derive(Clone)]
 pub struct DynamicRelayBalancer {
     relays: Arc<RwLock<HashMap<u32, Addr<RelayActor>>>>,
+    /// Relays por posição da chave de afinidade, usado com `RelayAffinity::ConsistentHash`
+    ring: Arc<RwLock<HashRing>>,
     metrics: Arc<RwLock<HashMap<u32, RelayMetrics>>>,
-    user_relay_mapping: Arc<RwLock<HashMap<String, u32>>>,
+    user_relay_mapping: Arc<RwLock<UserMapping>>,
+    /// Relays sendo drenados: não recebem usuários novos nem migrados
+    draining: Arc<RwLock<HashSet<u32>>>,
+    /// Relays cujo ator morreu, fora da seleção até a nova instância responder
+    unhealthy: Arc<RwLock<HashSet<u32>>>,
     max_connections_per_relay: usize,
+    /// Escolhe o relay de usuários novos e migrados
+    strategy: Arc<dyn BalancingStrategy>,
+    score_weights: ScoreWeights,
+    affinity: RelayAffinity,
+    mapping_config: MappingConfig,
+    mapping_counters: Arc<MappingCounters>,
+    /// Os relays avisam aqui quando um usuário sai; consumido por `start_mapping_cleanup`
+    departures: mpsc::UnboundedSender<UserDeparture>,
+    departures_receiver: Arc<std::sync::Mutex<Option<mpsc::UnboundedReceiver<UserDeparture>>>>,
 }
 
 impl DynamicRelayBalancer {
-    pub fn new(max_connections_per_relay: usize) -> Self {
+    pub fn new(
+        max_connections_per_relay: usize,
+        strategy: Arc<dyn BalancingStrategy>,
+        score_weights: ScoreWeights,
+        affinity: RelayAffinity,
+        mapping_config: MappingConfig,
+    ) -> Self {
+        let (departures, departures_receiver) = mpsc::unbounded_channel();
+
         Self {
             relays: Arc::new(RwLock::new(HashMap::new())),
+            ring: Arc::new(RwLock::new(HashRing::new(AFFINITY_RING_VNODES))),
             metrics: Arc::new(RwLock::new(HashMap::new())),
-            user_relay_mapping: Arc::new(RwLock::new(HashMap::new())),
+            user_relay_mapping: Arc::new(RwLock::new(UserMapping::default())),
+            draining: Arc::new(RwLock::new(HashSet::new())),
+            unhealthy: Arc::new(RwLock::new(HashSet::new())),
             max_connections_per_relay,
+            strategy,
+            score_weights,
+            affinity,
+            mapping_config,
+            mapping_counters: Arc::new(MappingCounters::default()),
+            departures,
+            departures_receiver: Arc::new(std::sync::Mutex::new(Some(departures_receiver))),
         }
     }
 
+    /// Canal para os relays avisarem que um usuário saiu
+    pub fn departures(&self) -> mpsc::UnboundedSender<UserDeparture> {
+        self.departures.clone()
+    }
+
+    pub fn affinity(&self) -> RelayAffinity {
+        self.affinity
+    }
+
+    pub fn strategy_name(&self) -> &'static str {
+        self.strategy.name()
+    }
+
     pub async fn add_relay(&self, relay_id: u32, relay_addr: Addr<RelayActor>) {
         let mut relays = self.relays.write().await;
+        let mut ring = self.ring.write().await;
         let mut metrics = self.metrics.write().await;
 
         relays.insert(relay_id, relay_addr);
+        ring.add(relay_id as usize, &format!("relay-{}", relay_id));
         metrics.insert(relay_id, RelayMetrics {
             relay_id,
             active_connections: 0,
@@ -49,6 +254,62 @@ impl DynamicRelayBalancer {
         });
     }
 
+    /// Tira o relay do balanceamento; as sessões dele devem ter sido drenadas antes
+    pub async fn remove_relay(&self, relay_id: u32) -> Option<Addr<RelayActor>> {
+        let mut relays = self.relays.write().await;
+        let mut ring = self.ring.write().await;
+        let mut mapping = self.user_relay_mapping.write().await;
+        let mut metrics = self.metrics.write().await;
+
+        ring.remove(relay_id as usize);
+        metrics.remove(&relay_id);
+        mapping.retain(|_, assignment| assignment.relay_id != relay_id);
+        self.draining.write().await.remove(&relay_id);
+        self.unhealthy.write().await.remove(&relay_id);
+        relays.remove(&relay_id)
+    }
+
+    /// Troca o endereço de um relay reiniciado; ele volta à seleção com `mark_healthy`
+    pub async fn replace_relay(&self, relay_id: u32, relay_addr: Addr<RelayActor>) {
+        self.unhealthy.write().await.insert(relay_id);
+        self.relays.write().await.insert(relay_id, relay_addr);
+    }
+
+    pub async fn mark_unhealthy(&self, relay_id: u32) {
+        self.unhealthy.write().await.insert(relay_id);
+    }
+
+    pub async fn mark_healthy(&self, relay_id: u32) -> bool {
+        self.unhealthy.write().await.remove(&relay_id)
+    }
+
+    pub async fn unhealthy_relays(&self) -> HashSet<u32> {
+        self.unhealthy.read().await.clone()
+    }
+
+    pub async fn relay_addrs(&self) -> HashMap<u32, Addr<RelayActor>> {
+        self.relays.read().await.clone()
+    }
+
+    /// Relays drenando ou sem ator vivo, que não recebem usuários
+    async fn unselectable(&self) -> HashSet<u32> {
+        let draining = self.draining.read().await;
+        let unhealthy = self.unhealthy.read().await;
+        draining.union(&unhealthy).copied().collect()
+    }
+
+    pub fn max_connections_per_relay(&self) -> usize {
+        self.max_connections_per_relay
+    }
+
+    pub async fn relay_count(&self) -> usize {
+        self.relays.read().await.len()
+    }
+
+    pub async fn draining_relays(&self) -> HashSet<u32> {
+        self.draining.read().await.clone()
+    }
+
     pub async fn update_relay_metrics(&self, relay_id: u32, connections: usize, throughput: f64, response_time: f64) {
         let mut metrics = self.metrics.write().await;
 
@@ -63,55 +324,143 @@ impl DynamicRelayBalancer {
         }
     }
 
-    pub async fn get_best_relay_for_user(&self, username: &str) -> Option<u32> {
+    /// Se o relay pode receber mais uma sessão
+    async fn accepts_sessions(&self, relay_id: u32) -> bool {
+        let metrics = self.metrics.read().await;
+        let unselectable = self.unselectable().await;
+        !unselectable.contains(&relay_id) && metrics.get(&relay_id)
+            .is_some_and(|metric| metric.active_connections < self.max_connections_per_relay)
+    }
+
+    /// Relay das sessões de `username`: o das sessões que ele já tem, ou um
+    /// novo escolhido conforme a afinidade. `affinity_key` substitui o
+    /// username no hash consistente, para agrupar usuários (ex.: de uma sala).
+    /// Com hash consistente não há mapeamento: as sessões que o usuário já
+    /// tem neste pod são encontradas pelo `user_location` do bus.
+    pub async fn get_best_relay_for_user(
+        &self,
+        username: &str,
+        affinity_key: Option<&str>,
+        bus: Option<&dyn MessageBus>,
+    ) -> Option<u32> {
+        if let RelayAffinity::ConsistentHash { .. } = self.affinity {
+            if let Some(relay_id) = self.relay_with_sessions_of(username, bus).await {
+                return Some(relay_id);
+            }
+            return self.select_relay(username, affinity_key).await;
+        }
+
         {
-            let mapping = self.user_relay_mapping.read().await;
-            if let Some(&existing_relay_id) = mapping.get(username) {
-                let metrics = self.metrics.read().await;
-                if let Some(metric) = metrics.get(&existing_relay_id) {
-                    if metric.active_connections < self.max_connections_per_relay {
-                        return Some(existing_relay_id);
-                    }
-                }
+            let mut mapping = self.user_relay_mapping.write().await;
+            if let Some(relay_id) = mapping.get(username).map(|assignment| assignment.relay_id)
+                && self.accepts_sessions(relay_id).await
+            {
+                mapping.touch(username, Instant::now());
+                return Some(relay_id);
             }
         }
         
-        let best_relay_id = self.select_optimal_relay().await?;
+        let best_relay_id = self.select_relay(username, affinity_key).await?;
         
         let mut mapping = self.user_relay_mapping.write().await;
-        mapping.insert(username.to_string(), best_relay_id);
+        if mapping.len() >= self.mapping_config.max_entries && !mapping.contains_key(username) {
+            self.evict_least_recently_seen(&mut mapping);
+        }
+        mapping.insert(username.to_string(), best_relay_id, Instant::now());
 
         Some(best_relay_id)
     }
 
-    async fn select_optimal_relay(&self) -> Option<u32> {
+    /// Relay deste pod onde o usuário já tem sessões vivas, se ainda aceita outra
+    async fn relay_with_sessions_of(&self, username: &str, bus: Option<&dyn MessageBus>) -> Option<u32> {
+        let bus = bus?;
+        let locations = bus.get_user_locations(username).await
+            .inspect_err(|e| eprintln!("Falha ao buscar sessões de {}: {}", username, e))
+            .ok()?;
+
+        for (pod_id, relay_id) in locations {
+            if pod_id == bus.pod_id() && self.accepts_sessions(relay_id).await {
+                return Some(relay_id);
+            }
+        }
+        None
+    }
+
+    fn evict_least_recently_seen(&self, mapping: &mut UserMapping) {
+        if mapping.pop_oldest().is_some() {
+            self.mapping_counters.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn select_relay(&self, username: &str, affinity_key: Option<&str>) -> Option<u32> {
+        match self.affinity {
+            RelayAffinity::Mapping => self.select_optimal_relay(username).await,
+            RelayAffinity::ConsistentHash { load_factor } => {
+                let relay_id = self.select_by_hash(affinity_key.unwrap_or(username), load_factor).await?;
+                // Até a próxima sincronização, para o limite de carga valer
+                // também numa rajada de conexões
+                if let Some(metric) = self.metrics.write().await.get_mut(&relay_id) {
+                    metric.active_connections += 1;
+                }
+                Some(relay_id)
+            }
+        }
+    }
+
+    /// Primeiro relay do anel, a partir de `key`, abaixo do limite de carga
+    /// (consistent hashing with bounded loads)
+    async fn select_by_hash(&self, key: &str, load_factor: f64) -> Option<u32> {
+        let ring = self.ring.read().await;
         let metrics = self.metrics.read().await;
+        let unselectable = self.unselectable().await;
 
-        if metrics.is_empty() {
+        let selectable: Vec<&RelayMetrics> = metrics.values()
+            .filter(|metric| !unselectable.contains(&metric.relay_id))
+            .collect();
+        if selectable.is_empty() {
             return None;
         }
-        
-        let mut best_relay_id = None;
-        let mut best_score = f64::NEG_INFINITY;
 
-        for (relay_id, metric) in metrics.iter() {
-            let capacity_factor = 1.0 - (metric.active_connections as f64 / self.max_connections_per_relay as f64);
-            let throughput_factor = 1.0 / (1.0 + metric.message_throughput / 1000.0);
-            let response_time_factor = 1.0 / (1.0 + metric.avg_response_time / 100.0);
-            
-            if metric.active_connections >= self.max_connections_per_relay {
+        let total_connections: usize = selectable.iter().map(|metric| metric.active_connections).sum();
+        let average = (total_connections + 1) as f64 / selectable.len() as f64;
+        let bound = ((average * load_factor).ceil() as usize).min(self.max_connections_per_relay);
+
+        ring.preference_list(key).into_iter()
+            .map(|node| node as u32)
+            .find(|relay_id| {
+                !unselectable.contains(relay_id) && metrics.get(relay_id)
+                    .is_some_and(|metric| metric.active_connections < bound)
+            })
+    }
+
+    async fn select_optimal_relay(&self, username: &str) -> Option<u32> {
+        let metrics = self.metrics.read().await;
+        let unselectable = self.unselectable().await;
+
+        let mut ordered: Vec<(&u32, &RelayMetrics)> = metrics.iter().collect();
+        ordered.sort_by_key(|(relay_id, _)| **relay_id);
+
+        let mut relay_ids = Vec::new();
+        let mut candidates = Vec::new();
+        for (relay_id, metric) in ordered {
+            if metric.active_connections >= self.max_connections_per_relay || unselectable.contains(relay_id) {
                 continue;
             }
 
-            let score = (capacity_factor * 0.5) + (throughput_factor * 0.3) + (response_time_factor * 0.2);
+            let capacity_factor = 1.0 - (metric.active_connections as f64 / self.max_connections_per_relay as f64);
+            let throughput_factor = 1.0 / (1.0 + metric.message_throughput / 1000.0);
+            let response_time_factor = 1.0 / (1.0 + metric.avg_response_time / 100.0);
 
-            if score > best_score {
-                best_score = score;
-                best_relay_id = Some(*relay_id);
-            }
+            relay_ids.push(*relay_id);
+            candidates.push(Candidate {
+                id: relay_id.to_string(),
+                active_connections: metric.active_connections,
+                score: self.score_weights.score(capacity_factor, throughput_factor, response_time_factor),
+            });
         }
 
-        best_relay_id
+        let index = self.strategy.select(&candidates, Some(username))?;
+        relay_ids.get(index).copied()
     }
 
     pub async fn get_relay_addr(&self, relay_id: u32) -> Option<Addr<RelayActor>> {
@@ -123,45 +472,227 @@ impl DynamicRelayBalancer {
         let mut mapping = self.user_relay_mapping.write().await;
         mapping.remove(username);
     }
+
+    /// Tira o usuário do mapeamento quando a última sessão dele no relay
+    /// termina, a não ser que ele já tenha voltado a escolher relay depois
+    /// disso ou esteja mapeado para outro (migrado)
+    pub async fn release_user(&self, departure: UserDeparture) -> bool {
+        let mut mapping = self.user_relay_mapping.write().await;
+        let released = mapping.get(&departure.username).is_some_and(|assignment| {
+            assignment.relay_id == departure.relay_id && assignment.last_seen <= departure.at
+        });
+        if released {
+            mapping.remove(&departure.username);
+            self.mapping_counters.released.fetch_add(1, Ordering::Relaxed);
+        }
+        released
+    }
+
+    /// Consome as saídas avisadas pelos relays; só a primeira chamada inicia
+    pub fn start_mapping_cleanup(&self) {
+        let Some(mut receiver) = self.departures_receiver.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return;
+        };
+
+        let balancer = self.clone();
+        tokio::spawn(async move {
+            while let Some(departure) = receiver.recv().await {
+                balancer.release_user(departure).await;
+            }
+        });
+    }
+
+    /// Renova quem está conectado e expira quem não foi visto por `ttl`
+    /// (saídas que não chegaram, como as de um relay que morreu). Retorna
+    /// quantas entradas expiraram.
+    pub async fn expire_mapping(&self) -> usize {
+        let local_users = self.local_users().await;
+        let now = Instant::now();
+
+        let mut mapping = self.user_relay_mapping.write().await;
+        let before = mapping.len();
+        // Relay que não respondeu: na dúvida, conta como conectado
+        let connected: HashSet<String> = mapping.iter()
+            .filter(|(username, assignment)| {
+                local_users.get(&assignment.relay_id).is_none_or(|users| users.contains(*username))
+            })
+            .map(|(username, _)| username.clone())
+            .collect();
+        for username in &connected {
+            mapping.touch(username, now);
+        }
+        mapping.retain(|username, assignment| {
+            connected.contains(username) || now.duration_since(assignment.last_seen) < self.mapping_config.ttl
+        });
+
+        let expired = before - mapping.len();
+        self.mapping_counters.expired.fetch_add(expired as u64, Ordering::Relaxed);
+        expired
+    }
+
+    pub async fn mapping_stats(&self) -> MappingStats {
+        MappingStats {
+            entries: self.user_relay_mapping.read().await.len(),
+            config: self.mapping_config,
+            released: self.mapping_counters.released.load(Ordering::Relaxed),
+            expired: self.mapping_counters.expired.load(Ordering::Relaxed),
+            evicted: self.mapping_counters.evicted.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Usuários com sessões em cada relay que respondeu
+    async fn local_users(&self) -> HashMap<u32, HashSet<String>> {
+        let mut local_users = HashMap::new();
+        for (relay_id, relay_addr) in self.relay_addrs().await {
+            if let Ok(users) = relay_addr.send(GetLocalUsers).await {
+                local_users.insert(relay_id, users.into_iter().collect());
+            }
+        }
+        local_users
+    }
     
     pub async fn rebalance_if_needed(&self) -> Vec<(String, u32, u32)> {
-        let metrics = self.metrics.read().await;
-        let mapping = self.user_relay_mapping.read().await;
+        // O limite de carga do hash já equilibra; migrar tiraria os usuários
+        // do relay para onde voltariam ao reconectar
+        if matches!(self.affinity, RelayAffinity::ConsistentHash { .. }) {
+            return vec![];
+        }
+
+        let (max_relay, min_relay, to_move) = {
+            let all_metrics = self.metrics.read().await;
+            let unselectable = self.unselectable().await;
+            let metrics: HashMap<u32, &RelayMetrics> = all_metrics.iter()
+                .filter(|(relay_id, _)| !unselectable.contains(relay_id))
+                .map(|(relay_id, metric)| (*relay_id, metric))
+                .collect();
+
+            if metrics.len() < 2 {
+                return vec![];
+            }
+
+            let max_load_relay = metrics.iter()
+                .max_by_key(|(_, m)| m.active_connections)
+                .map(|(id, _)| *id);
+
+            let min_load_relay = metrics.iter()
+                .min_by_key(|(_, m)| m.active_connections)
+                .map(|(id, _)| *id);
+
+            let (Some(max_relay), Some(min_relay)) = (max_load_relay, min_load_relay) else {
+                return vec![];
+            };
+            if max_relay == min_relay {
+                return vec![];
+            }
+
+            let max_connections = metrics.get(&max_relay).unwrap().active_connections;
+            let min_connections = metrics.get(&min_relay).unwrap().active_connections;
+
+            if max_connections <= min_connections + (self.max_connections_per_relay / 3) {
+                return vec![];
+            }
+            (max_relay, min_relay, (max_connections - min_connections) / 2)
+        };
 
-        if metrics.len() < 2 {
+        // Só quem está conectado agora: entradas de quem saiu não aliviam nada
+        let Some(relay_addr) = self.get_relay_addr(max_relay).await else {
             return vec![];
+        };
+        let Ok(connected) = relay_addr.send(GetLocalUsers).await else {
+            return vec![];
+        };
+
+        let mapping = self.user_relay_mapping.read().await;
+        connected.into_iter()
+            .filter(|username| mapping.get(username).is_some_and(|assignment| assignment.relay_id == max_relay))
+            .take(to_move)
+            .map(|username| (username, max_relay, min_relay))
+            .collect()
+    }
+
+    /// Migra cada usuário de `from` para `to` sem derrubar as conexões e
+    /// retorna quantos foram movidos. O mapeamento fica travado durante a
+    /// migração de cada usuário, então uma nova sessão dele só escolhe relay
+    /// depois que sessões e `user_location` já apontam para o destino.
+    pub async fn execute_rebalances(&self, rebalances: Vec<(String, u32, u32)>) -> usize {
+        let mut migrated = 0;
+
+        for (username, from, to) in rebalances {
+            let (Some(from_addr), Some(to_addr)) = (self.get_relay_addr(from).await, self.get_relay_addr(to).await) else {
+                continue;
+            };
+
+            let mut mapping = self.user_relay_mapping.write().await;
+            if mapping.get(&username).map(|assignment| assignment.relay_id) != Some(from) {
+                continue;
+            }
+
+            if Self::migrate_user(&mut mapping, username, (from, &from_addr), (to, to_addr)).await {
+                migrated += 1;
+            }
         }
 
-        let mut rebalances = vec![];
-        
-        let max_load_relay = metrics.iter()
-            .max_by_key(|(_, m)| m.active_connections)
-            .map(|(id, _)| *id);
-
-        let min_load_relay = metrics.iter()
-            .min_by_key(|(_, m)| m.active_connections)
-            .map(|(id, _)| *id);
-
-        if let (Some(max_relay), Some(min_relay)) = (max_load_relay, min_load_relay) {
-            if max_relay != min_relay {
-                let max_connections = metrics.get(&max_relay).unwrap().active_connections;
-                let min_connections = metrics.get(&min_relay).unwrap().active_connections;
-                
-                if max_connections > min_connections + (self.max_connections_per_relay / 3) {
-                    let users_to_move: Vec<String> = mapping.iter()
-                        .filter(|&(_, &relay_id)| relay_id == max_relay)
-                        .take((max_connections - min_connections) / 2)
-                        .map(|(username, _)| username.clone())
-                        .collect();
-
-                    for username in users_to_move {
-                        rebalances.push((username, max_relay, min_relay));
-                    }
+        migrated
+    }
+
+    /// Marca o relay como drenando e migra todos os usuários dele para os
+    /// outros relays; retorna quantos usuários ainda ficaram nele
+    pub async fn drain_relay(&self, relay_id: u32) -> usize {
+        self.draining.write().await.insert(relay_id);
+
+        let Some(relay_addr) = self.get_relay_addr(relay_id).await else {
+            return 0;
+        };
+        let Ok(users) = relay_addr.send(GetLocalUsers).await else {
+            return 0;
+        };
+
+        for username in users {
+            let Some(to) = self.select_relay(&username, None).await else {
+                break;
+            };
+            let Some(to_addr) = self.get_relay_addr(to).await else {
+                break;
+            };
+
+            let mut mapping = self.user_relay_mapping.write().await;
+            if Self::migrate_user(&mut mapping, username, (relay_id, &relay_addr), (to, to_addr)).await {
+                // Até a próxima sincronização, para não mandar todos ao mesmo relay
+                if let Some(metric) = self.metrics.write().await.get_mut(&to) {
+                    metric.active_connections += 1;
                 }
             }
         }
 
-        rebalances
+        // Quem conectou ou não pôde ser migrado durante a drenagem
+        relay_addr.send(GetLocalUsers).await.map_or(0, |users| users.len())
+    }
+
+    /// Migra as sessões de `username` e atualiza o mapeamento; retorna se
+    /// ele de fato foi movido
+    async fn migrate_user(
+        mapping: &mut UserMapping,
+        username: String,
+        (from, from_addr): (u32, &Addr<RelayActor>),
+        (to, to_addr): (u32, Addr<RelayActor>),
+    ) -> bool {
+        match from_addr.send(MigrateUser { username: username.clone(), to_relay_id: to, to: to_addr }).await {
+            Ok(true) => {
+                mapping.insert(username, to, Instant::now());
+                true
+            }
+            // Nenhuma sessão no relay: o usuário já se desconectou
+            Ok(false) => {
+                if mapping.get(&username).is_some_and(|assignment| assignment.relay_id == from) {
+                    mapping.remove(&username);
+                }
+                false
+            }
+            Err(e) => {
+                eprintln!("Falha ao migrar {} do relay {} para o {}: {}", username, from, to, e);
+                false
+            }
+        }
     }
 
     pub async fn sync_metrics_from_relays(&self) {
@@ -184,4 +715,232 @@ impl DynamicRelayBalancer {
     pub async fn get_relay_stats(&self) -> HashMap<u32, RelayMetrics> {
         self.metrics.read().await.clone()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{Actor, AsyncContext, Context};
+    use serde_json::json;
+    use crate::actors::DEFAULT_ROOM;
+    use crate::actors::persistence::{PersistenceActor, PersistenceConfig};
+    use crate::actors::relay::RelayRegistry;
+    use crate::actors::testing::{start_relay, TestClient};
+    use crate::balancing::HighestScore;
+    use crate::bus::memory::InMemoryBus;
+
+    const LOAD_FACTOR: f64 = 1.25;
+
+    async fn balancer(affinity: RelayAffinity, relays: u32) -> DynamicRelayBalancer {
+        let config = MappingConfig { ttl: Duration::from_secs(600), max_entries: 1_000 };
+        balancer_with(affinity, config, relays).await
+    }
+
+    async fn balancer_with(affinity: RelayAffinity, config: MappingConfig, relays: u32) -> DynamicRelayBalancer {
+        let balancer = DynamicRelayBalancer::new(
+            100,
+            Arc::new(HighestScore),
+            ScoreWeights::default(),
+            affinity,
+            config,
+        );
+        for relay_id in 1..=relays {
+            // O ator nunca roda: a seleção só olha anel e métricas
+            balancer.add_relay(relay_id, Context::<RelayActor>::new().address()).await;
+        }
+        balancer
+    }
+
+    async fn hash_balancer() -> DynamicRelayBalancer {
+        balancer(RelayAffinity::ConsistentHash { load_factor: LOAD_FACTOR }, 3).await
+    }
+
+    async fn preference(balancer: &DynamicRelayBalancer, key: &str) -> Vec<u32> {
+        balancer.ring.read().await.preference_list(key).into_iter().map(|node| node as u32).collect()
+    }
+
+    async fn set_connections(balancer: &DynamicRelayBalancer, relay_id: u32, connections: usize) {
+        balancer.update_relay_metrics(relay_id, connections, 0.0, 0.0).await;
+    }
+
+    #[tokio::test]
+    async fn select_by_hash_picks_ring_owner_under_the_bound() {
+        let balancer = hash_balancer().await;
+        let preference = preference(&balancer, "alice").await;
+
+        assert_eq!(balancer.select_by_hash("alice", LOAD_FACTOR).await, Some(preference[0]));
+    }
+
+    #[tokio::test]
+    async fn select_by_hash_overflows_to_next_relay_in_preference_list() {
+        let balancer = hash_balancer().await;
+        let preference = preference(&balancer, "alice").await;
+
+        // Média (10 + 1) / 3 com fator 1.25: limite de 5 conexões
+        set_connections(&balancer, preference[0], 10).await;
+        assert_eq!(balancer.select_by_hash("alice", LOAD_FACTOR).await, Some(preference[1]));
+
+        // Média (20 + 1) / 3: limite de 9, os dois primeiros estão acima
+        set_connections(&balancer, preference[1], 10).await;
+        assert_eq!(balancer.select_by_hash("alice", LOAD_FACTOR).await, Some(preference[2]));
+    }
+
+    #[tokio::test]
+    async fn select_by_hash_skips_unselectable_relays() {
+        let balancer = hash_balancer().await;
+        let preference = preference(&balancer, "alice").await;
+
+        balancer.mark_unhealthy(preference[0]).await;
+        assert_eq!(balancer.select_by_hash("alice", LOAD_FACTOR).await, Some(preference[1]));
+
+        balancer.draining.write().await.insert(preference[1]);
+        assert_eq!(balancer.select_by_hash("alice", LOAD_FACTOR).await, Some(preference[2]));
+
+        balancer.mark_unhealthy(preference[2]).await;
+        assert_eq!(balancer.select_by_hash("alice", LOAD_FACTOR).await, None);
+    }
+
+    #[tokio::test]
+    async fn consistent_hash_joins_existing_sessions_on_this_pod() {
+        let balancer = hash_balancer().await;
+        let bus = InMemoryBus::new("pod-a".to_string());
+        let preference = preference(&balancer, "alice").await;
+
+        bus.add_user_session("alice", "phone", preference[2]).await.unwrap();
+        let relay_id = balancer.get_best_relay_for_user("alice", None, Some(&bus)).await;
+        assert_eq!(relay_id, Some(preference[2]));
+
+        // Relay cheio: volta ao anel
+        set_connections(&balancer, preference[2], 100).await;
+        let relay_id = balancer.get_best_relay_for_user("alice", None, Some(&bus)).await;
+        assert_eq!(relay_id, Some(preference[0]));
+    }
+
+    #[tokio::test]
+    async fn consistent_hash_does_not_fill_the_mapping() {
+        let balancer = hash_balancer().await;
+
+        assert!(balancer.get_best_relay_for_user("alice", None, None).await.is_some());
+        assert_eq!(balancer.mapping_stats().await.entries, 0);
+    }
+
+    /// Relay que de fato roda e responde `GetLocalUsers` (sem nenhum usuário)
+    fn live_relay(balancer: &DynamicRelayBalancer, relay_id: u32) -> Addr<RelayActor> {
+        let persistence = PersistenceActor::new(PersistenceConfig::from_env()).start();
+        let bus = Arc::new(InMemoryBus::new("pod-a".to_string()));
+        RelayActor::new(relay_id, persistence, bus, RelayRegistry::default(), balancer.departures()).start()
+    }
+
+    fn departure(username: &str, relay_id: u32, at: Instant) -> UserDeparture {
+        UserDeparture { username: username.to_string(), relay_id, at }
+    }
+
+    #[tokio::test]
+    async fn release_user_removes_only_current_assignments() {
+        let balancer = balancer(RelayAffinity::Mapping, 2).await;
+
+        let relay_id = balancer.get_best_relay_for_user("alice", None, None).await.unwrap();
+        assert!(balancer.release_user(departure("alice", relay_id, Instant::now())).await);
+        assert_eq!(balancer.mapping_stats().await.entries, 0);
+
+        // Saída anterior à nova escolha de relay: o usuário já voltou
+        let departed_at = Instant::now();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let relay_id = balancer.get_best_relay_for_user("alice", None, None).await.unwrap();
+        assert!(!balancer.release_user(departure("alice", relay_id, departed_at)).await);
+
+        // Saída de outro relay: o usuário foi migrado
+        let other_relay = if relay_id == 1 { 2 } else { 1 };
+        assert!(!balancer.release_user(departure("alice", other_relay, Instant::now())).await);
+
+        let stats = balancer.mapping_stats().await;
+        assert_eq!((stats.entries, stats.released), (1, 1));
+    }
+
+    #[actix::test]
+    async fn expire_mapping_drops_unseen_users_after_ttl() {
+        let ttl = Duration::from_millis(50);
+        let config = MappingConfig { ttl, max_entries: 1_000 };
+        let balancer = balancer_with(RelayAffinity::Mapping, config, 0).await;
+        balancer.add_relay(1, live_relay(&balancer, 1)).await;
+
+        balancer.get_best_relay_for_user("alice", None, None).await.unwrap();
+        assert_eq!(balancer.expire_mapping().await, 0);
+
+        tokio::time::sleep(ttl * 2).await;
+        balancer.get_best_relay_for_user("bob", None, None).await.unwrap();
+        assert_eq!(balancer.expire_mapping().await, 1);
+
+        let mapping = balancer.user_relay_mapping.read().await;
+        assert!(!mapping.contains_key("alice"));
+        assert!(mapping.contains_key("bob"));
+        assert_eq!(mapping.by_last_seen.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn expire_mapping_keeps_users_of_unresponsive_relays() {
+        let config = MappingConfig { ttl: Duration::ZERO, max_entries: 1_000 };
+        let balancer = balancer_with(RelayAffinity::Mapping, config, 1).await;
+
+        balancer.get_best_relay_for_user("alice", None, None).await.unwrap();
+        assert_eq!(balancer.expire_mapping().await, 0);
+        assert_eq!(balancer.mapping_stats().await.entries, 1);
+    }
+
+    #[tokio::test]
+    async fn full_mapping_evicts_least_recently_seen() {
+        let config = MappingConfig { ttl: Duration::from_secs(600), max_entries: 2 };
+        let balancer = balancer_with(RelayAffinity::Mapping, config, 2).await;
+
+        for username in ["alice", "bob", "alice", "carol"] {
+            balancer.get_best_relay_for_user(username, None, None).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        let mapping = balancer.user_relay_mapping.read().await;
+        assert!(mapping.contains_key("alice"));
+        assert!(!mapping.contains_key("bob"));
+        assert!(mapping.contains_key("carol"));
+        assert_eq!(mapping.by_last_seen.len(), 2);
+        drop(mapping);
+        assert_eq!(balancer.mapping_stats().await.evicted, 1);
+    }
+
+    #[actix::test]
+    async fn rebalance_moves_a_live_user_without_leave_or_join_events() {
+        let balancer = balancer(RelayAffinity::Mapping, 0).await;
+        let bus: Arc<dyn MessageBus> = Arc::new(InMemoryBus::new("pod-a".to_string()));
+        for relay_id in 1..=2 {
+            balancer.add_relay(relay_id, start_relay(bus.clone(), relay_id)).await;
+        }
+        let from = balancer.get_best_relay_for_user("alice", None, None).await.unwrap();
+        let to = if from == 1 { 2 } else { 1 };
+        let (from_addr, to_addr) = (balancer.get_relay_addr(from).await.unwrap(), balancer.get_relay_addr(to).await.unwrap());
+
+        // Bob fica no relay de origem e Carol no de destino
+        let mut alice = TestClient::connect("alice", from_addr.clone()).await;
+        let mut bob = TestClient::connect("bob", from_addr.clone()).await;
+        let mut carol = TestClient::connect("carol", to_addr.clone()).await;
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.frames_within(Duration::from_millis(200)).await;
+        }
+
+        assert_eq!(balancer.execute_rebalances(vec![("alice".to_string(), from, to)]).await, 1);
+        assert!(!from_addr.send(GetLocalUsers).await.unwrap().contains(&"alice".to_string()));
+        assert!(to_addr.send(GetLocalUsers).await.unwrap().contains(&"alice".to_string()));
+        assert_eq!(balancer.user_relay_mapping.read().await.get("alice").map(|assignment| assignment.relay_id), Some(to));
+
+        // Ninguém vê alice sair ou entrar de novo
+        for client in [&mut alice, &mut bob, &mut carol] {
+            let frames = client.frames_within(Duration::from_millis(200)).await;
+            assert!(frames.is_empty(), "eventos da migração: {:?}", frames);
+        }
+
+        // A mesma conexão continua na sala, agora pelo relay de destino
+        alice.send("message", None, json!({ "room_id": DEFAULT_ROOM, "content": "migrada" }));
+        assert_eq!(bob.expect("message").await["payload"]["content"], "migrada");
+        assert_eq!(carol.expect("message").await["payload"]["content"], "migrada");
+        bob.send("message", None, json!({ "room_id": DEFAULT_ROOM, "content": "recebeu?" }));
+        assert_eq!(alice.expect("message").await["payload"]["content"], "recebeu?");
+    }
+}
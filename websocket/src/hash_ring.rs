@@ -0,0 +1,184 @@
+// src/hash_ring.rs
+use std::collections::BTreeSet;
+
+/// Hash de 64 bits estável entre processos, pods e versões do Rust (ao
+/// contrário do `DefaultHasher`): FNV-1a seguido do finalizador do
+/// MurmurHash3, que espalha nos bits altos as diferenças do fim da chave
+/// (`"no#1"`, `"no#2"`...)
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    hash
+}
+
+/// Anel de hash consistente com nós virtuais. Quando um nó entra ou sai, só
+/// as chaves dos arcos dele mudam de dono.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes_per_node: usize,
+    /// `(posição no anel, índice do nó)`, ordenado pela posição
+    vnodes: Vec<(u64, usize)>,
+    members: BTreeSet<usize>,
+}
+
+impl HashRing {
+    pub fn new(vnodes_per_node: usize) -> Self {
+        Self {
+            vnodes_per_node: vnodes_per_node.max(1),
+            vnodes: Vec::new(),
+            members: BTreeSet::new(),
+        }
+    }
+
+    /// Adiciona o nó `node`; as posições saem de `name` (a URL), para que
+    /// todos os pods montem o mesmo anel
+    pub fn add(&mut self, node: usize, name: &str) {
+        if !self.members.insert(node) {
+            return;
+        }
+
+        for vnode in 0..self.vnodes_per_node {
+            let position = stable_hash(format!("{}#{}", name, vnode).as_bytes());
+            self.vnodes.push((position, node));
+        }
+        self.vnodes.sort_unstable();
+    }
+
+    pub fn remove(&mut self, node: usize) {
+        if self.members.remove(&node) {
+            self.vnodes.retain(|(_, owner)| *owner != node);
+        }
+    }
+
+    pub fn contains(&self, node: usize) -> bool {
+        self.members.contains(&node)
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Nó dono de `key`: o primeiro nó virtual no sentido horário
+    pub fn get(&self, key: &str) -> Option<usize> {
+        if self.vnodes.is_empty() {
+            return None;
+        }
+
+        let position = stable_hash(key.as_bytes());
+        let index = self.vnodes.partition_point(|(vnode, _)| *vnode < position);
+        let (_, node) = self.vnodes[index % self.vnodes.len()];
+        Some(node)
+    }
+
+    /// Todos os nós na ordem em que `key` os encontra no sentido horário: o
+    /// dono primeiro, depois quem herdaria a chave se os anteriores saíssem
+    pub fn preference_list(&self, key: &str) -> Vec<usize> {
+        if self.vnodes.is_empty() {
+            return Vec::new();
+        }
+
+        let position = stable_hash(key.as_bytes());
+        let start = self.vnodes.partition_point(|(vnode, _)| *vnode < position);
+        let mut nodes = Vec::with_capacity(self.members.len());
+        for offset in 0..self.vnodes.len() {
+            let (_, node) = self.vnodes[(start + offset) % self.vnodes.len()];
+            if !nodes.contains(&node) {
+                nodes.push(node);
+                if nodes.len() == self.members.len() {
+                    break;
+                }
+            }
+        }
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(nodes: usize) -> HashRing {
+        let mut ring = HashRing::new(160);
+        for node in 0..nodes {
+            ring.add(node, &format!("redis://redis-{}:6379", node));
+        }
+        ring
+    }
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..2_000).map(|i| format!("room:{}", i))
+    }
+
+    #[test]
+    fn stable_hash_matches_golden_values() {
+        // Mudar estes valores remapeia canais e relays em todos os pods
+        assert_eq!(stable_hash(b""), 0xefd0_1f60_ba99_2926);
+        assert_eq!(stable_hash(b"alice"), 0x3507_d047_a67c_08f4);
+        assert_eq!(stable_hash(b"room:general"), 0x166d_6774_734d_91f0);
+        assert_eq!(stable_hash(b"redis://redis-0:6379#0"), 0xfa23_489f_8894_dfc1);
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        let before = ring(3);
+        let mut after = before.clone();
+        after.add(3, "redis://redis-3:6379");
+
+        let mut moved = 0;
+        for key in keys() {
+            let (old, new) = (before.get(&key), after.get(&key));
+            if old != new {
+                assert_eq!(new, Some(3), "{} mudou de {:?} para {:?}", key, old, new);
+                moved += 1;
+            }
+        }
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let before = ring(4);
+        let mut after = before.clone();
+        after.remove(2);
+
+        for key in keys() {
+            let (old, new) = (before.get(&key), after.get(&key));
+            if old != Some(2) {
+                assert_eq!(old, new, "{} não era do nó removido e mudou de dono", key);
+            } else {
+                assert_ne!(new, Some(2));
+            }
+        }
+    }
+
+    #[test]
+    fn preference_list_starts_with_owner_and_lists_each_member_once() {
+        let ring = ring(5);
+
+        for key in keys() {
+            let preference = ring.preference_list(&key);
+            assert_eq!(preference.first().copied(), ring.get(&key));
+
+            let mut sorted = preference.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, vec![0, 1, 2, 3, 4]);
+        }
+        assert!(HashRing::new(10).preference_list("room:0").is_empty());
+    }
+}
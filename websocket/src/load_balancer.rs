@@ -1,8 +1,19 @@
 use std::collections::HashMap;
-use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use serde::{Deserialize, Serialize};
+use crate::actors::RedisMessageType;
+use crate::actors::persistence::env_or;
+use crate::balancing::{BalancingStrategy, Candidate, ScoreWeights, WeightedRandom};
+use crate::bus::{unix_now, MessageBus};
+use crate::channels::Channel;
+
+/// Remetente das publicações de `pod_metrics`, que são do pod e não de um relay
+pub const POD_PUBLISHER_ID: u32 = 0;
+/// Sem métricas por esse tempo, o pod sai da visão do cluster
+const INACTIVE_POD_SECS: u64 = 60;
+/// Capacidade assumida para pods que não informam `connection_capacity`
+const DEFAULT_POD_CAPACITY: usize = 1000;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PodMetrics {
@@ -12,58 +23,172 @@ pub struct PodMetrics {
     pub memory_usage: f64,
     pub relay_count: usize,
     pub last_updated: u64,
+    /// Endereço pelo qual clientes alcançam este pod diretamente, passando
+    /// pelo ingress (`POD_PUBLIC_URL`); sem ele o pod não é destino de `307`
+    #[serde(default)]
+    pub public_url: Option<String>,
+    /// Conexões que os relays do pod comportam
+    #[serde(default)]
+    pub connection_capacity: usize,
+    /// O pod está encerrando e não aceita conexões novas
+    #[serde(default)]
+    pub draining: bool,
+}
+
+/// Quando um pod sobrecarregado recusa upgrades e indica outro pod ao cliente
+#[derive(Debug, Clone)]
+pub struct RedirectPolicy {
+    pub enabled: bool,
+    /// Uso de CPU (%) a partir do qual o pod está sobrecarregado
+    pub cpu_threshold: f64,
+    /// Uso de memória (%) a partir do qual o pod está sobrecarregado
+    pub memory_threshold: f64,
+    /// Fração de `connection_capacity` a partir da qual o pod está sobrecarregado
+    pub connection_threshold: f64,
+}
+
+impl RedirectPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: env_or("POD_REDIRECT_ENABLED", true),
+            cpu_threshold: env_or("POD_REDIRECT_CPU_THRESHOLD", 90.0),
+            memory_threshold: env_or("POD_REDIRECT_MEMORY_THRESHOLD", 90.0),
+            connection_threshold: env_or("POD_REDIRECT_CONNECTION_THRESHOLD", 0.9),
+        }
+    }
+
+    pub fn is_overloaded(&self, metrics: &PodMetrics) -> bool {
+        let connections_full = metrics.connection_capacity > 0
+            && metrics.active_connections as f64 >= metrics.connection_capacity as f64 * self.connection_threshold;
+
+        metrics.cpu_usage >= self.cpu_threshold
+            || metrics.memory_usage >= self.memory_threshold
+            || connections_full
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct LoadBalancer {
     pods: Arc<RwLock<HashMap<String, PodMetrics>>>,
     weights: Arc<RwLock<HashMap<String, f64>>>,
+    strategy: Arc<dyn BalancingStrategy>,
+    score_weights: ScoreWeights,
+}
+
+impl Default for LoadBalancer {
+    fn default() -> Self {
+        Self::new(Arc::new(WeightedRandom::default()), ScoreWeights::default())
+    }
 }
 
 impl LoadBalancer {
-    pub fn new() -> Self {
+    pub fn new(strategy: Arc<dyn BalancingStrategy>, score_weights: ScoreWeights) -> Self {
         Self {
             pods: Arc::new(RwLock::new(HashMap::new())),
             weights: Arc::new(RwLock::new(HashMap::new())),
+            strategy,
+            score_weights,
         }
     }
 
+    pub fn strategy_name(&self) -> &'static str {
+        self.strategy.name()
+    }
+
     pub async fn update_pod_metrics(&self, metrics: PodMetrics) {
         let mut pods = self.pods.write().await;
         let mut weights = self.weights.write().await;
         
-        let connection_factor = 1.0 - (metrics.active_connections as f64 / 1000.0).min(1.0);
+        let capacity = match metrics.connection_capacity {
+            0 => DEFAULT_POD_CAPACITY,
+            capacity => capacity,
+        };
+        let connection_factor = 1.0 - (metrics.active_connections as f64 / capacity as f64).min(1.0);
         let cpu_factor = 1.0 - (metrics.cpu_usage / 100.0);
         let memory_factor = 1.0 - (metrics.memory_usage / 100.0);
         
-        let weight = (connection_factor * 0.5) + (cpu_factor * 0.3) + (memory_factor * 0.2);
+        let weight = self.score_weights.score(connection_factor, cpu_factor, memory_factor);
 
         pods.insert(metrics.pod_id.clone(), metrics.clone());
         weights.insert(metrics.pod_id, weight.max(0.1));
     }
 
-    pub async fn select_best_pod(&self) -> Option<String> {
-        let weights = self.weights.read().await;
+    /// `username` só importa para estratégias com afinidade (`consistent_hash`)
+    pub async fn select_best_pod(&self, username: Option<&str>) -> Option<String> {
+        self.select_with_strategy(username, |_| true).await
+    }
 
-        if weights.is_empty() {
+    /// Pod recomendado por `select_best_pod` entre os que podem receber um
+    /// cliente recusado por este pod, se este estiver sobrecarregado. O pod
+    /// escolhido pode não ter `public_url`: aí o cliente só é recusado
+    pub async fn redirect_target(&self, own_pod_id: &str, username: &str, policy: &RedirectPolicy) -> Option<PodMetrics> {
+        if !policy.enabled {
             return None;
         }
-        
-        let total_weight: f64 = weights.values().sum();
-        
-        let mut hasher = DefaultHasher::new();
-        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos().hash(&mut hasher);
-        let random_value = (hasher.finish() as f64 / u64::MAX as f64) * total_weight;
-
-        let mut remaining_weight = random_value;
-        for (pod_id, weight) in weights.iter() {
-            remaining_weight -= weight;
-            if remaining_weight <= 0.0 {
-                return Some(pod_id.clone());
-            }
+
+        let overloaded = self.pods.read().await.get(own_pod_id)
+            .is_some_and(|metrics| policy.is_overloaded(metrics));
+        if !overloaded {
+            return None;
         }
-        
-        weights.keys().next().cloned()
+
+        let pod_id = self.select_with_strategy(Some(username), |pod| {
+            pod.pod_id != own_pod_id && !pod.draining && !policy.is_overloaded(pod)
+        }).await?;
+        self.pods.read().await.get(&pod_id).cloned()
+    }
+
+    /// Escolha da estratégia configurada entre os pods aceitos por `eligible`
+    async fn select_with_strategy(&self, username: Option<&str>, eligible: impl Fn(&PodMetrics) -> bool) -> Option<String> {
+        let pods = self.pods.read().await;
+        let mut candidates: Vec<Candidate> = self.weights.read().await.iter()
+            .filter_map(|(pod_id, weight)| {
+                let pod = pods.get(pod_id).filter(|pod| eligible(pod))?;
+                Some(Candidate {
+                    id: pod_id.clone(),
+                    active_connections: pod.active_connections,
+                    score: *weight,
+                })
+            })
+            .collect();
+        // A ordem do HashMap muda entre execuções; com ela fixa, uma semente
+        // fixa reproduz a mesma sequência de escolhas
+        candidates.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let index = self.strategy.select(&candidates, username)?;
+        candidates.into_iter().nth(index).map(|candidate| candidate.id)
+    }
+
+    /// Publica as métricas deste pod para os outros pods
+    pub async fn publish_pod_metrics(&self, bus: &dyn MessageBus, metrics: PodMetrics) {
+        let channel = Channel::PodMetrics.name();
+        if let Err(e) = bus.publish(&channel, POD_PUBLISHER_ID, RedisMessageType::PodMetrics(metrics)).await {
+            eprintln!("Falha ao publicar métricas do pod: {}", e);
+        }
+    }
+
+    /// Assina `pod_metrics` e mantém as métricas dos outros pods atualizadas
+    pub fn start_cluster_sync(&self, bus: Arc<dyn MessageBus>) {
+        let load_balancer = self.clone();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            // A assinatura vive enquanto a task existir
+            let _subscription = bus.subscribe(&Channel::PodMetrics.name(), POD_PUBLISHER_ID, tx);
+
+            while let Some(message) = rx.recv().await {
+                let RedisMessageType::PodMetrics(mut metrics) = message.message_type else {
+                    continue;
+                };
+                if metrics.pod_id != message.from_pod_id {
+                    continue;
+                }
+                // O relógio do outro pod pode estar adiantado ou atrasado; a
+                // limpeza usa o momento em que as métricas chegaram aqui
+                metrics.last_updated = unix_now();
+                load_balancer.update_pod_metrics(metrics).await;
+            }
+        });
     }
 
     pub async fn get_pod_stats(&self) -> HashMap<String, PodMetrics> {
@@ -81,7 +206,7 @@ impl LoadBalancer {
 
         let inactive_pods: Vec<String> = pods
             .iter()
-            .filter(|(_, metrics)| current_time - metrics.last_updated > 60)
+            .filter(|(_, metrics)| current_time.saturating_sub(metrics.last_updated) > INACTIVE_POD_SECS)
             .map(|(pod_id, _)| pod_id.clone())
             .collect();
 
@@ -90,4 +215,146 @@ impl LoadBalancer {
             weights.remove(&pod_id);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::balancing::StrategyRng;
+    use crate::bus::memory::InMemoryBus;
+
+    fn pod(pod_id: &str, active_connections: usize, cpu_usage: f64) -> PodMetrics {
+        PodMetrics {
+            pod_id: pod_id.to_string(),
+            active_connections,
+            cpu_usage,
+            memory_usage: 20.0,
+            relay_count: 2,
+            last_updated: unix_now(),
+            public_url: None,
+            connection_capacity: 1000,
+            draining: false,
+        }
+    }
+
+    async fn seeded_balancer(seed: u64) -> LoadBalancer {
+        let balancer = LoadBalancer::new(
+            Arc::new(WeightedRandom::new(StrategyRng::seeded(seed))),
+            ScoreWeights::default(),
+        );
+        for metrics in [pod("pod-a", 100, 10.0), pod("pod-b", 500, 50.0), pod("pod-c", 900, 90.0)] {
+            balancer.update_pod_metrics(metrics).await;
+        }
+        balancer
+    }
+
+    #[tokio::test]
+    async fn select_best_pod_honours_weights() {
+        let balancer = seeded_balancer(7).await;
+        let weights = balancer.weights.read().await.clone();
+        let total: f64 = weights.values().sum();
+
+        let draws = 30_000;
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..draws {
+            *counts.entry(balancer.select_best_pod(None).await.unwrap()).or_default() += 1;
+        }
+
+        for (pod_id, weight) in weights {
+            let observed = counts.get(&pod_id).copied().unwrap_or(0) as f64 / draws as f64;
+            assert!((observed - weight / total).abs() < 0.015, "{}: observado {:.4}, esperado {:.4}", pod_id, observed, weight / total);
+        }
+    }
+
+    #[tokio::test]
+    async fn select_best_pod_is_reproducible_with_seed() {
+        let mut sequences = Vec::new();
+        for _ in 0..2 {
+            let balancer = seeded_balancer(7).await;
+            let mut sequence = Vec::new();
+            for _ in 0..50 {
+                sequence.push(balancer.select_best_pod(None).await.unwrap());
+            }
+            sequences.push(sequence);
+        }
+
+        assert_eq!(sequences[0], sequences[1]);
+    }
+
+    #[tokio::test]
+    async fn redirect_target_skips_draining_and_overloaded_pods() {
+        let balancer = LoadBalancer::new(
+            Arc::new(WeightedRandom::new(StrategyRng::seeded(3))),
+            ScoreWeights::default(),
+        );
+        let policy = RedirectPolicy { enabled: true, cpu_threshold: 80.0, memory_threshold: 90.0, connection_threshold: 0.9 };
+        let draining = PodMetrics { draining: true, ..pod("pod-b", 10, 10.0) };
+        for metrics in [pod("pod-a", 950, 50.0), draining, pod("pod-c", 100, 85.0), pod("pod-d", 200, 30.0)] {
+            balancer.update_pod_metrics(metrics).await;
+        }
+
+        for _ in 0..20 {
+            // pod-d não anuncia `public_url` e ainda assim é o destino
+            let target = balancer.redirect_target("pod-a", "alice", &policy).await.unwrap();
+            assert_eq!(target.pod_id, "pod-d");
+            assert_eq!(target.public_url, None);
+        }
+        assert!(balancer.redirect_target("pod-d", "alice", &policy).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn cluster_sync_accepts_only_metrics_about_the_publishing_pod() {
+        let bus: Arc<dyn MessageBus> = Arc::new(InMemoryBus::new("pod-a".to_string()));
+        let balancer = LoadBalancer::default();
+        balancer.start_cluster_sync(bus.clone());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Enviadas por um relay do pod-a, para não serem filtradas como próprias
+        let stale = PodMetrics { last_updated: 0, ..pod("pod-a", 10, 10.0) };
+        bus.publish(&Channel::PodMetrics.name(), 1, RedisMessageType::PodMetrics(stale)).await.unwrap();
+        let forged = pod("pod-b", 10, 10.0);
+        bus.publish(&Channel::PodMetrics.name(), 1, RedisMessageType::PodMetrics(forged)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let pods = balancer.get_pod_stats().await;
+        assert_eq!(pods.keys().collect::<Vec<_>>(), ["pod-a"]);
+        // O horário que vale é o da chegada, não o do relógio de quem publicou
+        assert!(pods["pod-a"].last_updated >= unix_now() - 1);
+    }
+
+    #[tokio::test]
+    async fn cleanup_drops_pods_without_recent_metrics() {
+        let balancer = LoadBalancer::default();
+        let silent = PodMetrics { last_updated: unix_now() - INACTIVE_POD_SECS - 1, ..pod("pod-b", 10, 10.0) };
+        balancer.update_pod_metrics(pod("pod-a", 10, 10.0)).await;
+        balancer.update_pod_metrics(silent).await;
+
+        balancer.cleanup_inactive_pods().await;
+
+        assert_eq!(balancer.get_pod_stats().await.keys().collect::<Vec<_>>(), ["pod-a"]);
+        assert!(!balancer.weights.read().await.contains_key("pod-b"));
+    }
+
+    #[tokio::test]
+    async fn busier_pods_weigh_less() {
+        let balancer = seeded_balancer(7).await;
+        let weights = balancer.weights.read().await.clone();
+
+        assert!(weights["pod-a"] > weights["pod-b"]);
+        assert!(weights["pod-b"] > weights["pod-c"]);
+        assert!(weights.values().all(|weight| *weight >= 0.1));
+    }
+
+    #[test]
+    fn overloaded_by_any_threshold() {
+        let policy = RedirectPolicy { enabled: true, cpu_threshold: 80.0, memory_threshold: 90.0, connection_threshold: 0.9 };
+
+        assert!(!policy.is_overloaded(&pod("pod-a", 899, 79.9)));
+        assert!(policy.is_overloaded(&pod("pod-a", 100, 80.0)));
+        assert!(policy.is_overloaded(&PodMetrics { memory_usage: 95.0, ..pod("pod-a", 100, 10.0) }));
+        assert!(policy.is_overloaded(&pod("pod-a", 900, 10.0)));
+        // Sem capacidade informada, as conexões não contam
+        assert!(!policy.is_overloaded(&PodMetrics { connection_capacity: 0, ..pod("pod-a", 5_000, 10.0) }));
+    }
+}
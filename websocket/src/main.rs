@@ -1,26 +1,60 @@
+use std::collections::HashMap;
 use std::env;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use actix::{Actor};
 use actix_web::{web, App, HttpServer, HttpResponse};
+use serde::Deserialize;
 use serde_json::json;
 use log::{info, debug, warn, error};
 use sysinfo::{System};
 use tokio::sync::Mutex;
-use crate::actors::relay::RelayActor;
+use crate::actors::{is_valid_room_id, GetPersistenceStats, ResumeRequest};
+use crate::actors::persistence::{PersistenceActor, PersistenceConfig};
 use crate::actors::ws::WsConn;
-use crate::load_balancer::{LoadBalancer, PodMetrics};
-use crate::dynamic_relay_balancer::{DynamicRelayBalancer, RelayMetrics};
+use crate::auth::{bearer_token, is_admin, RedirectSigner, TokenVerifier};
+use crate::load_balancer::{LoadBalancer, PodMetrics, RedirectPolicy};
+use crate::protocol::{negotiate_version, subprotocol_name, SUPPORTED_VERSIONS};
+use crate::dynamic_relay_balancer::{DynamicRelayBalancer, MappingConfig, RelayAffinity};
+use crate::bus::MessageBus;
+use crate::balancing::ScoreWeights;
+use crate::relay_pool::{PoolError, PoolTrigger, RelayPool, RelayPoolConfig, RetireOutcome};
+use crate::shutdown::DrainConfig;
 
 pub mod actors;
 pub mod load_balancer;
 pub mod dynamic_relay_balancer;
+pub mod balancing;
+pub mod bus;
+pub mod channels;
+pub mod hash_ring;
 pub mod redis_cluster;
+pub mod redis_backend;
+pub mod redis_transport;
+pub mod relay_pool;
+pub mod protocol;
+pub mod auth;
+pub mod shutdown;
 
 pub struct AppState {
     relay_balancer: DynamicRelayBalancer,
     load_balancer: LoadBalancer,
+    persistence: actix::Addr<PersistenceActor>,
+    /// Message bus compartilhado pelos relays do pod (`None` se estava indisponível)
+    bus: Option<Arc<dyn MessageBus>>,
+    /// Cria e aposenta relays do pod (`None` sem message bus)
+    relay_pool: Option<RelayPool>,
+    token_verifier: TokenVerifier,
+    redirect_signer: RedirectSigner,
+    /// Token dos endpoints `/admin`; sem ele, ficam desabilitados
+    admin_token: Option<String>,
+    redirect_policy: RedirectPolicy,
+    /// Endereço deste pod anunciado aos outros, destino dos redirecionamentos
+    public_url: Option<String>,
     pod_id: String,
     system: Arc<Mutex<System>>,
+    /// Ligado no encerramento: upgrades são recusados e `/health` falha
+    draining: Arc<AtomicBool>,
 }
 
 impl AppState {
@@ -49,25 +83,61 @@ impl AppState {
             .unwrap_or_else(|_| format!("pod-{}", std::process::id()));
         info!("Pod ID: {}", pod_id);
 
+        let token_verifier = TokenVerifier::from_env();
+        let redirect_signer = RedirectSigner::from_env();
+        let admin_token = env::var("ADMIN_TOKEN").ok();
+        let redirect_policy = RedirectPolicy::from_env();
+        let public_url = env::var("POD_PUBLIC_URL").ok();
+        debug!("Redirecionamento de upgrades: {:?}, URL pública: {:?}", redirect_policy, public_url);
+
         info!("Criando DynamicRelayBalancer e LoadBalancer");
-        let relay_balancer = DynamicRelayBalancer::new(max_connections_per_relay);
-        let load_balancer = LoadBalancer::new();
-        
-        info!("Iniciando {} relays", relay_count);
-        for i in 0..relay_count {
-            let relay_id = relay_start_id + i;
-            debug!("Tentando iniciar relay {}", relay_id);
-
-            match RelayActor::new(relay_id).await {
-                Ok(relay_actor) => {
-                    let relay_addr = relay_actor.start();
-                    relay_balancer.add_relay(relay_id, relay_addr).await;
-                    info!("Pod {}: Relay {} iniciado com sucesso e conectado ao Redis Cluster", pod_id, relay_id);
-                }
-                Err(e) => {
-                    error!("Falha ao iniciar relay {}: {}", relay_id, e);
-                }
+        let relay_balancer = DynamicRelayBalancer::new(
+            max_connections_per_relay,
+            balancing::strategy_from_env("RELAY_BALANCING_STRATEGY", "highest_score"),
+            ScoreWeights::from_env("RELAY_SCORE_WEIGHTS"),
+            RelayAffinity::from_env(),
+            MappingConfig::from_env(),
+        );
+        relay_balancer.start_mapping_cleanup();
+        let load_balancer = LoadBalancer::new(
+            balancing::strategy_from_env("POD_BALANCING_STRATEGY", "weighted_random"),
+            ScoreWeights::from_env("POD_SCORE_WEIGHTS"),
+        );
+        debug!("Estratégias de balanceamento: relays {} ({:?}), pods {}",
+               relay_balancer.strategy_name(), relay_balancer.affinity(), load_balancer.strategy_name());
+
+        info!("Iniciando pipeline de persistência de mensagens");
+        let persistence = PersistenceActor::new(PersistenceConfig::from_env()).start();
+
+        info!("Conectando ao message bus");
+        let bus = match bus::from_env().await {
+            Ok(bus) => {
+                info!("Message bus: {}", bus.name());
+                Some(bus)
+            }
+            Err(e) => {
+                error!("Falha ao conectar ao message bus, nenhum relay será iniciado: {}", e);
+                None
             }
+        };
+
+        let relay_pool = bus.as_ref().map(|bus| {
+            let config = RelayPoolConfig::from_env(relay_count as usize);
+            debug!("Pool de relays configurado: {:?}", config);
+            RelayPool::new(relay_balancer.clone(), bus.clone(), persistence.clone(), config, relay_start_id)
+        });
+
+        if let Some(relay_pool) = &relay_pool {
+            info!("Iniciando {} relays", relay_count);
+            relay_pool.start_initial(relay_count).await;
+            relay_pool.start_supervisor();
+            info!("Pod {}: Relays {}..={} iniciados e conectados ao message bus",
+                  pod_id, relay_start_id, relay_start_id + relay_count.saturating_sub(1));
+        }
+
+        if let Some(bus) = &bus {
+            info!("Sincronizando métricas dos pods pelo message bus");
+            load_balancer.start_cluster_sync(bus.clone());
         }
 
         info!("Inicializando sistema de monitoramento sysinfo");
@@ -77,11 +147,24 @@ impl AppState {
         AppState {
             relay_balancer,
             load_balancer,
+            persistence,
+            bus,
+            relay_pool,
+            token_verifier,
+            redirect_signer,
+            admin_token,
+            redirect_policy,
+            public_url,
             pod_id,
             system,
+            draining: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
     async fn get_cpu_usage(system: &Arc<Mutex<System>>) -> f64 {
         let mut sys = system.lock().await;
         sys.refresh_all();
@@ -108,10 +191,15 @@ impl AppState {
         info!("Iniciando sistema de atualização de métricas");
         let load_balancer = self.load_balancer.clone();
         let relay_balancer = self.relay_balancer.clone();
+        let relay_pool = self.relay_pool.clone();
+        let bus = self.bus.clone();
         let pod_id = self.pod_id.clone();
+        let public_url = self.public_url.clone();
         let system = self.system.clone();
+        let draining = self.draining.clone();
 
-        tokio::spawn(async move {
+        // No arbiter do actix: o autoscaler inicia atores de relay
+        actix::spawn(async move {
             let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
             info!("Metrics updater configurado para executar a cada 10 segundos");
 
@@ -140,33 +228,214 @@ impl AppState {
                         .duration_since(std::time::UNIX_EPOCH)
                         .unwrap()
                         .as_secs(),
+                    public_url: public_url.clone(),
+                    connection_capacity: relay_stats.len() * relay_balancer.max_connections_per_relay(),
+                    draining: draining.load(Ordering::Relaxed),
                 };
 
                 info!("Atualizando métricas do pod: {} conexões, CPU: {:.2}%, Mem: {:.2}%", 
                      total_connections, cpu_usage, memory_usage);
-                load_balancer.update_pod_metrics(pod_metrics).await;
+                load_balancer.update_pod_metrics(pod_metrics.clone()).await;
+                if let Some(bus) = &bus {
+                    load_balancer.publish_pod_metrics(bus.as_ref(), pod_metrics).await;
+                }
                 load_balancer.cleanup_inactive_pods().await;
+
+                // Encerrando: as sessões vão ser fechadas e o pool não muda mais
+                if draining.load(Ordering::Relaxed) {
+                    continue;
+                }
                 
                 let rebalances = relay_balancer.rebalance_if_needed().await;
                 if !rebalances.is_empty() {
-                    warn!("Rebalanceamento necessário: {} usuários precisam ser redistribuídos", rebalances.len());
+                    let planned = rebalances.len();
+                    let migrated = relay_balancer.execute_rebalances(rebalances).await;
+                    info!("Rebalanceamento: {} de {} usuários migrados", migrated, planned);
+                }
+
+                let expired = relay_balancer.expire_mapping().await;
+                if expired > 0 {
+                    debug!("{} usuários desconectados expiraram do mapeamento de relays", expired);
+                }
+
+                if let Some(relay_pool) = &relay_pool {
+                    relay_pool.autoscale().await;
                 }
             }
         });
     }
 }
 
-#[actix_web::get("/ws/{username}")]
+#[derive(Deserialize)]
+struct UpgradeParams {
+    version: Option<u16>,
+    access_token: Option<String>,
+    /// `session_id` de uma conexão anterior, para retomar salas e mensagens perdidas
+    resume_token: Option<String>,
+    /// Maior `seq` recebida na conexão anterior, de clientes que só
+    /// acompanham uma sala; `room_seqs` prevalece
+    last_seq: Option<u64>,
+    /// Maior `seq` recebida em cada sala, como `sala:seq,sala:seq`
+    room_seqs: Option<String>,
+    /// Marcador assinado pelo pod que redirecionou o cliente para este; com
+    /// ele válido não redirecionamos de novo, para evitar ciclos
+    redirect_token: Option<String>,
+    /// Chave que substitui o username na escolha do relay com
+    /// `RELAY_AFFINITY=consistent_hash` (ex.: a sala principal do cliente,
+    /// para concentrar os membros dela no mesmo relay)
+    affinity: Option<String>,
+}
+
+/// Lê `sala:seq,sala:seq`; pares malformados são ignorados
+fn parse_room_seqs(raw: &str) -> HashMap<String, u64> {
+    raw.split(',')
+        .filter_map(|pair| {
+            let (room_id, seq) = pair.split_once(':')?;
+            Some((room_id.to_string(), seq.parse().ok()?))
+        })
+        .filter(|(room_id, _)| is_valid_room_id(room_id))
+        .collect()
+}
+
+/// Parâmetros que não seguem para o pod de destino: o token de acesso não
+/// deve aparecer numa URL de redirecionamento (logs, histórico) e o
+/// marcador é reemitido
+const NON_FORWARDED_PARAMS: &[&str] = &["access_token", "redirect_token"];
+
+/// URL do upgrade no pod de destino, com o mesmo path e query, sem o token
+/// de acesso e com o marcador de redirecionamento
+fn redirect_location(public_url: &str, path: &str, query: &str, redirect_token: &str) -> String {
+    let token_param = format!("redirect_token={}", redirect_token);
+    let params: Vec<&str> = query.split('&')
+        .filter(|pair| {
+            let key = pair.split_once('=').map_or(*pair, |(key, _)| key);
+            !pair.is_empty() && !NON_FORWARDED_PARAMS.contains(&key)
+        })
+        .chain([token_param.as_str()])
+        .collect();
+
+    format!("{}{}?{}", public_url.trim_end_matches('/'), path, params.join("&"))
+}
+
+/// Recusa o upgrade num pod sobrecarregado. Com `public_url`, o destino é
+/// alcançável pelo ingress e vai no `Location` de um `307`; sem ela, a
+/// resposta é um `503`, que o ingress reenvia a outro pod
+/// (`proxy-next-upstream`). Navegadores não seguem redirecionamentos no
+/// upgrade; o corpo traz o destino para o cliente reconectar sozinho. O
+/// `Location` não leva o token de acesso: o cliente o envia de novo no
+/// header `Authorization` ou em `access_token`.
+fn overloaded_response(state: &AppState, username: &str, target: PodMetrics, req: &actix_web::HttpRequest) -> HttpResponse {
+    let overloaded = HttpResponse::ServiceUnavailable()
+        .insert_header((actix_web::http::header::RETRY_AFTER, "1"))
+        .json(json!({
+            "error": "pod_overloaded",
+            "pod_id": target.pod_id,
+        }));
+    let Some(public_url) = target.public_url else {
+        info!("Pod {} sobrecarregado: upgrade de {} recusado, pod {} tem folga", state.pod_id, username, target.pod_id);
+        return overloaded;
+    };
+    let redirect_token = match state.redirect_signer.issue(username, &target.pod_id) {
+        Ok(redirect_token) => redirect_token,
+        Err(e) => {
+            error!("Falha ao assinar o marcador de redirecionamento para {}: {}", username, e);
+            return overloaded;
+        }
+    };
+
+    let location = redirect_location(&public_url, req.path(), req.query_string(), &redirect_token);
+    info!("Pod {} sobrecarregado: usuário {} redirecionado para o pod {}", state.pod_id, username, target.pod_id);
+    HttpResponse::TemporaryRedirect()
+        .insert_header((actix_web::http::header::LOCATION, location.clone()))
+        .json(json!({
+            "error": "pod_overloaded",
+            "pod_id": target.pod_id,
+            "redirect_to": location,
+        }))
+}
+
+#[actix_web::get("/ws")]
 async fn websocket(
+    req: actix_web::HttpRequest,
+    stream: web::Payload,
+    params: web::Query<UpgradeParams>,
+    state: web::Data<AppState>,
+) -> Result<actix_web::HttpResponse, actix_web::Error> {
+    start_session(req, stream, params.into_inner(), state, None).await
+}
+
+/// Rota antiga, mantida para clientes existentes: o username do path só é
+/// aceito se for o mesmo do token
+#[actix_web::get("/ws/{username}")]
+async fn websocket_with_username(
     req: actix_web::HttpRequest,
     stream: web::Payload,
     username: web::Path<String>,
+    params: web::Query<UpgradeParams>,
+    state: web::Data<AppState>,
+) -> Result<actix_web::HttpResponse, actix_web::Error> {
+    start_session(req, stream, params.into_inner(), state, Some(username.into_inner())).await
+}
+
+async fn start_session(
+    req: actix_web::HttpRequest,
+    stream: web::Payload,
+    params: UpgradeParams,
     state: web::Data<AppState>,
+    claimed_username: Option<String>,
 ) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    let username = username.into_inner();
+    if state.is_draining() {
+        return Ok(HttpResponse::ServiceUnavailable().json(json!({
+            "error": "pod_draining",
+            "pod_id": state.pod_id,
+        })));
+    }
+
+    let Some(token) = bearer_token(&req, params.access_token.as_deref()) else {
+        warn!("Upgrade WebSocket sem token de acesso");
+        return Ok(HttpResponse::Unauthorized().json(json!({ "error": "missing_token" })));
+    };
+    let claims = match state.token_verifier.verify(&token) {
+        Ok(claims) => claims,
+        Err(e) => {
+            warn!("Token de acesso inválido no upgrade WebSocket: {}", e);
+            return Ok(HttpResponse::Unauthorized().json(json!({ "error": "invalid_token" })));
+        }
+    };
+    if claimed_username.as_ref().is_some_and(|claimed| claimed != &claims.sub) {
+        warn!("Token de {} usado para conectar como {:?}", claims.sub, claimed_username);
+        return Ok(HttpResponse::Forbidden().json(json!({ "error": "username_mismatch" })));
+    }
+
+    let username = claims.sub;
     info!("Nova conexão WebSocket solicitada para usuário: {}", username);
+
+    let offered_protocols = req.headers()
+        .get(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok());
+    let Some(version) = negotiate_version(offered_protocols, params.version) else {
+        warn!("Usuário {} pediu versões de protocolo não suportadas: {:?} / {:?}",
+              username, offered_protocols, params.version);
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "unsupported_version",
+            "supported_versions": SUPPORTED_VERSIONS,
+        })));
+    };
+    debug!("Protocolo v{} negociado para usuário: {}", version, username);
+
+    let redirected = params.redirect_token.as_deref()
+        .is_some_and(|token| state.redirect_signer.accepts(token, &username, &state.pod_id));
+    if params.redirect_token.is_some() && !redirected {
+        debug!("Marcador de redirecionamento inválido para {}, ignorado", username);
+    }
+    if !redirected
+        && let Some(target) = state.load_balancer.redirect_target(&state.pod_id, &username, &state.redirect_policy).await
+    {
+        return Ok(overloaded_response(&state, &username, target, &req));
+    }
     
-    let relay_id = state.relay_balancer.get_best_relay_for_user(&username).await
+    let relay_id = state.relay_balancer
+        .get_best_relay_for_user(&username, params.affinity.as_deref(), state.bus.as_deref()).await
         .ok_or_else(|| {
             error!("Nenhum relay disponível para usuário: {}", username);
             actix_web::error::ErrorInternalServerError("No relay available")
@@ -180,8 +449,17 @@ async fn websocket(
         })?;
 
     info!("Estabelecendo conexão WebSocket: usuário {} -> relay {}", username, relay_id);
-    let conn = WsConn::new(username, relay_addr);
-    actix_web_actors::ws::start(conn, &req, stream)
+    let resume = params.resume_token.map(|token| ResumeRequest {
+        token,
+        last_seq: params.last_seq.unwrap_or(0),
+        room_seqs: params.room_seqs.as_deref().map(parse_room_seqs).unwrap_or_default(),
+    });
+    let conn = WsConn::new(username, relay_addr, version, resume);
+    let protocol = subprotocol_name(version);
+    let protocols = [protocol.as_str()];
+    actix_web_actors::ws::WsResponseBuilder::new(conn, &req, stream)
+        .protocols(&protocols)
+        .start()
 }
 
 #[actix_web::get("/health")]
@@ -190,8 +468,10 @@ async fn health(state: actix_web::web::Data<AppState>) -> actix_web::HttpRespons
     let relay_stats = state.relay_balancer.get_relay_stats().await;
     let pod_stats = state.load_balancer.get_pod_stats().await;
 
+    let draining = state.is_draining();
+
     let response = json!({
-        "status": "healthy",
+        "status": if draining { "draining" } else { "healthy" },
         "pod_id": state.pod_id,
         "relays": relay_stats,
         "cluster_pods": pod_stats.len()
@@ -199,6 +479,10 @@ async fn health(state: actix_web::web::Data<AppState>) -> actix_web::HttpRespons
     
     debug!("Health check respondido: {} relays ativos, {} pods no cluster", 
            relay_stats.len(), pod_stats.len());
+    // Fora do balanceamento do Service enquanto drena
+    if draining {
+        return HttpResponse::ServiceUnavailable().json(response);
+    }
     HttpResponse::Ok().json(response)
 }
 
@@ -209,6 +493,9 @@ async fn metrics(state: actix_web::web::Data<AppState>) -> actix_web::HttpRespon
     state.relay_balancer.sync_metrics_from_relays().await;
     let relay_stats = state.relay_balancer.get_relay_stats().await;
     let pod_stats = state.load_balancer.get_pod_stats().await;
+    let persistence_stats = state.persistence.send(GetPersistenceStats).await.ok();
+    let bus_stats = state.bus.as_ref().map(|bus| bus.info());
+    let relay_mapping = state.relay_balancer.mapping_stats().await;
 
     let timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -218,6 +505,9 @@ async fn metrics(state: actix_web::web::Data<AppState>) -> actix_web::HttpRespon
     let response = json!({
         "pod_metrics": pod_stats,
         "relay_metrics": relay_stats,
+        "relay_mapping": relay_mapping,
+        "persistence": persistence_stats,
+        "bus": bus_stats,
         "timestamp": timestamp
     });
     
@@ -233,10 +523,15 @@ async fn get_relays(state: actix_web::web::Data<AppState>) -> actix_web::HttpRes
     state.relay_balancer.sync_metrics_from_relays().await;
     let relay_stats = state.relay_balancer.get_relay_stats().await;
     let active_relay_ids: Vec<_> = relay_stats.keys().collect();
+    let pool = match &state.relay_pool {
+        Some(relay_pool) => Some(relay_pool.status().await),
+        None => None,
+    };
 
     let response = json!({
         "active_relays": active_relay_ids,
         "detailed_stats": relay_stats,
+        "pool": pool,
         "pod_id": state.pod_id
     });
     
@@ -245,6 +540,82 @@ async fn get_relays(state: actix_web::web::Data<AppState>) -> actix_web::HttpRes
     HttpResponse::Ok().json(response)
 }
 
+fn pool_error_response(e: PoolError) -> HttpResponse {
+    let code = match e {
+        PoolError::AtMaxRelays(_) => "at_max_relays",
+        PoolError::AtMinRelays(_) => "at_min_relays",
+        PoolError::UnknownRelay(_) => "unknown_relay",
+    };
+    let body = json!({ "error": code, "message": e.to_string() });
+
+    match e {
+        PoolError::UnknownRelay(_) => HttpResponse::NotFound().json(body),
+        _ => HttpResponse::Conflict().json(body),
+    }
+}
+
+/// Resposta de erro se a requisição não traz o token de admin
+fn reject_non_admin(req: &actix_web::HttpRequest, state: &AppState) -> Option<HttpResponse> {
+    if is_admin(req, state.admin_token.as_deref()) {
+        return None;
+    }
+
+    warn!("Requisição administrativa sem token de admin válido");
+    Some(HttpResponse::Unauthorized().json(json!({ "error": "invalid_admin_token" })))
+}
+
+fn bus_unavailable() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(json!({ "error": "bus_unavailable" }))
+}
+
+#[actix_web::post("/admin/relays")]
+async fn admin_add_relay(req: actix_web::HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if let Some(response) = reject_non_admin(&req, &state) {
+        return response;
+    }
+    let Some(relay_pool) = &state.relay_pool else {
+        return bus_unavailable();
+    };
+
+    match relay_pool.spawn_relay(PoolTrigger::Admin, "pedido manual".to_string()).await {
+        Ok(relay_id) => {
+            info!("Pod {}: Relay {} adicionado manualmente", state.pod_id, relay_id);
+            HttpResponse::Created().json(json!({ "relay_id": relay_id }))
+        }
+        Err(e) => pool_error_response(e),
+    }
+}
+
+#[actix_web::delete("/admin/relays/{relay_id}")]
+async fn admin_remove_relay(
+    req: actix_web::HttpRequest,
+    relay_id: web::Path<u32>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    if let Some(response) = reject_non_admin(&req, &state) {
+        return response;
+    }
+    let Some(relay_pool) = &state.relay_pool else {
+        return bus_unavailable();
+    };
+
+    let relay_id = relay_id.into_inner();
+    // Métricas atualizadas para a drenagem escolher bem os destinos
+    state.relay_balancer.sync_metrics_from_relays().await;
+    match relay_pool.retire_relay(relay_id, PoolTrigger::Admin, "pedido manual".to_string()).await {
+        Ok(outcome) => {
+            info!("Pod {}: Remoção manual do relay {}: {:?}", state.pod_id, relay_id, outcome);
+            let body = json!({ "relay_id": relay_id, "outcome": outcome });
+            match outcome {
+                RetireOutcome::Retired => HttpResponse::Ok().json(body),
+                // A drenagem continua no ciclo de métricas
+                RetireOutcome::Draining { .. } => HttpResponse::Accepted().json(body),
+            }
+        }
+        Err(e) => pool_error_response(e),
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
@@ -256,17 +627,77 @@ async fn main() -> std::io::Result<()> {
     info!("Iniciando sistema de métricas...");
     app_state.start_metrics_updater().await;
 
+    let drain_config = DrainConfig::from_env();
+    debug!("Drenagem no encerramento: {:?}", drain_config);
+    let shutdown_state = app_state.clone();
+
     info!("Configurando servidor HTTP na porta 9002...");
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         info!("Configurando rotas da aplicação");
         App::new()
             .app_data(app_state.clone())
             .service(websocket)
+            .service(websocket_with_username)
             .service(health)
             .service(get_relays)
             .service(metrics)
+            .service(admin_add_relay)
+            .service(admin_remove_relay)
     })
+        // Os sinais são tratados abaixo, para drenar as conexões antes de parar
+        .disable_signals()
         .bind(("0.0.0.0", 9002))?
-        .run()
-        .await
-}
\ No newline at end of file
+        .run();
+
+    let server_handle = server.handle();
+    actix::spawn(async move {
+        shutdown::wait_for_signal().await;
+        info!("Pod {}: Encerrando, drenando conexões por até {:?}", shutdown_state.pod_id, drain_config.timeout);
+        shutdown_state.draining.store(true, Ordering::Relaxed);
+
+        let remaining = shutdown::drain_connections(&shutdown_state.relay_balancer, drain_config).await;
+        // As mensagens das sessões encerradas já estão na fila de persistência
+        let unpersisted = shutdown::flush_persistence(&shutdown_state.persistence, drain_config).await;
+        info!("Pod {}: Drenagem concluída ({} sessões e {} mensagens não gravadas restantes), parando o servidor",
+              shutdown_state.pod_id, remaining, unpersisted);
+        // Com sessões restantes, não espera por elas de novo
+        server_handle.stop(remaining == 0).await;
+    });
+
+    server.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_room_seqs_skips_malformed_pairs_and_invalid_rooms() {
+        let seqs = parse_room_seqs("general:12,dev:40,semseq,random:x,room:general:3,:5,bad room:1");
+
+        assert_eq!(seqs.len(), 2);
+        assert_eq!(seqs["general"], 12);
+        assert_eq!(seqs["dev"], 40);
+    }
+
+    #[test]
+    fn redirect_location_drops_access_token_and_adds_marker() {
+        let location = redirect_location(
+            "wss://pod-b.chat.example/",
+            "/ws",
+            "access_token=eyJ.secret.sig&version=2&redirect_token=old&affinity=dev",
+            "eyJ.marker.sig",
+        );
+
+        assert_eq!(location, "wss://pod-b.chat.example/ws?version=2&affinity=dev&redirect_token=eyJ.marker.sig");
+        assert!(!location.contains("access_token"));
+    }
+
+    #[test]
+    fn redirect_location_without_query_only_carries_marker() {
+        assert_eq!(
+            redirect_location("wss://pod-b.chat.example", "/ws/alice", "", "m"),
+            "wss://pod-b.chat.example/ws/alice?redirect_token=m"
+        );
+    }
+}
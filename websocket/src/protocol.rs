@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use crate::actors::{AckStatus, DEFAULT_ROOM};
+
+/// Versão usada quando o cliente não negocia nenhuma
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Versões que este servidor sabe falar, da mais nova para a mais antiga
+pub const SUPPORTED_VERSIONS: &[u16] = &[1];
+
+const SUBPROTOCOL_PREFIX: &str = "chat.v";
+
+/// Nome do subprotocolo WebSocket (`Sec-WebSocket-Protocol`) de uma versão, ex.: `chat.v1`
+pub fn subprotocol_name(version: u16) -> String {
+    format!("{}{}", SUBPROTOCOL_PREFIX, version)
+}
+
+/// Escolhe a versão do protocolo a partir do que o cliente ofereceu no
+/// upgrade: subprotocolos `chat.vN` em `Sec-WebSocket-Protocol` e/ou
+/// `?version=N` na query. Sem oferta, usa `PROTOCOL_VERSION`; com oferta mas
+/// nenhuma versão suportada, retorna `None`.
+pub fn negotiate_version(offered_protocols: Option<&str>, query_version: Option<u16>) -> Option<u16> {
+    let mut offered: Vec<u16> = offered_protocols
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|protocol| protocol.trim().strip_prefix(SUBPROTOCOL_PREFIX)?.parse().ok())
+        .collect();
+    offered.extend(query_version);
+
+    if offered.is_empty() {
+        return Some(PROTOCOL_VERSION);
+    }
+
+    SUPPORTED_VERSIONS.iter()
+        .copied()
+        .find(|version| offered.contains(version))
+}
+
+/// Envelope comum a todo frame de texto, nos dois sentidos:
+/// `{"type": "...", "version": 1, "id": "...", "payload": {...}}`.
+/// `id` é opcional e escolhido pelo cliente; respostas a um frame o repetem.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub version: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub frame: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(version: u16, id: Option<String>, frame: T) -> Self {
+        Self { version, id, frame }
+    }
+}
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+/// Frames enviados pelo cliente
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Message {
+        #[serde(default = "default_room")]
+        room_id: String,
+        content: String,
+    },
+    JoinRoom { room_id: String },
+    LeaveRoom { room_id: String },
+    DirectMessage { to: String, content: String },
+    /// Confirma o recebimento de um `message` ou `direct_message` do servidor;
+    /// mensagens sem ack são reenviadas quando o usuário reconecta
+    Ack { message_id: String },
+}
+
+/// Frames enviados pelo servidor
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// Primeiro frame da conexão, confirma usuário, sessão e versão negociada.
+    /// `session_id` também é o resume token para uma reconexão futura.
+    Welcome { username: String, session_id: String, version: u16 },
+    /// A sessão anterior foi retomada e as mensagens perdidas já foram reenviadas
+    Resumed { replayed: usize },
+    Message { message_id: String, seq: u64, username: String, room_id: String, content: String },
+    UserJoined { username: String },
+    UserLeft { username: String },
+    RoomJoined { username: String, room_id: String },
+    RoomLeft { username: String, room_id: String },
+    DirectMessage { message_id: String, from: String, to: String, content: String },
+    DirectQueued { message_id: String, to: String },
+    /// Resposta a um frame `message`/`direct_message` do cliente, com o mesmo `id`
+    Ack { message_id: String, status: AckStatus },
+    /// O destinatário `to` confirmou o recebimento da mensagem direta
+    Delivered { message_id: String, to: String },
+    Error { code: ErrorCode, message: String },
+    /// O pod está encerrando: o servidor fecha a conexão em `retry_after_ms`
+    /// e o cliente deve reconectar (o balanceador o manda a outro pod),
+    /// retomando a sessão com `resume_token`
+    Reconnect { reason: String, retry_after_ms: u64, resume_token: String },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedFrame,
+    UnsupportedVersion,
+    UnsupportedFrame,
+    InvalidRoom,
+    NotRoomMember,
+    /// O resume token expirou ou não pertence ao usuário; a conexão segue como nova
+    ResumeFailed,
+}
+
+impl ServerFrame {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerFrame::Error { code, message: message.into() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn negotiates_first_supported_version_offered() {
+        assert_eq!(subprotocol_name(1), "chat.v1");
+        assert_eq!(negotiate_version(None, None), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(Some("chat.v1"), None), Some(1));
+        assert_eq!(negotiate_version(Some("chat.v2, chat.v1"), None), Some(1));
+        assert_eq!(negotiate_version(None, Some(1)), Some(1));
+        // Subprotocolos que não são `chat.vN` não contam como oferta
+        assert_eq!(negotiate_version(Some("graphql-ws"), None), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn rejects_offers_without_a_supported_version() {
+        assert_eq!(negotiate_version(Some("chat.v2"), None), None);
+        assert_eq!(negotiate_version(None, Some(3)), None);
+        assert_eq!(negotiate_version(Some("chat.v2"), Some(3)), None);
+    }
+
+    #[test]
+    fn parses_client_envelopes() {
+        let envelope: Envelope<ClientFrame> = serde_json::from_value(json!({
+            "type": "message",
+            "version": 1,
+            "id": "c1",
+            "payload": { "content": "oi" },
+        })).unwrap();
+        assert_eq!(envelope.version, 1);
+        assert_eq!(envelope.id.as_deref(), Some("c1"));
+        match envelope.frame {
+            ClientFrame::Message { room_id, content } => {
+                assert_eq!(room_id, DEFAULT_ROOM);
+                assert_eq!(content, "oi");
+            }
+            frame => panic!("frame inesperado: {:?}", frame),
+        }
+
+        let ack: Envelope<ClientFrame> = serde_json::from_value(json!({
+            "type": "ack",
+            "version": 1,
+            "payload": { "message_id": "m1" },
+        })).unwrap();
+        assert!(ack.id.is_none());
+        assert!(matches!(ack.frame, ClientFrame::Ack { message_id } if message_id == "m1"));
+
+        let unknown = serde_json::from_value::<Envelope<ClientFrame>>(json!({
+            "type": "typing",
+            "version": 1,
+            "payload": {},
+        }));
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn serializes_server_envelopes() {
+        let ack = Envelope::new(1, Some("c1".to_string()), ServerFrame::Ack {
+            message_id: "m1".to_string(),
+            status: AckStatus::Persisted,
+        });
+        assert_eq!(serde_json::to_value(&ack).unwrap(), json!({
+            "type": "ack",
+            "version": 1,
+            "id": "c1",
+            "payload": { "message_id": "m1", "status": "persisted" },
+        }));
+
+        let error = Envelope::new(1, None, ServerFrame::error(ErrorCode::NotRoomMember, "fora da sala"));
+        assert_eq!(serde_json::to_value(&error).unwrap(), json!({
+            "type": "error",
+            "version": 1,
+            "payload": { "code": "not_room_member", "message": "fora da sala" },
+        }));
+    }
+}
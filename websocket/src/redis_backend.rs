@@ -0,0 +1,268 @@
+// src/redis_backend.rs
+use std::time::Duration;
+use redis::aio::{ConnectionLike, ConnectionManager, ConnectionManagerConfig};
+use redis::cluster::{ClusterClient, ClusterClientBuilder, ClusterConfig};
+use redis::cluster_async::ClusterConnection;
+use redis::{Client, Cmd, Pipeline, ProtocolVersion, PushInfo, RedisFuture, RedisResult, Value};
+use tokio::sync::mpsc;
+
+/// Timeouts das conexões multiplexadas; sem eles um nó travado segura os relays
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Teto da espera entre tentativas de reconexão, em ms; o padrão do cliente
+/// cresce até minutos entre uma tentativa e outra
+const MAX_RECONNECT_DELAY_MS: u64 = 1_000;
+
+/// Topologia do Redis, escolhida por `REDIS_MODE`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedisMode {
+    /// Instâncias independentes: canais e chaves são distribuídos por hash
+    /// entre elas e cada uma tem seu próprio Pub/Sub
+    Standalone,
+    /// Redis Cluster: o cliente roteia por slot e segue MOVED/ASK; o Pub/Sub
+    /// usa os comandos sharded (SPUBLISH/SSUBSCRIBE)
+    Cluster,
+}
+
+impl RedisMode {
+    pub fn from_env() -> Self {
+        match std::env::var("REDIS_MODE").as_deref() {
+            Ok("cluster") => RedisMode::Cluster,
+            Ok("standalone") | Err(_) => RedisMode::Standalone,
+            Ok(other) => {
+                eprintln!("REDIS_MODE desconhecido '{}', usando standalone", other);
+                RedisMode::Standalone
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RedisMode::Standalone => "standalone",
+            RedisMode::Cluster => "cluster",
+        }
+    }
+}
+
+/// Conexão multiplexada compartilhada pelos comandos; as duas variantes
+/// reconectam sozinhas
+#[derive(Clone)]
+pub enum RedisConnection {
+    Standalone(ConnectionManager),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Standalone(conn) => conn.req_packed_command(cmd),
+            RedisConnection::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Standalone(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Standalone(conn) => conn.get_db(),
+            RedisConnection::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+/// De onde saem as conexões dedicadas das assinaturas: um nó standalone ou
+/// o cluster inteiro
+#[derive(Clone)]
+pub enum RedisEndpoint {
+    Standalone(Client),
+    Cluster(ClusterClient),
+}
+
+impl RedisEndpoint {
+    /// Conexão fora da multiplexada compartilhada, para comandos bloqueantes
+    /// como `XREADGROUP BLOCK`
+    pub async fn dedicated_connection(&self, response_timeout: Duration) -> RedisResult<RedisConnection> {
+        match self {
+            RedisEndpoint::Standalone(client) => {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(CONNECTION_TIMEOUT)
+                    .set_response_timeout(response_timeout)
+                    .set_max_delay(MAX_RECONNECT_DELAY_MS);
+                let conn = ConnectionManager::new_with_config(client.clone(), config).await?;
+                Ok(RedisConnection::Standalone(conn))
+            }
+            RedisEndpoint::Cluster(client) => {
+                let config = ClusterConfig::new()
+                    .set_connection_timeout(CONNECTION_TIMEOUT)
+                    .set_response_timeout(response_timeout);
+                let conn = client.get_async_connection_with_config(config).await?;
+                Ok(RedisConnection::Cluster(conn))
+            }
+        }
+    }
+}
+
+/// Conecta a um nó standalone e confirma com PING
+pub async fn connect_standalone(url: &str) -> RedisResult<(RedisEndpoint, RedisConnection)> {
+    let client = Client::open(url)?;
+    let config = ConnectionManagerConfig::new()
+        .set_connection_timeout(CONNECTION_TIMEOUT)
+        .set_response_timeout(RESPONSE_TIMEOUT)
+        .set_number_of_retries(3)
+        .set_max_delay(MAX_RECONNECT_DELAY_MS);
+    let conn = ConnectionManager::new_with_config(client.clone(), config).await?;
+    let mut conn = RedisConnection::Standalone(conn);
+
+    ping(&mut conn).await?;
+    Ok((RedisEndpoint::Standalone(client), conn))
+}
+
+/// Conecta ao Redis Cluster a partir dos nós semente e confirma com PING.
+/// Usa RESP3, exigido pelas assinaturas sharded.
+pub async fn connect_cluster(seed_urls: &[&str]) -> RedisResult<(RedisEndpoint, RedisConnection)> {
+    let client = ClusterClientBuilder::new(seed_urls.to_vec())
+        .connection_timeout(CONNECTION_TIMEOUT)
+        .response_timeout(RESPONSE_TIMEOUT)
+        .retries(3)
+        .use_protocol(ProtocolVersion::RESP3)
+        .build()?;
+    let conn = client.get_async_connection().await?;
+    let mut conn = RedisConnection::Cluster(conn);
+
+    ping(&mut conn).await?;
+    Ok((RedisEndpoint::Cluster(client), conn))
+}
+
+/// Conexão de cluster que entrega as publicações das assinaturas em
+/// `push_sender`; o cliente refaz as assinaturas após reconectar
+pub async fn cluster_push_connection(
+    client: &ClusterClient,
+    push_sender: mpsc::UnboundedSender<PushInfo>,
+) -> RedisResult<ClusterConnection> {
+    let config = ClusterConfig::new()
+        .set_connection_timeout(CONNECTION_TIMEOUT)
+        .set_push_sender(push_sender);
+    client.get_async_connection_with_config(config).await
+}
+
+async fn ping(conn: &mut RedisConnection) -> RedisResult<()> {
+    let response: String = redis::cmd("PING").query_async(conn).await?;
+    if response != "PONG" {
+        return Err(redis::RedisError::from((
+            redis::ErrorKind::ResponseError,
+            "Resposta inesperada ao PING",
+            response
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Nó falso que responde +PONG ao PING e +OK ao resto; conta as conexões
+    async fn fake_node() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(serve(socket));
+            }
+        });
+        (url, connections)
+    }
+
+    async fn serve(mut socket: TcpStream) {
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 1024];
+        while let Ok(read) = socket.read(&mut chunk).await {
+            if read == 0 {
+                return;
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+            while let Some((command, consumed)) = parse_command(&buffer) {
+                buffer.drain(..consumed);
+                let reply: &[u8] = if command.eq_ignore_ascii_case("PING") { b"+PONG\r\n" } else { b"+OK\r\n" };
+                if socket.write_all(reply).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Nome do próximo comando RESP completo no buffer e quantos bytes ele ocupa
+    fn parse_command(buffer: &[u8]) -> Option<(String, usize)> {
+        let mut lines = Vec::new();
+        let mut position = 0;
+        let next_line = |position: &mut usize| {
+            let end = buffer[*position..].windows(2).position(|w| w == b"\r\n")? + *position;
+            let line = String::from_utf8_lossy(&buffer[*position..end]).to_string();
+            *position = end + 2;
+            Some(line)
+        };
+
+        let args: usize = next_line(&mut position)?.strip_prefix('*')?.parse().ok()?;
+        for _ in 0..args {
+            let len: usize = next_line(&mut position)?.strip_prefix('$')?.parse().ok()?;
+            if buffer.len() < position + len + 2 {
+                return None;
+            }
+            lines.push(String::from_utf8_lossy(&buffer[position..position + len]).to_string());
+            position += len + 2;
+        }
+        Some((lines.into_iter().next()?, position))
+    }
+
+    #[tokio::test]
+    async fn clones_share_one_multiplexed_connection() {
+        let (url, connections) = fake_node().await;
+        let (_endpoint, conn) = connect_standalone(&url).await.unwrap();
+
+        let pings = (0..20).map(|_| {
+            let mut conn = conn.clone();
+            tokio::spawn(async move { ping(&mut conn).await })
+        });
+        for ping in pings {
+            ping.await.unwrap().unwrap();
+        }
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unresponsive_node_fails_instead_of_hanging() {
+        // Aceita a conexão mas nunca responde
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        // 4 tentativas de até 2 s com pouco mais de 1 s entre elas, com o jitter
+        let result = tokio::time::timeout(Duration::from_secs(20), connect_standalone(&url)).await
+            .expect("a conexão deveria desistir pelos timeouts");
+        assert!(result.is_err());
+    }
+}
@@ -1,63 +1,145 @@
 // src/redis_cluster.rs
-use redis::{Client, Commands, Connection};
+use redis::AsyncCommands;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::actors::{RedisMessage, RedisMessageType};
-use std::time::Duration;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use async_trait::async_trait;
+use crate::actors::{DirectMessage, PendingDelivery, RedisMessage, RedisMessageType, ResumeState, UserMessage};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use crate::actors::persistence::env_or;
+use crate::bus::{
+    self, BusResult, MessageBus, Subscription, unix_now, OFFLINE_QUEUE_MAX_LEN, OFFLINE_QUEUE_TTL_SECS,
+    PENDING_DELIVERY_MAX_LEN, PENDING_DELIVERY_TTL_SECS, RESUME_TTL_SECS, ROOM_BUFFER_MAX_LEN,
+    ROOM_BUFFER_TTL_SECS, USER_LOCATION_TTL_SECS,
+};
+use crate::hash_ring::HashRing;
+use crate::redis_backend::{self, RedisConnection, RedisEndpoint, RedisMode};
+use crate::redis_transport::{self, RedisTransport};
+
+/// PINGs seguidos sem resposta até um nó sair do anel
+const NODE_FAILURE_THRESHOLD: u32 = 2;
+/// Valores de `seq` reservados por milissegundo no piso do contador
+const SEQ_PER_MS: u64 = 1024;
+
+const SESSIONS_LUA_PRELUDE: &str = r#"
+local function live_sessions(key, now, ttl)
+  local entries = redis.call('HGETALL', key)
+  local count = 0
+  for i = 1, #entries, 2 do
+    local seen = tonumber(string.match(entries[i + 1], ':(%d+)$'))
+    if seen and now - seen <= ttl then
+      count = count + 1
+    else
+      redis.call('HDEL', key, entries[i])
+    end
+  end
+  return count
+end
+local key_type = redis.call('TYPE', KEYS[1]).ok
+if key_type ~= 'hash' and key_type ~= 'none' then
+  redis.call('DEL', KEYS[1])
+end
+"#;
+
+// Próximo valor do contador, nunca abaixo do piso derivado do relógio: se a
+// chave mudar de nó (o dono saiu do anel, ou voltou com um valor antigo), a
+// contagem continua acima de tudo o que já foi atribuído
+// ARGV: piso (agora em ms * SEQ_PER_MS)
+static NEXT_SEQ_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| redis::Script::new(r#"
+local current = tonumber(redis.call('GET', KEYS[1]) or '0')
+local next_seq = math.max(current + 1, tonumber(ARGV[1]))
+redis.call('SET', KEYS[1], string.format('%.0f', next_seq))
+return next_seq
+"#));
+
+// ARGV: agora, ttl, session_id, "{pod}:{relay}"
+static ADD_SESSION_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| redis::Script::new(&format!("{}{}", SESSIONS_LUA_PRELUDE, r#"
+redis.call('HSET', KEYS[1], ARGV[3], ARGV[4] .. ':' .. ARGV[1])
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return live_sessions(KEYS[1], tonumber(ARGV[1]), tonumber(ARGV[2]))
+"#)));
+
+// ARGV: agora, ttl, session_id
+static REMOVE_SESSION_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| redis::Script::new(&format!("{}{}", SESSIONS_LUA_PRELUDE, r#"
+redis.call('HDEL', KEYS[1], ARGV[3])
+return live_sessions(KEYS[1], tonumber(ARGV[1]), tonumber(ARGV[2]))
+"#)));
+
+/// Um nó Redis (ou o cluster inteiro, em `REDIS_MODE=cluster`): o endpoint
+/// abre as conexões dedicadas das assinaturas e a conexão multiplexada
+/// atende os comandos
+#[derive(Clone)]
+struct RedisNode {
+    /// URL do nó; define as posições dele no anel
+    name: String,
+    endpoint: RedisEndpoint,
+    conn: RedisConnection,
+}
+
+/// Contadores dos comandos enviados ao Redis, expostos em `/metrics`
+#[derive(Default)]
+struct RedisStats {
+    commands: AtomicU64,
+    errors: AtomicU64,
+    total_latency_us: AtomicU64,
+}
 
+/// Acesso ao Redis compartilhado por todos os relays do pod. Clonar é barato:
+/// os clones usam as mesmas conexões multiplexadas.
 #[derive(Clone)]
 pub struct RedisClusterManager {
-    clients: Vec<Client>,
+    nodes: Vec<RedisNode>,
     pod_id: String,
-    channel_mapping: Arc<RwLock<HashMap<String, usize>>>,
-    is_cluster_mode: bool,
+    mode: RedisMode,
+    transport: RedisTransport,
+    stats: Arc<RedisStats>,
+    /// Nós saudáveis; canais e chaves de estado são distribuídos por hash
+    /// consistente
+    ring: Arc<RwLock<HashRing>>,
+    /// Incrementado a cada mudança do anel, para os relays reassinarem os
+    /// canais que mudaram de nó
+    ring_generation: Arc<AtomicU64>,
 }
 
 impl RedisClusterManager {
-    pub fn new() -> Result<Self, redis::RedisError> {
+    pub async fn new() -> Result<Self, redis::RedisError> {
         let redis_nodes = std::env::var("REDIS_CLUSTER_NODES")
             .unwrap_or_else(|_| "redis://redis-0.redis.default.svc.cluster.local:6379,redis://redis-1.redis.default.svc.cluster.local:6379,redis://redis-2.redis.default.svc.cluster.local:6379".to_string());
 
-        let node_urls: Vec<&str> = redis_nodes.split(',').collect();
-        let mut clients = Vec::new();
+        let mode = RedisMode::from_env();
+        let mut nodes = Vec::new();
+
+        if mode == RedisMode::Cluster {
+            // Os nós são apenas sementes: o cliente descobre a topologia e
+            // segue os redirecionamentos MOVED/ASK
+            let seed_urls: Vec<&str> = redis_nodes.split(',').map(str::trim).collect();
+            println!("Conectando ao Redis Cluster: {:?}", seed_urls);
+
+            let (endpoint, conn) = redis_backend::connect_cluster(&seed_urls).await?;
+            println!("✅ Conectado ao Redis Cluster");
+            nodes.push(RedisNode { name: "cluster".to_string(), endpoint, conn });
+        }
 
         // Tentar conectar a cada nó
-        for url in &node_urls {
+        for url in redis_nodes.split(',').filter(|_| mode == RedisMode::Standalone) {
             let url = url.trim();
             println!("Tentando conectar ao Redis: {}", url);
 
-            match Client::open(url) {
-                Ok(client) => {
-                    // Testar conexão
-                    match client.get_connection() {
-                        Ok(mut conn) => {
-                            match redis::cmd("PING").query::<String>(&mut conn) {
-                                Ok(response) if response == "PONG" => {
-                                    println!("✅ Conectado com sucesso: {}", url);
-                                    clients.push(client);
-                                }
-                                Ok(response) => {
-                                    println!("⚠️  Resposta inesperada de {}: {}", url, response);
-                                }
-                                Err(e) => {
-                                    println!("❌ Falha no PING para {}: {}", url, e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            println!("❌ Falha na conexão para {}: {}", url, e);
-                        }
-                    }
+            match redis_backend::connect_standalone(url).await {
+                Ok((endpoint, conn)) => {
+                    println!("✅ Conectado com sucesso: {}", url);
+                    nodes.push(RedisNode { name: url.to_string(), endpoint, conn });
                 }
                 Err(e) => {
-                    println!("❌ Falha ao criar cliente para {}: {}", url, e);
+                    println!("❌ Falha na conexão para {}: {}", url, e);
                 }
             }
         }
 
         // Se nenhum nó cluster funcionar, usar fallback
-        if clients.is_empty() {
+        if nodes.is_empty() {
             let fallback_urls = vec![
                 "redis://redis.default.svc.cluster.local:6379",
                 "redis://redis-service:6379",
@@ -68,233 +150,658 @@ impl RedisClusterManager {
 
             for fallback_url in fallback_urls {
                 println!("Tentando fallback: {}", fallback_url);
-                match Client::open(fallback_url) {
-                    Ok(client) => {
-                        match client.get_connection() {
-                            Ok(mut conn) => {
-                                if redis::cmd("PING").query::<String>(&mut conn).is_ok() {
-                                    println!("✅ Fallback funcionando: {}", fallback_url);
-                                    clients.push(client);
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                println!("❌ Fallback falhou {}: {}", fallback_url, e);
-                            }
-                        }
+                match redis_backend::connect_standalone(fallback_url).await {
+                    Ok((endpoint, conn)) => {
+                        println!("✅ Fallback funcionando: {}", fallback_url);
+                        nodes.push(RedisNode { name: fallback_url.to_string(), endpoint, conn });
+                        break;
                     }
                     Err(e) => {
-                        println!("❌ Erro no cliente fallback {}: {}", fallback_url, e);
+                        println!("❌ Fallback falhou {}: {}", fallback_url, e);
                     }
                 }
             }
         }
 
-        if clients.is_empty() {
+        if nodes.is_empty() {
             return Err(redis::RedisError::from((
                 redis::ErrorKind::IoError,
                 "Não foi possível conectar a nenhuma instância Redis"
             )));
         }
 
-        let pod_id = std::env::var("POD_NAME")
-            .unwrap_or_else(|_| format!("pod-{}", std::process::id()));
+        let pod_id = bus::pod_id_from_env();
 
-        let is_cluster_mode = clients.len() > 1;
+        let transport = RedisTransport::from_env();
+
+        let mut ring = HashRing::new(env_or("REDIS_RING_VNODES", 160));
+        for (index, node) in nodes.iter().enumerate() {
+            ring.add(index, &node.name);
+        }
 
         println!("🎯 Redis Manager inicializado:");
         println!("  📦 Pod ID: {}", pod_id);
-        println!("  🔗 Conexões: {}", clients.len());
-        println!("  🏗️  Modo cluster: {}", is_cluster_mode);
+        println!("  🔗 Conexões: {}", nodes.len());
+        println!("  🏗️  Modo: {}", mode.name());
+        println!("  📨 Transporte: {}", transport.name());
 
         Ok(Self {
-            clients,
+            nodes,
             pod_id,
-            channel_mapping: Arc::new(RwLock::new(HashMap::new())),
-            is_cluster_mode,
+            mode,
+            transport,
+            stats: Arc::new(RedisStats::default()),
+            ring: Arc::new(RwLock::new(ring)),
+            ring_generation: Arc::new(AtomicU64::new(0)),
         })
     }
 
-    // Particiona canais baseado em hash consistente
-    fn get_client_for_channel(&self, channel: &str) -> &Client {
-        if !self.is_cluster_mode || self.clients.len() == 1 {
-            return &self.clients[0];
+    /// Verifica periodicamente os nós (standalone com mais de um nó): um nó
+    /// que para de responder sai do anel e volta quando responde de novo.
+    /// Só os canais e chaves dos arcos dele mudam de dono; o estado que
+    /// estava nele (sessões, buffers, resume tokens) se perde, o dos outros
+    /// nós continua valendo.
+    pub fn start_membership_monitor(&self) {
+        if self.nodes.len() < 2 {
+            return;
         }
 
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
+        let manager = self.clone();
+        let interval = Duration::from_secs(env_or("REDIS_MEMBERSHIP_CHECK_SECS", 5));
 
-        let mut hasher = DefaultHasher::new();
-        channel.hash(&mut hasher);
-        let index = (hasher.finish() % self.clients.len() as u64) as usize;
+        tokio::spawn(async move {
+            let mut failures = vec![0u32; manager.nodes.len()];
 
-        &self.clients[index]
+            loop {
+                tokio::time::sleep(interval).await;
+
+                for (index, node) in manager.nodes.iter().enumerate() {
+                    let mut conn = node.conn.clone();
+                    let healthy = redis::cmd("PING").query_async::<String>(&mut conn).await
+                        .is_ok_and(|response| response == "PONG");
+                    failures[index] = if healthy { 0 } else { failures[index] + 1 };
+
+                    let mut ring = manager.ring.write().unwrap_or_else(|e| e.into_inner());
+                    let is_member = ring.contains(index);
+
+                    if healthy && !is_member {
+                        ring.add(index, &node.name);
+                        println!("✅ Nó Redis {} voltou ao anel", node.name);
+                    } else if is_member && failures[index] >= NODE_FAILURE_THRESHOLD && ring.len() > 1 {
+                        // O último nó nunca sai: sem nenhum, não há para onde rotear
+                        ring.remove(index);
+                        println!("❌ Nó Redis {} removido do anel", node.name);
+                    } else {
+                        continue;
+                    }
+
+                    manager.ring_generation.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
     }
 
-    pub async fn publish_message(
-        &self,
-        channel: &str,
-        from_relay_id: u32,
-        message_type: RedisMessageType,
-    ) -> Result<(), redis::RedisError> {
-        let message = RedisMessage {
-            from_pod_id: self.pod_id.clone(),
-            from_relay_id,
-            message_type,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-        };
+    // Particiona canais e chaves de estado pelo anel de hash consistente
+    fn get_node_for_key(&self, key: &str) -> &RedisNode {
+        self.get_node_index_for_key(key).1
+    }
 
-        let payload = serde_json::to_string(&message)
-            .map_err(|e| redis::RedisError::from((
-                redis::ErrorKind::TypeError,
-                "Serialization",
-                e.to_string()
-            )))?;
+    fn get_node_index_for_key(&self, key: &str) -> (usize, &RedisNode) {
+        // Em modo cluster há um único "nó" e o cliente roteia por slot
+        if self.nodes.len() == 1 {
+            return (0, &self.nodes[0]);
+        }
 
-        let client = self.get_client_for_channel(channel);
+        let ring = self.ring.read().unwrap_or_else(|e| e.into_inner());
+        let index = ring.get(key).unwrap_or(0);
 
-        // Usar blocking task para evitar problemas de async
-        let client = client.clone();
-        let channel = channel.to_string();
+        (index, &self.nodes[index])
+    }
 
-        tokio::task::spawn_blocking(move || {
-            let mut conn = client.get_connection()?;
-            let _: () = conn.publish(channel, payload)?;
-            Ok::<_, redis::RedisError>(())
-        })
-            .await
-            .map_err(|e| redis::RedisError::from((
-                redis::ErrorKind::IoError,
-                "Task",
-                e.to_string()
-            )))??;
+    /// Conexão multiplexada do nó responsável pelo canal ou pela chave
+    fn conn_for(&self, key: &str) -> RedisConnection {
+        self.get_node_for_key(key).conn.clone()
+    }
 
-        Ok(())
+    /// Grupo de pipeline de uma chave: no modo cluster um pipeline só pode
+    /// tocar um slot, no standalone um nó
+    fn pipeline_group_for(&self, key: &str) -> (usize, RedisConnection) {
+        let (index, node) = self.get_node_index_for_key(key);
+        (pipeline_group(self.mode, index, key), node.conn.clone())
     }
 
-    pub async fn subscribe_to_channel(&self, channel: &str) -> Result<tokio::sync::mpsc::UnboundedReceiver<RedisMessage>, redis::RedisError> {
-        let client = self.get_client_for_channel(channel).clone();
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        let pod_id = self.pod_id.clone();
-        let channel = channel.to_string();
+    /// Próxima `seq` do contador `key` (ver `NEXT_SEQ_SCRIPT`)
+    async fn next_seq(&self, key: &str) -> BusResult<u64> {
+        let mut conn = self.conn_for(key);
+        let floor = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64 * SEQ_PER_MS;
 
-        tokio::spawn(async move {
-            loop {
-                match client.get_async_pubsub().await {
-                    Ok(mut pubsub) => {
-                        if let Ok(_) = pubsub.subscribe(&channel).await {
-                            println!("Conectado ao canal Redis: {}", channel);
-
-                            use futures_util::StreamExt;
-                            let mut stream = pubsub.into_on_message();
-
-                            while let Some(msg) = stream.next().await {
-                                if let Ok(payload) = msg.get_payload::<String>() {
-                                    if let Ok(redis_message) = serde_json::from_str::<RedisMessage>(&payload) {
-                                        if redis_message.from_pod_id != pod_id {
-                                            if tx.send(redis_message).is_err() {
-                                                println!("Canal fechado para {}", channel);
-                                                return;
-                                            }
-                                        }
-                                    }
-                                }
+        self.timed(NEXT_SEQ_SCRIPT.key(key).arg(floor).invoke_async::<u64>(&mut conn)).await
+    }
+
+    /// Executa um comando contabilizando latência e erros
+    async fn timed<T, F>(&self, command: F) -> BusResult<T>
+    where
+        F: Future<Output = Result<T, redis::RedisError>>,
+    {
+        let start = Instant::now();
+        let result = command.await;
+
+        self.stats.commands.fetch_add(1, Ordering::Relaxed);
+        self.stats.total_latency_us.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if result.is_err() {
+            self.stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(result?)
+    }
+
+    /// Encaminha uma publicação recebida; retorna `false` se `tx` fechou
+    fn forward_payload<F>(payload: Option<String>, tx: &mpsc::UnboundedSender<RedisMessage>, is_own: &F) -> bool
+    where
+        F: Fn(&RedisMessage) -> bool,
+    {
+        let Some(redis_message) = payload
+            .and_then(|payload| serde_json::from_str::<RedisMessage>(&payload).ok()) else {
+            return true;
+        };
+
+        is_own(&redis_message) || tx.send(redis_message).is_ok()
+    }
+
+    async fn consume_pubsub<F>(client: redis::Client, channel: String, tx: mpsc::UnboundedSender<RedisMessage>, is_own: F)
+    where
+        F: Fn(&RedisMessage) -> bool,
+    {
+        loop {
+            match client.get_async_pubsub().await {
+                Ok(mut pubsub) => {
+                    if pubsub.subscribe(&channel).await.is_ok() {
+                        println!("Conectado ao canal Redis: {}", channel);
+
+                        use futures_util::StreamExt;
+                        let mut stream = pubsub.into_on_message();
+
+                        while let Some(msg) = stream.next().await {
+                            if !Self::forward_payload(msg.get_payload::<String>().ok(), &tx, &is_own) {
+                                println!("Canal fechado para {}", channel);
+                                return;
                             }
                         }
                     }
-                    Err(e) => {
-                        eprintln!("Erro na conexão Redis para {}: {}", channel, e);
-                    }
                 }
+                Err(e) => {
+                    eprintln!("Erro na conexão Redis para {}: {}", channel, e);
+                }
+            }
+
+            // Reconectar após 3 segundos em caso de erro
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            println!("Tentando reconectar ao Redis para canal: {}", channel);
+        }
+    }
+
+    /// Assinatura via SSUBSCRIBE: o canal vive no shard dono do seu slot, e o
+    /// cliente de cluster refaz a assinatura quando o slot muda de nó
+    async fn consume_sharded_pubsub<F>(
+        client: redis::cluster::ClusterClient,
+        channel: String,
+        tx: mpsc::UnboundedSender<RedisMessage>,
+        is_own: F,
+    ) where
+        F: Fn(&RedisMessage) -> bool,
+    {
+        loop {
+            let (push_tx, mut push_rx) = mpsc::unbounded_channel();
+
+            match redis_backend::cluster_push_connection(&client, push_tx).await {
+                Ok(mut conn) => {
+                    if conn.ssubscribe(&channel).await.is_ok() {
+                        println!("Conectado ao canal Redis (sharded): {}", channel);
+
+                        while let Some(push) = push_rx.recv().await {
+                            if push.kind != redis::PushKind::SMessage {
+                                continue;
+                            }
 
-                // Reconectar após 3 segundos em caso de erro
-                tokio::time::sleep(Duration::from_secs(3)).await;
-                println!("Tentando reconectar ao Redis para canal: {}", channel);
+                            let payload = push.data.get(1)
+                                .and_then(|value| redis::from_redis_value::<String>(value).ok());
+                            if !Self::forward_payload(payload, &tx, &is_own) {
+                                println!("Canal fechado para {}", channel);
+                                return;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Erro na conexão Redis Cluster para {}: {}", channel, e);
+                }
             }
-        });
 
-        Ok(rx)
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            println!("Tentando reconectar ao Redis Cluster para canal: {}", channel);
+        }
     }
+}
 
-    // Implementar circuit breaker para tolerância a falhas
-    pub async fn publish_with_fallback(
+/// Chaves do mesmo grupo podem ir num único pipeline: no cluster, as do
+/// mesmo slot; no standalone, as do mesmo nó (`node_index`)
+fn pipeline_group(mode: RedisMode, node_index: usize, key: &str) -> usize {
+    match mode {
+        RedisMode::Cluster => redis::cluster_routing::get_slot(key.as_bytes()) as usize,
+        RedisMode::Standalone => node_index,
+    }
+}
+
+#[async_trait]
+impl MessageBus for RedisClusterManager {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn publish(
         &self,
-        primary_channel: &str,
-        fallback_channel: &str,
+        channel: &str,
         from_relay_id: u32,
         message_type: RedisMessageType,
-    ) -> Result<(), redis::RedisError> {
-        match self.publish_message(primary_channel, from_relay_id, message_type.clone()).await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                eprintln!("Falha no canal primário {}, tentando fallback {}: {}",
-                          primary_channel, fallback_channel, e);
-                self.publish_message(fallback_channel, from_relay_id, message_type).await
+    ) -> BusResult<()> {
+        let message = RedisMessage {
+            from_pod_id: self.pod_id.clone(),
+            from_relay_id,
+            message_type,
+            timestamp: unix_now(),
+        };
+
+        let payload = serde_json::to_string(&message)?;
+        let mut conn = self.conn_for(channel);
+
+        self.timed(async {
+            match &self.transport {
+                RedisTransport::PubSub if self.mode == RedisMode::Cluster => {
+                    redis::cmd("SPUBLISH").arg(channel).arg(payload).query_async::<()>(&mut conn).await
+                }
+                RedisTransport::PubSub => conn.publish::<_, _, ()>(channel, payload).await,
+                RedisTransport::Streams(config) => redis_transport::xadd(&mut conn, channel, &payload, config).await,
             }
+        }).await?;
+        Ok(())
+    }
+
+    fn pod_id(&self) -> &str {
+        &self.pod_id
+    }
+
+    fn subscribe(
+        &self,
+        channel: &str,
+        relay_id: u32,
+        tx: mpsc::UnboundedSender<RedisMessage>,
+    ) -> Subscription {
+        let (node_index, node) = self.get_node_index_for_key(channel);
+        let node = node.clone();
+        let channel = channel.to_string();
+        let pod_id = self.pod_id.clone();
+        let is_own = move |message: &RedisMessage| {
+            message.from_pod_id == pod_id && message.from_relay_id == relay_id
+        };
+
+        if let RedisTransport::Streams(config) = &self.transport {
+            let group = redis_transport::consumer_group(&self.pod_id, relay_id);
+            let task = tokio::spawn(redis_transport::consume_stream(
+                node.endpoint,
+                channel.clone(),
+                group.clone(),
+                config.clone(),
+                tx,
+                is_own,
+            ));
+
+            // Com streams, `close` também remove o consumer group do relay
+            let mut conn = node.conn;
+            return Subscription::new(channel.clone(), node_index, task).on_close(move || {
+                tokio::spawn(async move {
+                    if let Err(e) = redis_transport::destroy_group(&mut conn, &channel, &group).await {
+                        eprintln!("Falha ao remover consumer group {} de {}: {}", group, channel, e);
+                    }
+                });
+            });
         }
+
+        let task = match node.endpoint {
+            RedisEndpoint::Standalone(client) => tokio::spawn(Self::consume_pubsub(client, channel.clone(), tx, is_own)),
+            RedisEndpoint::Cluster(client) => tokio::spawn(Self::consume_sharded_pubsub(client, channel.clone(), tx, is_own)),
+        };
+
+        Subscription::new(channel, node_index, task)
+    }
+
+    /// Versão atual do anel; muda sempre que um nó entra ou sai
+    fn topology_generation(&self) -> u64 {
+        self.ring_generation.load(Ordering::Relaxed)
+    }
+
+    /// Se o canal da assinatura passou para outro nó desde que ela foi feita
+    fn subscription_moved(&self, subscription: &Subscription) -> bool {
+        self.get_node_index_for_key(subscription.channel()).0 != subscription.node()
     }
 
     // Health check das conexões
-    pub async fn health_check(&self) -> bool {
-        let client = &self.clients[0]; // Testar pelo menos uma conexão
-
-        let client = client.clone();
-        match tokio::task::spawn_blocking(move || {
-            let mut conn = client.get_connection()?;
-            redis::cmd("PING").query::<String>(&mut conn)
-        }).await {
-            Ok(Ok(response)) => response == "PONG",
-            _ => false,
+    async fn health_check(&self) -> bool {
+        // Basta um nó do anel responder: as chaves dos outros passam para ele
+        let members: Vec<RedisConnection> = {
+            let ring = self.ring.read().unwrap_or_else(|e| e.into_inner());
+            self.nodes.iter().enumerate()
+                .filter(|(index, _)| self.nodes.len() == 1 || ring.contains(*index))
+                .map(|(_, node)| node.conn.clone())
+                .collect()
+        };
+
+        for mut conn in members {
+            let response = self.timed(redis::cmd("PING").query_async::<String>(&mut conn)).await;
+            if response.is_ok_and(|response| response == "PONG") {
+                return true;
+            }
         }
+        false
     }
 
-    pub async fn set_user_location(&self, username: &str, relay_id: u32) -> Result<(), redis::RedisError> {
-        let client = self.get_client_for_channel(&format!("user:{}", username)).clone();
+    /// Registra uma sessão em `user_location:{username}` e retorna quantas
+    /// sessões vivas o usuário tem em todo o cluster (incluindo esta)
+    async fn add_user_session(&self, username: &str, session_id: &str, relay_id: u32) -> BusResult<usize> {
         let key = format!("user_location:{}", username);
-        let value = format!("{}:{}", self.pod_id, relay_id);
+        let mut conn = self.conn_for(&key);
+        let location = format!("{}:{}", self.pod_id, relay_id);
+
+        self.timed(ADD_SESSION_SCRIPT
+            .key(key)
+            .arg(unix_now())
+            .arg(USER_LOCATION_TTL_SECS)
+            .arg(session_id)
+            .arg(location)
+            .invoke_async::<usize>(&mut conn)).await
+    }
 
-        tokio::task::spawn_blocking(move || {
-            let mut conn = client.get_connection()?;
-            let _: () = conn.set_ex(key, value, 300)?;
-            Ok::<_, redis::RedisError>(())
-        })
-            .await
-            .map_err(|e| redis::RedisError::from((
-                redis::ErrorKind::IoError,
-                "Task",
-                e.to_string()
-            )))??;
+    /// Remove uma sessão e retorna quantas sessões vivas o usuário ainda tem
+    async fn remove_user_session(&self, username: &str, session_id: &str) -> BusResult<usize> {
+        let key = format!("user_location:{}", username);
+        let mut conn = self.conn_for(&key);
+
+        self.timed(REMOVE_SESSION_SCRIPT
+            .key(key)
+            .arg(unix_now())
+            .arg(USER_LOCATION_TTL_SECS)
+            .arg(session_id)
+            .invoke_async::<usize>(&mut conn)).await
+    }
+
+    /// Renova todas as sessões `(username, session_id)` de um relay num único pipeline por nó
+    async fn refresh_user_sessions(&self, sessions: Vec<(String, String)>, relay_id: u32) -> BusResult<()> {
+        let value = format!("{}:{}:{}", self.pod_id, relay_id, unix_now());
+
+        let mut pipes: HashMap<usize, (RedisConnection, redis::Pipeline)> = HashMap::new();
+        for (username, session_id) in sessions {
+            let key = format!("user_location:{}", username);
+            let (group, conn) = self.pipeline_group_for(&key);
+            pipes.entry(group)
+                .or_insert_with(|| (conn, redis::pipe()))
+                .1
+                .hset(&key, session_id, &value).ignore()
+                .expire(&key, USER_LOCATION_TTL_SECS as i64).ignore();
+        }
+
+        for (_, (mut conn, pipe)) in pipes {
+            self.timed(pipe.query_async::<()>(&mut conn)).await?;
+        }
 
         Ok(())
     }
 
-    pub async fn remove_user_location(&self, username: &str) -> Result<(), redis::RedisError> {
-        let client = self.get_client_for_channel(&format!("user:{}", username)).clone();
+    /// Um único HSET com todos os campos, aplicado de uma vez pelo Redis
+    async fn move_user_sessions(&self, username: &str, session_ids: Vec<String>, relay_id: u32) -> BusResult<()> {
+        if session_ids.is_empty() {
+            return Ok(());
+        }
+
         let key = format!("user_location:{}", username);
+        let mut conn = self.conn_for(&key);
+        let value = format!("{}:{}:{}", self.pod_id, relay_id, unix_now());
+        let fields: Vec<(String, String)> = session_ids.into_iter()
+            .map(|session_id| (session_id, value.clone()))
+            .collect();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(&key, &fields).ignore()
+            .expire(&key, USER_LOCATION_TTL_SECS as i64).ignore();
+        self.timed(pipe.query_async::<()>(&mut conn)).await
+    }
 
-        tokio::task::spawn_blocking(move || {
-            let mut conn = client.get_connection()?;
-            let _: () = conn.del(key)?;
-            Ok::<_, redis::RedisError>(())
-        })
-            .await
-            .map_err(|e| redis::RedisError::from((
-                redis::ErrorKind::IoError,
-                "Task",
-                e.to_string()
-            )))??;
+    /// Retorna os `(pod_id, relay_id)` distintos onde o usuário tem sessões vivas
+    async fn get_user_locations(&self, username: &str) -> BusResult<Vec<(String, u32)>> {
+        let key = format!("user_location:{}", username);
+        let mut conn = self.conn_for(&key);
+
+        let values: Vec<String> = self.timed(conn.hvals(key)).await?;
+
+        let now = unix_now();
+        let mut locations: Vec<(String, u32)> = values.iter()
+            .filter_map(|value| {
+                // Formato: "{pod_id}:{relay_id}:{visto_em}"
+                let mut parts = value.rsplitn(3, ':');
+                let seen_at: u64 = parts.next()?.parse().ok()?;
+                let relay_id = parts.next()?.parse().ok()?;
+                let pod_id = parts.next()?;
+                (now.saturating_sub(seen_at) <= USER_LOCATION_TTL_SECS).then(|| (pod_id.to_string(), relay_id))
+            })
+            .collect();
+        locations.sort();
+        locations.dedup();
+
+        Ok(locations)
+    }
+
+    /// Guarda uma mensagem direta para entrega quando o destinatário se conectar
+    async fn push_offline_message(&self, message: &DirectMessage) -> BusResult<()> {
+        let key = format!("offline_messages:{}", message.to);
+        let mut conn = self.conn_for(&key);
+        let payload = serde_json::to_string(message)?;
+
+        self.timed(redis::pipe()
+            .atomic()
+            .rpush(&key, payload).ignore()
+            .ltrim(&key, -(OFFLINE_QUEUE_MAX_LEN as isize), -1).ignore()
+            .expire(&key, OFFLINE_QUEUE_TTL_SECS as i64).ignore()
+            .query_async::<()>(&mut conn)).await
+    }
+
+    /// Remove e retorna as mensagens diretas pendentes de um usuário
+    async fn take_offline_messages(&self, username: &str) -> BusResult<Vec<DirectMessage>> {
+        let key = format!("offline_messages:{}", username);
+        let mut conn = self.conn_for(&key);
+
+        let (payloads,): (Vec<String>,) = self.timed(redis::pipe()
+            .atomic()
+            .lrange(&key, 0, -1)
+            .del(&key).ignore()
+            .query_async(&mut conn)).await?;
+
+        Ok(payloads.iter()
+            .filter_map(|payload| serde_json::from_str(payload).ok())
+            .collect())
+    }
+
+    /// Atribui a `seq` da mensagem no contador da sala e a guarda no buffer
+    /// da sala. O contador não expira: a `seq` nunca volta atrás na sala
+    async fn buffer_room_message(&self, mut message: UserMessage) -> BusResult<UserMessage> {
+        let key = format!("room_buffer:{}", message.room_id);
+        let mut conn = self.conn_for(&key);
+
+        message.seq = self.next_seq(&format!("room_seq:{}", message.room_id)).await?;
+        let payload = serde_json::to_string(&message)?;
+
+        self.timed(redis::pipe()
+            .cmd("XADD").arg(&key).arg("MAXLEN").arg("~").arg(ROOM_BUFFER_MAX_LEN)
+            .arg("*").arg("payload").arg(payload).ignore()
+            .expire(&key, ROOM_BUFFER_TTL_SECS as i64).ignore()
+            .query_async::<()>(&mut conn)).await?;
+
+        Ok(message)
+    }
+
+    /// Mensagens ainda no buffer da sala com `seq` maior que `after_seq`
+    async fn read_room_buffer(&self, room_id: &str, after_seq: u64) -> BusResult<Vec<UserMessage>> {
+        let key = format!("room_buffer:{}", room_id);
+        let mut conn = self.conn_for(&key);
+
+        let reply: redis::streams::StreamRangeReply = self.timed(conn.xrange_all(key)).await?;
+
+        Ok(reply.ids.iter()
+            .filter_map(|entry| entry.get::<String>("payload"))
+            .filter_map(|payload| serde_json::from_str::<UserMessage>(&payload).ok())
+            .filter(|message| message.seq > after_seq)
+            .collect())
+    }
+
+    /// Grava o estado de retomada de várias sessões (`session_id -> estado`)
+    async fn save_resume_states(&self, states: Vec<(String, ResumeState)>) -> BusResult<()> {
+        let mut pipes: HashMap<usize, (RedisConnection, redis::Pipeline)> = HashMap::new();
+        for (session_id, state) in states {
+            let payload = serde_json::to_string(&state)?;
+            let key = format!("resume:{}", session_id);
+            let (group, conn) = self.pipeline_group_for(&key);
+            pipes.entry(group)
+                .or_insert_with(|| (conn, redis::pipe()))
+                .1
+                .set_ex(key, payload, RESUME_TTL_SECS).ignore();
+        }
+
+        for (_, (mut conn, pipe)) in pipes {
+            self.timed(pipe.query_async::<()>(&mut conn)).await?;
+        }
 
         Ok(())
     }
 
-    pub fn get_cluster_info(&self) -> HashMap<String, String> {
+    /// Lê e invalida um resume token; cada token só pode ser usado uma vez
+    async fn take_resume_state(&self, token: &str) -> BusResult<Option<ResumeState>> {
+        let key = format!("resume:{}", token);
+        let mut conn = self.conn_for(&key);
+
+        let payload: Option<String> = self.timed(conn.get_del(key)).await?;
+
+        Ok(payload.and_then(|payload| serde_json::from_str(&payload).ok()))
+    }
+
+    /// Guarda entregas não confirmadas de uma sessão encerrada
+    async fn push_pending_deliveries(&self, username: &str, deliveries: &[PendingDelivery]) -> BusResult<()> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        let key = format!("pending_delivery:{}", username);
+        let mut conn = self.conn_for(&key);
+        let payloads = deliveries.iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.timed(redis::pipe()
+            .atomic()
+            .rpush(&key, payloads).ignore()
+            .ltrim(&key, -(PENDING_DELIVERY_MAX_LEN as isize), -1).ignore()
+            .expire(&key, PENDING_DELIVERY_TTL_SECS as i64).ignore()
+            .query_async::<()>(&mut conn)).await
+    }
+
+    /// Remove e retorna as entregas não confirmadas de um usuário, na ordem original
+    async fn take_pending_deliveries(&self, username: &str) -> BusResult<Vec<PendingDelivery>> {
+        let key = format!("pending_delivery:{}", username);
+        let mut conn = self.conn_for(&key);
+
+        let (payloads,): (Vec<String>,) = self.timed(redis::pipe()
+            .atomic()
+            .lrange(&key, 0, -1)
+            .del(&key).ignore()
+            .query_async(&mut conn)).await?;
+
+        Ok(payloads.iter()
+            .filter_map(|payload| serde_json::from_str(payload).ok())
+            .collect())
+    }
+
+    fn info(&self) -> HashMap<String, String> {
+        let commands = self.stats.commands.load(Ordering::Relaxed);
+        let total_latency_us = self.stats.total_latency_us.load(Ordering::Relaxed);
+        let avg_latency_us = total_latency_us.checked_div(commands).unwrap_or(0);
+
         let mut info = HashMap::new();
         info.insert("pod_id".to_string(), self.pod_id.clone());
-        info.insert("client_count".to_string(), self.clients.len().to_string());
-        info.insert("is_cluster_mode".to_string(), self.is_cluster_mode.to_string());
+        info.insert("backend".to_string(), self.name().to_string());
+        info.insert("client_count".to_string(), self.nodes.len().to_string());
+        info.insert("mode".to_string(), self.mode.name().to_string());
+        info.insert("ring_members".to_string(), self.ring.read().unwrap_or_else(|e| e.into_inner()).len().to_string());
+        info.insert("ring_generation".to_string(), self.topology_generation().to_string());
+        info.insert("transport".to_string(), self.transport.name().to_string());
+        info.insert("commands".to_string(), commands.to_string());
+        info.insert("command_errors".to_string(), self.stats.errors.load(Ordering::Relaxed).to_string());
+        info.insert("avg_command_latency_us".to_string(), avg_latency_us.to_string());
         info
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(from_relay_id: u32) -> String {
+        serde_json::to_string(&RedisMessage {
+            from_pod_id: "pod-a".to_string(),
+            from_relay_id,
+            message_type: RedisMessageType::RelayHeartbeat { relay_id: from_relay_id, active_connections: 0 },
+            timestamp: 0,
+        }).unwrap()
+    }
+
+    #[test]
+    fn cluster_pipelines_are_grouped_by_slot() {
+        // Valor de referência do CLUSTER KEYSLOT; com hash tag só ela conta
+        assert_eq!(pipeline_group(RedisMode::Cluster, 0, "foo"), 12182);
+        assert_eq!(
+            pipeline_group(RedisMode::Cluster, 0, "{user1000}.following"),
+            pipeline_group(RedisMode::Cluster, 0, "user1000"),
+        );
+
+        // Hash tags iguais caem no mesmo slot, chaves diferentes não precisam
+        assert_eq!(
+            pipeline_group(RedisMode::Cluster, 0, "{alice}:a"),
+            pipeline_group(RedisMode::Cluster, 0, "{alice}:b"),
+        );
+        assert_ne!(
+            pipeline_group(RedisMode::Cluster, 0, "user_location:alice"),
+            pipeline_group(RedisMode::Cluster, 0, "user_location:bob"),
+        );
+    }
+
+    #[test]
+    fn standalone_pipelines_are_grouped_by_node() {
+        assert_eq!(pipeline_group(RedisMode::Standalone, 2, "user_location:alice"), 2);
+        assert_eq!(pipeline_group(RedisMode::Standalone, 2, "user_location:bob"), 2);
+    }
+
+    #[test]
+    fn forwards_publications_from_other_relays_only() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let is_own = |message: &RedisMessage| message.from_relay_id == 1;
+
+        assert!(RedisClusterManager::forward_payload(Some(heartbeat(1)), &tx, &is_own));
+        assert!(RedisClusterManager::forward_payload(Some("{".to_string()), &tx, &is_own));
+        assert!(RedisClusterManager::forward_payload(None, &tx, &is_own));
+        assert!(RedisClusterManager::forward_payload(Some(heartbeat(2)), &tx, &is_own));
+        assert_eq!(rx.try_recv().unwrap().from_relay_id, 2);
+        assert!(rx.try_recv().is_err());
+
+        // Sem receptor a assinatura termina
+        drop(rx);
+        assert!(!RedisClusterManager::forward_payload(Some(heartbeat(2)), &tx, &is_own));
+    }
+}
@@ -0,0 +1,290 @@
+// src/redis_transport.rs
+use std::time::Duration;
+use redis::AsyncCommands;
+use redis::streams::{StreamReadOptions, StreamReadReply};
+use tokio::sync::mpsc;
+use crate::actors::RedisMessage;
+use crate::actors::persistence::env_or;
+use crate::redis_backend::{RedisConnection, RedisEndpoint};
+
+/// Como as mensagens entre relays trafegam pelo Redis, escolhido por `REDIS_TRANSPORT`
+#[derive(Debug, Clone)]
+pub enum RedisTransport {
+    /// PUBLISH/SUBSCRIBE: sem estado no Redis, mas o que for publicado enquanto
+    /// um assinante reconecta se perde
+    PubSub,
+    /// Um stream por canal (XADD), lido por um consumer group por relay
+    /// (XREADGROUP); quem reconecta continua de onde parou
+    Streams(StreamsConfig),
+}
+
+impl RedisTransport {
+    pub fn from_env() -> Self {
+        match std::env::var("REDIS_TRANSPORT").as_deref() {
+            Ok("streams") => RedisTransport::Streams(StreamsConfig::from_env()),
+            Ok("pubsub") | Err(_) => RedisTransport::PubSub,
+            Ok(other) => {
+                eprintln!("REDIS_TRANSPORT desconhecido '{}', usando pubsub", other);
+                RedisTransport::PubSub
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RedisTransport::PubSub => "pubsub",
+            RedisTransport::Streams(_) => "streams",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamsConfig {
+    /// Tamanho aproximado máximo de cada stream (`XADD MAXLEN ~`)
+    pub max_len: usize,
+    /// Streams sem publicações por este tempo expiram (inboxes de relays que sumiram)
+    pub ttl_secs: i64,
+    /// Quanto cada `XREADGROUP` espera por novas entradas
+    pub block: Duration,
+    /// Entradas lidas por chamada
+    pub batch_size: usize,
+}
+
+impl StreamsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            max_len: env_or("REDIS_STREAM_MAXLEN", 10_000),
+            ttl_secs: env_or("REDIS_STREAM_TTL_SECS", 24 * 3600),
+            block: Duration::from_millis(env_or("REDIS_STREAM_BLOCK_MS", 5_000)),
+            batch_size: env_or("REDIS_STREAM_BATCH_SIZE", 100),
+        }
+    }
+}
+
+/// Stream que substitui o canal Pub/Sub de mesmo nome
+pub fn stream_key(channel: &str) -> String {
+    format!("stream:{}", channel)
+}
+
+/// Consumer group (e consumer) de um relay; cada relay tem o seu, então
+/// todos recebem todas as entradas, como no Pub/Sub
+pub fn consumer_group(pod_id: &str, relay_id: u32) -> String {
+    format!("relay:{}:{}", pod_id, relay_id)
+}
+
+/// Adiciona `payload` ao stream do canal, aparando o stream e renovando o TTL
+pub async fn xadd(conn: &mut RedisConnection, channel: &str, payload: &str, config: &StreamsConfig) -> Result<(), redis::RedisError> {
+    let key = stream_key(channel);
+
+    redis::pipe()
+        .cmd("XADD").arg(&key).arg("MAXLEN").arg("~").arg(config.max_len)
+        .arg("*").arg("payload").arg(payload).ignore()
+        .expire(&key, config.ttl_secs).ignore()
+        .query_async::<()>(conn)
+        .await
+}
+
+/// Remove o consumer group do relay quando ele deixa o canal de vez
+pub async fn destroy_group(conn: &mut RedisConnection, channel: &str, group: &str) -> Result<(), redis::RedisError> {
+    conn.xgroup_destroy::<_, _, ()>(stream_key(channel), group).await
+}
+
+/// Lê o stream do canal pelo consumer group até `tx` fechar, reconectando a
+/// cada 3 segundos em caso de erro. Entradas só recebem XACK depois de
+/// encaminhadas; as que ficaram pendentes numa conexão anterior são
+/// relidas antes das novas.
+pub async fn consume_stream<F>(
+    endpoint: RedisEndpoint,
+    channel: String,
+    group: String,
+    config: StreamsConfig,
+    tx: mpsc::UnboundedSender<RedisMessage>,
+    is_own: F,
+) where
+    F: Fn(&RedisMessage) -> bool,
+{
+    loop {
+        match consume_until_closed(&endpoint, &channel, &group, &config, &tx, &is_own).await {
+            Ok(()) => {
+                println!("Canal fechado para {}", channel);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Erro no stream Redis {}: {}", channel, e);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        println!("Tentando reconectar ao stream Redis: {}", channel);
+    }
+}
+
+async fn consume_until_closed<F>(
+    endpoint: &RedisEndpoint,
+    channel: &str,
+    group: &str,
+    config: &StreamsConfig,
+    tx: &mpsc::UnboundedSender<RedisMessage>,
+    is_own: &F,
+) -> Result<(), redis::RedisError>
+where
+    F: Fn(&RedisMessage) -> bool,
+{
+    let key = stream_key(channel);
+    // Conexão própria: o XREADGROUP BLOCK seguraria os comandos da compartilhada
+    let mut conn = endpoint.dedicated_connection(config.block + Duration::from_secs(5)).await?;
+
+    // Grupo novo começa no fim do stream, como uma assinatura nova; um grupo
+    // existente continua do último ID entregue
+    match conn.xgroup_create_mkstream::<_, _, _, ()>(&key, group, "$").await {
+        Ok(()) => {}
+        Err(e) if e.code() == Some("BUSYGROUP") => {}
+        Err(e) => return Err(e),
+    }
+    println!("Conectado ao stream Redis: {} (grupo {})", key, group);
+
+    // Primeiro as entradas pendentes (lidas mas sem XACK antes da queda)
+    let mut pending_cursor = "0".to_string();
+    loop {
+        let options = StreamReadOptions::default()
+            .group(group, group)
+            .count(config.batch_size);
+        let reply: Option<StreamReadReply> = conn.xread_options(&[&key], &[&pending_cursor], &options).await?;
+        let entries: Vec<_> = reply.into_iter().flat_map(|reply| reply.keys).flat_map(|key| key.ids).collect();
+        let Some(last) = entries.last() else {
+            break;
+        };
+        pending_cursor = last.id.clone();
+
+        if !forward(&mut conn, &key, group, entries, tx, is_own).await? {
+            return Ok(());
+        }
+    }
+
+    loop {
+        let options = StreamReadOptions::default()
+            .group(group, group)
+            .count(config.batch_size)
+            .block(config.block.as_millis() as usize);
+        let reply: Option<StreamReadReply> = conn.xread_options(&[&key], &[">"], &options).await?;
+        let entries: Vec<_> = reply.into_iter().flat_map(|reply| reply.keys).flat_map(|key| key.ids).collect();
+        if entries.is_empty() {
+            continue;
+        }
+
+        if !forward(&mut conn, &key, group, entries, tx, is_own).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// Encaminha as entradas para `tx` e confirma com XACK; retorna `false` se
+/// `tx` fechou (as entradas não confirmadas ficam pendentes no grupo)
+async fn forward<F>(
+    conn: &mut RedisConnection,
+    key: &str,
+    group: &str,
+    entries: Vec<redis::streams::StreamId>,
+    tx: &mpsc::UnboundedSender<RedisMessage>,
+    is_own: &F,
+) -> Result<bool, redis::RedisError>
+where
+    F: Fn(&RedisMessage) -> bool,
+{
+    let (handled, open) = dispatch_entries(entries, tx, is_own);
+    if !handled.is_empty() {
+        conn.xack::<_, _, _, ()>(key, group, &handled).await?;
+    }
+
+    Ok(open)
+}
+
+/// Envia as entradas em ordem para `tx` e retorna os IDs que podem receber
+/// XACK e se `tx` continua aberto. Parando em `tx` fechado, a entrada atual
+/// e as seguintes ficam pendentes e são relidas na próxima conexão.
+fn dispatch_entries<F>(
+    entries: Vec<redis::streams::StreamId>,
+    tx: &mpsc::UnboundedSender<RedisMessage>,
+    is_own: &F,
+) -> (Vec<String>, bool)
+where
+    F: Fn(&RedisMessage) -> bool,
+{
+    let mut handled = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        let message = entry.get::<String>("payload")
+            .and_then(|payload| serde_json::from_str::<RedisMessage>(&payload).ok());
+
+        // Payload inválido também recebe XACK para não ficar pendente para sempre
+        let sent = match message.filter(|message| !is_own(message)) {
+            Some(message) => tx.send(message).is_ok(),
+            None => true,
+        };
+        if !sent {
+            return (handled, false);
+        }
+        handled.push(entry.id);
+    }
+
+    (handled, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use redis::streams::StreamId;
+    use crate::actors::RedisMessageType;
+
+    fn entry(id: &str, from_relay_id: u32) -> StreamId {
+        let message = RedisMessage {
+            from_pod_id: "pod-a".to_string(),
+            from_relay_id,
+            message_type: RedisMessageType::RelayHeartbeat { relay_id: from_relay_id, active_connections: 0 },
+            timestamp: 0,
+        };
+        raw_entry(id, &serde_json::to_string(&message).unwrap())
+    }
+
+    fn raw_entry(id: &str, payload: &str) -> StreamId {
+        StreamId {
+            id: id.to_string(),
+            map: HashMap::from([("payload".to_string(), redis::Value::BulkString(payload.as_bytes().to_vec()))]),
+        }
+    }
+
+    #[test]
+    fn names_streams_and_groups_per_channel_and_relay() {
+        assert_eq!(stream_key("room_messages_general"), "stream:room_messages_general");
+        assert_eq!(consumer_group("pod-a", 3), "relay:pod-a:3");
+    }
+
+    #[test]
+    fn acks_forwarded_own_and_invalid_entries() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let entries = vec![entry("1-0", 2), entry("2-0", 1), raw_entry("3-0", "{"), entry("4-0", 3)];
+
+        let (handled, open) = dispatch_entries(entries, &tx, &|message: &RedisMessage| message.from_relay_id == 1);
+
+        assert!(open);
+        assert_eq!(handled, ["1-0", "2-0", "3-0", "4-0"]);
+        // As próprias publicações e o payload inválido não são encaminhados
+        assert_eq!(rx.try_recv().unwrap().from_relay_id, 2);
+        assert_eq!(rx.try_recv().unwrap().from_relay_id, 3);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn entries_not_forwarded_stay_pending() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        drop(rx);
+        let entries = vec![entry("1-0", 1), entry("2-0", 2), entry("3-0", 2)];
+
+        let (handled, open) = dispatch_entries(entries, &tx, &|message: &RedisMessage| message.from_relay_id == 1);
+
+        // Só a própria publicação recebe XACK; o resto é relido ao reconectar
+        assert!(!open);
+        assert_eq!(handled, ["1-0"]);
+    }
+}
@@ -0,0 +1,479 @@
+// src/relay_pool.rs
+//! Tamanho do pool de relays do pod em tempo de execução: cria relays quando
+//! todos passam do limite de uso e aposenta os ociosos, drenando as sessões
+//! deles para os demais antes de parar o ator. Os relays rodam sob um
+//! `Supervisor`, e um relay cujo ator morreu é recriado com o mesmo id.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use actix::Supervisor;
+use serde::Serialize;
+use tokio::sync::{Mutex, RwLock};
+use crate::actors::{GetMetrics, RetireRelay};
+use crate::actors::persistence::{env_or, PersistenceActor};
+use crate::actors::relay::{RelayActor, RelayRegistry};
+use crate::bus::{unix_now, MessageBus};
+use crate::dynamic_relay_balancer::DynamicRelayBalancer;
+
+/// Decisões guardadas para o `/relays`
+const MAX_DECISIONS: usize = 50;
+/// Intervalo da verificação de relays mortos
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayPoolConfig {
+    pub min_relays: usize,
+    pub max_relays: usize,
+    /// Uso (conexões / capacidade) a partir do qual um relay conta como cheio
+    pub scale_up_threshold: f64,
+    /// Uso abaixo do qual um relay conta como ocioso
+    pub scale_down_threshold: f64,
+    /// Por quanto tempo um relay precisa ficar ocioso até ser aposentado
+    #[serde(serialize_with = "serialize_secs")]
+    pub idle_period: Duration,
+}
+
+fn serialize_secs<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+impl RelayPoolConfig {
+    pub fn from_env(initial_relays: usize) -> Self {
+        let min_relays = env_or("RELAY_MIN_COUNT", 1).max(1);
+        Self {
+            min_relays,
+            max_relays: env_or("RELAY_MAX_COUNT", initial_relays.max(min_relays) * 2).max(min_relays),
+            scale_up_threshold: env_or("RELAY_SCALE_UP_THRESHOLD", 0.8),
+            scale_down_threshold: env_or("RELAY_SCALE_DOWN_THRESHOLD", 0.1),
+            idle_period: Duration::from_secs(env_or("RELAY_IDLE_SECS", 300)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolAction {
+    Spawn,
+    /// Relay tirado da seleção; as sessões dele estão sendo migradas
+    Drain,
+    Retire,
+    /// Ator morto recriado com o mesmo id; fora da seleção até responder
+    Restart,
+    /// Relay reiniciado voltou a responder e a receber usuários
+    Recover,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolTrigger {
+    Auto,
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolDecision {
+    pub relay_id: u32,
+    pub action: PoolAction,
+    pub trigger: PoolTrigger,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+/// Resultado de um pedido de aposentadoria
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RetireOutcome {
+    Retired,
+    /// Ainda há usuários no relay; ele continua drenando e é aposentado
+    /// quando esvaziar
+    Draining { remaining_users: usize },
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    AtMaxRelays(usize),
+    AtMinRelays(usize),
+    UnknownRelay(u32),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::AtMaxRelays(max) => write!(f, "pool já tem o máximo de {} relays", max),
+            PoolError::AtMinRelays(min) => write!(f, "pool já tem o mínimo de {} relays", min),
+            PoolError::UnknownRelay(relay_id) => write!(f, "relay {} não existe neste pod", relay_id),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+#[derive(Clone)]
+pub struct RelayPool {
+    balancer: DynamicRelayBalancer,
+    bus: Arc<dyn MessageBus>,
+    persistence: actix::Addr<PersistenceActor>,
+    config: RelayPoolConfig,
+    /// IDs nunca são reaproveitados, para o inbox de um relay aposentado não
+    /// receber mensagens destinadas a um novo
+    next_relay_id: Arc<AtomicU32>,
+    /// Registro de sessões de cada relay, que sobrevive ao ator
+    registries: Arc<RwLock<HashMap<u32, RelayRegistry>>>,
+    /// Desde quando cada relay está abaixo de `scale_down_threshold`
+    idle_since: Arc<RwLock<HashMap<u32, Instant>>>,
+    decisions: Arc<RwLock<VecDeque<PoolDecision>>>,
+    /// Uma decisão de cada vez, seja do autoscaler ou de um admin
+    scaling: Arc<Mutex<()>>,
+}
+
+impl RelayPool {
+    pub fn new(
+        balancer: DynamicRelayBalancer,
+        bus: Arc<dyn MessageBus>,
+        persistence: actix::Addr<PersistenceActor>,
+        config: RelayPoolConfig,
+        first_relay_id: u32,
+    ) -> Self {
+        Self {
+            balancer,
+            bus,
+            persistence,
+            config,
+            next_relay_id: Arc::new(AtomicU32::new(first_relay_id)),
+            registries: Arc::new(RwLock::new(HashMap::new())),
+            idle_since: Arc::new(RwLock::new(HashMap::new())),
+            decisions: Arc::new(RwLock::new(VecDeque::new())),
+            scaling: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Inicia os relays configurados em `RELAY_COUNT`, sem registrar decisões
+    pub async fn start_initial(&self, count: u32) {
+        for _ in 0..count {
+            self.start_relay().await;
+        }
+    }
+
+    async fn start_relay(&self) -> u32 {
+        let relay_id = self.next_relay_id.fetch_add(1, Ordering::Relaxed);
+        let registry = RelayRegistry::default();
+        self.registries.write().await.insert(relay_id, registry.clone());
+
+        let relay_addr = self.start_supervised(relay_id, registry);
+        self.balancer.add_relay(relay_id, relay_addr).await;
+        relay_id
+    }
+
+    fn start_supervised(&self, relay_id: u32, registry: RelayRegistry) -> actix::Addr<RelayActor> {
+        let persistence = self.persistence.clone();
+        let bus = self.bus.clone();
+        let departures = self.balancer.departures();
+        Supervisor::start(move |_| RelayActor::new(relay_id, persistence, bus, registry, departures))
+    }
+
+    async fn record(&self, relay_id: u32, action: PoolAction, trigger: PoolTrigger, reason: String) {
+        println!("Pool de relays: {:?} do relay {} ({:?}): {}", action, relay_id, trigger, reason);
+
+        let mut decisions = self.decisions.write().await;
+        decisions.push_back(PoolDecision {
+            relay_id,
+            action,
+            trigger,
+            reason,
+            timestamp: unix_now(),
+        });
+        while decisions.len() > MAX_DECISIONS {
+            decisions.pop_front();
+        }
+    }
+
+    /// Relays que ainda aceitam usuários
+    async fn active_relay_count(&self) -> usize {
+        self.balancer.relay_count().await - self.balancer.draining_relays().await.len()
+    }
+
+    pub async fn spawn_relay(&self, trigger: PoolTrigger, reason: String) -> Result<u32, PoolError> {
+        let _scaling = self.scaling.lock().await;
+        self.spawn_locked(trigger, reason).await
+    }
+
+    async fn spawn_locked(&self, trigger: PoolTrigger, reason: String) -> Result<u32, PoolError> {
+        if self.balancer.relay_count().await >= self.config.max_relays {
+            return Err(PoolError::AtMaxRelays(self.config.max_relays));
+        }
+
+        let relay_id = self.start_relay().await;
+        self.record(relay_id, PoolAction::Spawn, trigger, reason).await;
+        Ok(relay_id)
+    }
+
+    pub async fn retire_relay(&self, relay_id: u32, trigger: PoolTrigger, reason: String) -> Result<RetireOutcome, PoolError> {
+        let _scaling = self.scaling.lock().await;
+        self.retire_locked(relay_id, trigger, reason).await
+    }
+
+    async fn retire_locked(&self, relay_id: u32, trigger: PoolTrigger, reason: String) -> Result<RetireOutcome, PoolError> {
+        if self.balancer.get_relay_addr(relay_id).await.is_none() {
+            return Err(PoolError::UnknownRelay(relay_id));
+        }
+        let already_draining = self.balancer.draining_relays().await.contains(&relay_id);
+        if !already_draining && self.active_relay_count().await <= self.config.min_relays {
+            return Err(PoolError::AtMinRelays(self.config.min_relays));
+        }
+
+        if !already_draining {
+            self.record(relay_id, PoolAction::Drain, trigger, reason).await;
+        }
+        self.finish_draining(relay_id, trigger).await
+    }
+
+    /// Migra o que restou no relay e o encerra se ficou vazio
+    async fn finish_draining(&self, relay_id: u32, trigger: PoolTrigger) -> Result<RetireOutcome, PoolError> {
+        let remaining_users = self.balancer.drain_relay(relay_id).await;
+        if remaining_users > 0 {
+            return Ok(RetireOutcome::Draining { remaining_users });
+        }
+
+        let Some(relay_addr) = self.balancer.get_relay_addr(relay_id).await else {
+            return Err(PoolError::UnknownRelay(relay_id));
+        };
+        // Uma sessão pode ter chegado entre a drenagem e o pedido
+        if !relay_addr.send(RetireRelay).await.unwrap_or(true) {
+            return Ok(RetireOutcome::Draining { remaining_users: 1 });
+        }
+
+        self.balancer.remove_relay(relay_id).await;
+        self.idle_since.write().await.remove(&relay_id);
+        self.registries.write().await.remove(&relay_id);
+        self.record(relay_id, PoolAction::Retire, trigger, "sessões drenadas".to_string()).await;
+        Ok(RetireOutcome::Retired)
+    }
+
+    /// Executado a cada ciclo de métricas, depois de `sync_metrics_from_relays`
+    pub async fn autoscale(&self) {
+        let _scaling = self.scaling.lock().await;
+
+        let draining = self.balancer.draining_relays().await;
+        for relay_id in &draining {
+            if let Err(e) = self.finish_draining(*relay_id, PoolTrigger::Auto).await {
+                eprintln!("Pool de relays: falha ao terminar drenagem do relay {}: {}", relay_id, e);
+            }
+        }
+
+        // Relays reiniciando não contam para a decisão
+        let unhealthy = self.balancer.unhealthy_relays().await;
+        let capacity = self.balancer.max_connections_per_relay() as f64;
+        let usage: Vec<(u32, f64, usize)> = self.balancer.get_relay_stats().await.into_values()
+            .filter(|metric| !draining.contains(&metric.relay_id) && !unhealthy.contains(&metric.relay_id))
+            .map(|metric| (metric.relay_id, metric.active_connections as f64 / capacity, metric.active_connections))
+            .collect();
+        if usage.is_empty() {
+            return;
+        }
+
+        if usage.iter().all(|(_, utilization, _)| *utilization >= self.config.scale_up_threshold) {
+            let reason = format!("todos os {} relays acima de {:.0}% de uso",
+                                 usage.len(), self.config.scale_up_threshold * 100.0);
+            if let Err(e) = self.spawn_locked(PoolTrigger::Auto, reason).await {
+                eprintln!("Pool de relays: {}", e);
+            }
+            return;
+        }
+
+        let now = Instant::now();
+        let mut idle_since = self.idle_since.write().await;
+        idle_since.retain(|relay_id, _| usage.iter().any(|(id, _, _)| id == relay_id));
+        for (relay_id, utilization, _) in &usage {
+            if *utilization < self.config.scale_down_threshold {
+                idle_since.entry(*relay_id).or_insert(now);
+            } else {
+                idle_since.remove(relay_id);
+            }
+        }
+
+        if usage.len() <= self.config.min_relays {
+            return;
+        }
+
+        // Só aposenta se os relays restantes absorvem as conexões sem ficar cheios
+        let total_connections: usize = usage.iter().map(|(_, _, connections)| connections).sum();
+        let remaining_capacity = (usage.len() - 1) as f64 * capacity * self.config.scale_up_threshold;
+        if total_connections as f64 >= remaining_capacity {
+            return;
+        }
+
+        let candidate = usage.iter()
+            .filter(|(relay_id, _, _)| {
+                idle_since.get(relay_id).is_some_and(|since| now.duration_since(*since) >= self.config.idle_period)
+            })
+            .min_by_key(|(_, _, connections)| *connections)
+            .map(|(relay_id, _, _)| *relay_id);
+        drop(idle_since);
+
+        if let Some(relay_id) = candidate {
+            let reason = format!("abaixo de {:.0}% de uso por {}s",
+                                 self.config.scale_down_threshold * 100.0, self.config.idle_period.as_secs());
+            if let Err(e) = self.retire_locked(relay_id, PoolTrigger::Auto, reason).await {
+                eprintln!("Pool de relays: {}", e);
+            }
+        }
+    }
+
+    /// O `Supervisor` reinicia um relay que parou, mas não um que entrou em
+    /// pânico: aí a mailbox fecha e o relay é recriado aqui, recuperando as
+    /// conexões pelo registro
+    pub fn start_supervisor(&self) {
+        let pool = self.clone();
+
+        actix::spawn(async move {
+            loop {
+                tokio::time::sleep(SUPERVISE_INTERVAL).await;
+                pool.supervise().await;
+            }
+        });
+    }
+
+    async fn supervise(&self) {
+        let _scaling = self.scaling.lock().await;
+        let unhealthy = self.balancer.unhealthy_relays().await;
+
+        for (relay_id, relay_addr) in self.balancer.relay_addrs().await {
+            if relay_addr.connected() {
+                if unhealthy.contains(&relay_id) && relay_addr.send(GetMetrics).await.is_ok() {
+                    self.balancer.mark_healthy(relay_id).await;
+                    self.record(relay_id, PoolAction::Recover, PoolTrigger::Auto, "relay respondendo".to_string()).await;
+                }
+                continue;
+            }
+
+            self.balancer.mark_unhealthy(relay_id).await;
+            let registry = self.registries.write().await.entry(relay_id).or_default().clone();
+            let relay_addr = self.start_supervised(relay_id, registry);
+            self.balancer.replace_relay(relay_id, relay_addr).await;
+            self.record(relay_id, PoolAction::Restart, PoolTrigger::Auto, "mailbox do relay fechada".to_string()).await;
+        }
+    }
+
+    pub async fn status(&self) -> serde_json::Value {
+        let mut draining: Vec<u32> = self.balancer.draining_relays().await.into_iter().collect();
+        draining.sort();
+        let mut unhealthy: Vec<u32> = self.balancer.unhealthy_relays().await.into_iter().collect();
+        unhealthy.sort();
+        let decisions: Vec<PoolDecision> = self.decisions.read().await.iter().cloned().collect();
+
+        serde_json::json!({
+            "config": self.config,
+            "draining_relays": draining,
+            "unhealthy_relays": unhealthy,
+            "decisions": decisions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{AsyncContext, Context};
+    use crate::balancing::{HighestScore, ScoreWeights};
+    use crate::bus::memory::InMemoryBus;
+    use crate::dynamic_relay_balancer::{MappingConfig, RelayAffinity};
+
+    /// Capacidade de cada relay nos testes
+    const CAPACITY: usize = 100;
+
+    fn config(min_relays: usize, max_relays: usize) -> RelayPoolConfig {
+        RelayPoolConfig {
+            min_relays,
+            max_relays,
+            scale_up_threshold: 0.8,
+            scale_down_threshold: 0.1,
+            idle_period: Duration::ZERO,
+        }
+    }
+
+    async fn pool(config: RelayPoolConfig, relays: u32) -> RelayPool {
+        let balancer = DynamicRelayBalancer::new(
+            CAPACITY,
+            Arc::new(HighestScore),
+            ScoreWeights::default(),
+            RelayAffinity::Mapping,
+            MappingConfig { ttl: Duration::from_secs(600), max_entries: 1_000 },
+        );
+        let bus = Arc::new(InMemoryBus::new("pod-a".to_string()));
+        let persistence = Context::<PersistenceActor>::new().address();
+        let pool = RelayPool::new(balancer, bus, persistence, config, 1);
+        pool.start_initial(relays).await;
+        pool
+    }
+
+    async fn set_connections(pool: &RelayPool, connections: &[(u32, usize)]) {
+        for (relay_id, connections) in connections {
+            pool.balancer.update_relay_metrics(*relay_id, *connections, 0.0, 0.0).await;
+        }
+    }
+
+    async fn actions(pool: &RelayPool) -> Vec<(u32, PoolAction)> {
+        pool.decisions.read().await.iter().map(|decision| (decision.relay_id, decision.action)).collect()
+    }
+
+    #[actix::test]
+    async fn spawns_when_every_relay_is_full_up_to_the_maximum() {
+        let pool = pool(config(1, 3), 2).await;
+
+        set_connections(&pool, &[(1, 90), (2, 50)]).await;
+        pool.autoscale().await;
+        assert_eq!(pool.balancer.relay_count().await, 2);
+
+        set_connections(&pool, &[(1, 90), (2, 80)]).await;
+        pool.autoscale().await;
+        assert_eq!(pool.balancer.relay_count().await, 3);
+        assert!(matches!(actions(&pool).await[..], [(3, PoolAction::Spawn)]));
+
+        // No máximo de relays, nem com todos cheios
+        set_connections(&pool, &[(1, 90), (2, 90), (3, 90)]).await;
+        pool.autoscale().await;
+        assert_eq!(pool.balancer.relay_count().await, 3);
+    }
+
+    #[actix::test]
+    async fn retires_an_idle_relay_down_to_the_minimum() {
+        let pool = pool(config(1, 3), 2).await;
+
+        set_connections(&pool, &[(1, 30), (2, 0)]).await;
+        pool.autoscale().await;
+        assert_eq!(pool.balancer.relay_count().await, 1);
+        assert!(pool.balancer.get_relay_addr(1).await.is_some());
+        assert!(matches!(actions(&pool).await[..], [(2, PoolAction::Drain), (2, PoolAction::Retire)]));
+
+        set_connections(&pool, &[(1, 0)]).await;
+        pool.autoscale().await;
+        assert_eq!(pool.balancer.relay_count().await, 1);
+    }
+
+    #[actix::test]
+    async fn keeps_idle_relay_when_the_others_could_not_absorb_its_load() {
+        let pool = pool(config(1, 3), 2).await;
+
+        // 80 conexões num só relay o deixariam no limite de expansão
+        set_connections(&pool, &[(1, 75), (2, 5)]).await;
+        pool.autoscale().await;
+        assert_eq!(pool.balancer.relay_count().await, 2);
+        assert!(actions(&pool).await.is_empty());
+    }
+
+    #[actix::test]
+    async fn waits_for_the_idle_period_before_retiring() {
+        let pool = pool(RelayPoolConfig { idle_period: Duration::from_millis(100), ..config(1, 3) }, 2).await;
+
+        set_connections(&pool, &[(1, 30), (2, 0)]).await;
+        pool.autoscale().await;
+        assert_eq!(pool.balancer.relay_count().await, 2);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        pool.autoscale().await;
+        assert_eq!(pool.balancer.relay_count().await, 1);
+    }
+}
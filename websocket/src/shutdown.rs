@@ -0,0 +1,192 @@
+// src/shutdown.rs
+//! Encerramento do pod com drenagem das conexões: ao receber SIGTERM o pod
+//! recusa upgrades, responde `/health` como indisponível, pede a cada
+//! cliente que reconecte em outro pod e só sai quando as conexões acabam ou
+//! o prazo se esgota. Antes de sair, grava as mensagens que ainda estão na
+//! fila de persistência.
+
+use std::time::Duration;
+use actix::Addr;
+use log::{info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Instant;
+use crate::actors::{DrainConnections, FlushPersistence, GetPersistenceStats, ReleaseSessions};
+use crate::actors::persistence::{env_or, PersistenceActor};
+use crate::dynamic_relay_balancer::DynamicRelayBalancer;
+
+/// Intervalo entre as contagens de conexões restantes
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy)]
+pub struct DrainConfig {
+    /// Prazo total da drenagem; deve caber em `terminationGracePeriodSeconds`
+    pub timeout: Duration,
+    /// Maior atraso sorteado para cada cliente reconectar
+    pub max_jitter: Duration,
+    /// Prazo para gravar a fila de persistência depois da drenagem; somado a
+    /// `timeout`, deve caber em `terminationGracePeriodSeconds`
+    pub flush_timeout: Duration,
+}
+
+impl DrainConfig {
+    pub fn from_env() -> Self {
+        let timeout = Duration::from_secs(env_or("DRAIN_TIMEOUT_SECS", 30));
+        let max_jitter = Duration::from_millis(env_or("DRAIN_RECONNECT_JITTER_MS", 10_000));
+        Self {
+            timeout,
+            // Todos os clientes precisam ser desconectados antes do prazo
+            max_jitter: max_jitter.min(timeout.mul_f64(0.8)),
+            flush_timeout: Duration::from_secs(env_or("DRAIN_FLUSH_TIMEOUT_SECS", 10)),
+        }
+    }
+}
+
+/// Espera SIGTERM (Kubernetes) ou SIGINT (Ctrl+C)
+pub async fn wait_for_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Falha ao registrar handler de SIGTERM, só SIGINT encerra o pod: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => info!("SIGTERM recebido"),
+        _ = tokio::signal::ctrl_c() => info!("SIGINT recebido"),
+    }
+}
+
+/// Pede aos clientes de todos os relays que reconectem em outro pod e
+/// espera as conexões acabarem ou `config.timeout` passar; as sessões que
+/// restarem saem do `user_location`. Retorna quantas restaram.
+pub async fn drain_connections(relay_balancer: &DynamicRelayBalancer, config: DrainConfig) -> usize {
+    let deadline = Instant::now() + config.timeout;
+    let relays = relay_balancer.relay_addrs().await;
+
+    let mut notified = 0;
+    for relay_addr in relays.values() {
+        notified += relay_addr.send(DrainConnections { max_jitter: config.max_jitter }).await.unwrap_or(0);
+    }
+    info!("Drenagem: {} sessões avisadas para reconectar em até {:?}", notified, config.max_jitter);
+
+    let mut remaining = notified;
+    while remaining > 0 && Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        relay_balancer.sync_metrics_from_relays().await;
+        remaining = relay_balancer.get_relay_stats().await.values()
+            .map(|metric| metric.active_connections)
+            .sum();
+    }
+
+    if remaining > 0 {
+        let mut released = 0;
+        for relay_addr in relays.values() {
+            released += relay_addr.send(ReleaseSessions).await.unwrap_or(0);
+        }
+        warn!("Drenagem: prazo esgotado com {} sessões; {} removidas do message bus", remaining, released);
+    }
+    // As últimas desconexões ainda removem as sessões do bus em segundo plano
+    tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+
+    remaining
+}
+
+/// Grava as mensagens aceitas que ainda estão na fila de persistência, por
+/// até `config.flush_timeout`. Retorna quantas ficaram sem gravar.
+pub async fn flush_persistence(persistence: &Addr<PersistenceActor>, config: DrainConfig) -> usize {
+    if tokio::time::timeout(config.flush_timeout, persistence.send(FlushPersistence)).await.is_err() {
+        warn!("Persistência: prazo de {:?} esgotado antes de esvaziar a fila", config.flush_timeout);
+    }
+
+    let remaining = persistence.send(GetPersistenceStats).await
+        .map(|stats| stats.queued)
+        .unwrap_or(0);
+    if remaining > 0 {
+        warn!("Persistência: {} mensagens não gravadas no encerramento", remaining);
+    }
+    remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use actix::Actor;
+    use crate::actors::PersistMessage;
+    use crate::actors::persistence::PersistenceConfig;
+    use crate::actors::testing::{start_relay, TestClient};
+    use crate::balancing::{HighestScore, ScoreWeights};
+    use crate::bus::MessageBus;
+    use crate::bus::memory::InMemoryBus;
+    use crate::dynamic_relay_balancer::{MappingConfig, RelayAffinity};
+
+    #[actix::test]
+    async fn flush_persistence_gives_up_at_the_deadline() {
+        // Porta fechada: todo lote falha e volta para a fila
+        let persistence = PersistenceActor::new(PersistenceConfig {
+            endpoint: "http://127.0.0.1:1/messages/batch".to_string(),
+            service_token: String::new(),
+            batch_size: 10,
+            flush_interval: Duration::from_secs(60),
+            queue_capacity: 100,
+            retry_backoff: Duration::from_millis(20),
+        }).start();
+        persistence.do_send(PersistMessage {
+            message_id: "m1".to_string(),
+            username: "alice".to_string(),
+            content: "oi".to_string(),
+            room_id: "general".to_string(),
+            timestamp: time::OffsetDateTime::UNIX_EPOCH,
+            ack_to: None,
+        });
+        let config = DrainConfig {
+            timeout: Duration::from_secs(1),
+            max_jitter: Duration::ZERO,
+            flush_timeout: Duration::from_millis(300),
+        };
+
+        let started = Instant::now();
+        let remaining = flush_persistence(&persistence, config).await;
+
+        assert_eq!(remaining, 1);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[actix::test]
+    async fn drain_asks_clients_to_reconnect_and_waits_for_them() {
+        let balancer = DynamicRelayBalancer::new(
+            100,
+            Arc::new(HighestScore),
+            ScoreWeights::default(),
+            RelayAffinity::Mapping,
+            MappingConfig { ttl: Duration::from_secs(600), max_entries: 1_000 },
+        );
+        let bus: Arc<dyn MessageBus> = Arc::new(InMemoryBus::new("pod-a".to_string()));
+        let relay = start_relay(bus.clone(), 1);
+        balancer.add_relay(1, relay.clone()).await;
+        let mut alice = TestClient::connect("alice", relay.clone()).await;
+        let mut bob = TestClient::connect("bob", relay).await;
+        let config = DrainConfig {
+            timeout: Duration::from_secs(5),
+            max_jitter: Duration::from_millis(200),
+            flush_timeout: Duration::ZERO,
+        };
+
+        let started = Instant::now();
+        let remaining = drain_connections(&balancer, config).await;
+
+        assert_eq!(remaining, 0);
+        assert!(started.elapsed() < config.timeout);
+        for client in [&mut alice, &mut bob] {
+            let reconnect = client.expect("reconnect").await;
+            assert_eq!(reconnect["payload"]["resume_token"], client.session_id.as_str());
+            assert!(reconnect["payload"]["retry_after_ms"].as_u64().unwrap() <= 200);
+            // Depois do aviso só chegam as saídas dos outros, até a conexão fechar
+            let rest = client.frames_within(Duration::from_secs(1)).await;
+            assert!(rest.iter().all(|frame| frame["type"] == "room_left" || frame["type"] == "user_left"));
+        }
+        assert!(bus.get_user_locations("alice").await.unwrap().is_empty());
+    }
+}